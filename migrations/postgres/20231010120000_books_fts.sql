@@ -0,0 +1,9 @@
+-- Full-text index over book titles and authors.
+-- The vector is a generated column, so Postgres keeps it up to date on
+-- every insert and update. Title matches are weighted above author matches.
+ALTER TABLE books ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', coalesce(title, '')), 'A') ||
+    setweight(to_tsvector('simple', coalesce(author, '')), 'B')
+) STORED;
+
+CREATE INDEX books_search_vector_idx ON books USING GIN (search_vector);
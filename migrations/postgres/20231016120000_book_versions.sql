@@ -0,0 +1,4 @@
+-- Optimistic concurrency: every write bumps `version`, and clients say
+-- which version they are changing.
+ALTER TABLE books ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE books ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
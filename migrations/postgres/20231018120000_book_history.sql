@@ -0,0 +1,23 @@
+-- Every insert, update and delete of a book, with who made it and the
+-- book as it was before and after. There is deliberately no foreign key:
+-- the history of a deleted book is kept.
+CREATE TABLE book_history (
+    id BIGSERIAL PRIMARY KEY,
+    book_id INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL,
+    before JSONB,
+    after JSONB
+);
+
+CREATE INDEX book_history_book_idx ON book_history (book_id, changed_at);
+
+-- Books that existed before history was kept start with a single entry,
+-- holding their state at the time.
+INSERT INTO book_history (book_id, action, actor, changed_at, after)
+SELECT id, 'insert', created_by, created_at,
+       jsonb_build_object('id', id, 'title', title, 'author', author, 'version', version,
+                          'created_at', created_at, 'created_by', created_by,
+                          'updated_at', updated_at, 'updated_by', updated_by)
+FROM books;
@@ -0,0 +1,25 @@
+-- Full-text index over book titles and authors.
+-- This is an external-content table: the text lives in `books`, and the
+-- triggers below keep the index in step with every insert, update and delete.
+CREATE VIRTUAL TABLE books_fts USING fts5(
+    title,
+    author,
+    content='books',
+    content_rowid='id'
+);
+
+-- Index any rows that already exist.
+INSERT INTO books_fts(books_fts) VALUES ('rebuild');
+
+CREATE TRIGGER books_fts_insert AFTER INSERT ON books BEGIN
+    INSERT INTO books_fts(rowid, title, author) VALUES (new.id, new.title, new.author);
+END;
+
+CREATE TRIGGER books_fts_delete AFTER DELETE ON books BEGIN
+    INSERT INTO books_fts(books_fts, rowid, title, author) VALUES ('delete', old.id, old.title, old.author);
+END;
+
+CREATE TRIGGER books_fts_update AFTER UPDATE ON books BEGIN
+    INSERT INTO books_fts(books_fts, rowid, title, author) VALUES ('delete', old.id, old.title, old.author);
+    INSERT INTO books_fts(rowid, title, author) VALUES (new.id, new.title, new.author);
+END;
@@ -0,0 +1,10 @@
+-- Optimistic concurrency: every write bumps `version`, and clients say
+-- which version they are changing. `updated_at` is an RFC 3339 UTC
+-- timestamp, always written as strftime('%Y-%m-%dT%H:%M:%fZ', 'now') so
+-- that timestamps compare correctly as text.
+-- SQLite won't add a column with a non-constant default, so existing rows
+-- are stamped separately.
+ALTER TABLE books ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE books ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z';
+
+UPDATE books SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now');
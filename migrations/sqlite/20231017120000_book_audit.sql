@@ -0,0 +1,11 @@
+-- Who created and last changed each book, and when it was created.
+-- Rows from before this migration are credited to 'unknown', and treated
+-- as created when they were last updated.
+ALTER TABLE books ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z';
+ALTER TABLE books ADD COLUMN created_by TEXT NOT NULL DEFAULT 'unknown';
+ALTER TABLE books ADD COLUMN updated_by TEXT NOT NULL DEFAULT 'unknown';
+
+UPDATE books SET created_at = updated_at;
+
+-- For incremental sync with `?updated_since=`.
+CREATE INDEX books_updated_at_idx ON books (updated_at);
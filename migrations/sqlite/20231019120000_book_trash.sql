@@ -0,0 +1,5 @@
+-- Deleting a book moves it to the trash by setting deleted_at. It can be
+-- restored until it is purged.
+ALTER TABLE books ADD COLUMN deleted_at TEXT;
+
+CREATE INDEX books_deleted_at_idx ON books (deleted_at);
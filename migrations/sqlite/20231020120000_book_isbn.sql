@@ -0,0 +1,7 @@
+-- Books are identified by ISBN. isbn13 holds the canonical ISBN-13, and
+-- isbn10 the equivalent ISBN-10 where one exists (978 ISBNs only). No two
+-- live books may share an ISBN; a book in the trash doesn't count.
+ALTER TABLE books ADD COLUMN isbn13 TEXT;
+ALTER TABLE books ADD COLUMN isbn10 TEXT;
+
+CREATE UNIQUE INDEX books_isbn13_idx ON books (isbn13) WHERE deleted_at IS NULL;
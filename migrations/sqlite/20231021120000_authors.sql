@@ -0,0 +1,28 @@
+-- Authors are the people credited on books. book_authors holds each
+-- book's credits in order, with the part each person played. books.author
+-- keeps a display string built from the credits, for sorting, search and
+-- clients that only know the single author field.
+CREATE TABLE authors (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX authors_name_idx ON authors (lower(name));
+
+CREATE TABLE book_authors (
+    book_id INTEGER NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    author_id INTEGER NOT NULL REFERENCES authors (id),
+    position INTEGER NOT NULL,
+    role TEXT NOT NULL DEFAULT 'author',
+    PRIMARY KEY (book_id, position)
+);
+
+CREATE INDEX book_authors_author_idx ON book_authors (author_id);
+
+-- Every existing book has a single author.
+INSERT OR IGNORE INTO authors (name)
+SELECT author FROM books WHERE author IS NOT NULL AND author <> '' ORDER BY id;
+
+INSERT INTO book_authors (book_id, author_id, position)
+SELECT books.id, authors.id, 0
+FROM books JOIN authors ON lower(authors.name) = lower(books.author);
@@ -0,0 +1,30 @@
+-- Which edition of a book the library holds: its publisher, when it was
+-- published, its edition statement, length and language, and where it falls
+-- in a series. Publishers and series are shared between books, and matched
+-- by name ignoring case. published_on is an ISO 8601 date.
+CREATE TABLE publishers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX publishers_name_idx ON publishers (lower(name));
+
+CREATE TABLE series (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX series_name_idx ON series (lower(name));
+
+ALTER TABLE books ADD COLUMN publisher_id INTEGER REFERENCES publishers (id);
+ALTER TABLE books ADD COLUMN published_on TEXT;
+ALTER TABLE books ADD COLUMN edition TEXT;
+ALTER TABLE books ADD COLUMN page_count INTEGER;
+ALTER TABLE books ADD COLUMN language TEXT;
+ALTER TABLE books ADD COLUMN series_id INTEGER REFERENCES series (id);
+ALTER TABLE books ADD COLUMN series_volume INTEGER;
+
+CREATE INDEX books_publisher_idx ON books (publisher_id);
+CREATE INDEX books_series_idx ON books (series_id, series_volume);
+CREATE INDEX books_published_on_idx ON books (published_on);
+CREATE INDEX books_language_idx ON books (language);
@@ -0,0 +1,46 @@
+//! Who is making a request, for the audit columns on `books`.
+//!
+//! The service has no authentication of its own. It expects to sit behind
+//! a proxy that authenticates users and passes the user name on in the
+//! `X-User` header. Requests without one are recorded as `anonymous`.
+
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use std::convert::Infallible;
+
+/// The header naming the user making a request.
+pub const USER_HEADER: &str = "x-user";
+
+/// The name recorded when a request doesn't say who made it.
+pub const ANONYMOUS: &str = "anonymous";
+
+/// The longest user name recorded, in characters. Longer names are cut.
+const MAX_ACTOR_LEN: usize = 128;
+
+/// The user making a request, taken from the `X-User` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor(pub String);
+
+impl Actor {
+    /// The user name, as recorded in the database.
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let name = parts
+            .headers
+            .get(USER_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(ANONYMOUS);
+        Ok(Actor(name.chars().take(MAX_ACTOR_LEN).collect()))
+    }
+}
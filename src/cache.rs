@@ -0,0 +1,665 @@
+//! Caches that sit in front of the book repository.
+//!
+//! A cache is part of the injected `AppState`, so each router (and each
+//! test) has its own. Which implementation is used is chosen by
+//! configuration; see `CacheConfig`.
+//!
+//! Entries are either a single book, keyed by ID, or the result of a query
+//! (a page of the listing, its tag facets, or a search), keyed by the
+//! normalized query.
+//! Changing a book only evicts that book's entry, but every query result
+//! is dropped, since the change may move the book into or out of any of
+//! them.
+//!
+//! Every invalidation also bumps the cache's generation. A result loaded
+//! under an older generation may already be stale, so it is never stored.
+//! `SingleFlight` makes sure only one load per key and generation is in
+//! flight at a time, with everyone else waiting for its result.
+//!
+//! The generation also records when the cache was last invalidated, which
+//! is the last time this process changed a book. The REST layer uses it
+//! for cheap list `ETag`s and for `Last-Modified`.
+
+use crate::config::{CacheConfig, CacheKind};
+use crate::db::{Book, BookQuery, Page, SearchHit, TagCount};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Identifies a cache entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CacheKey {
+    /// A single book, by ID
+    Book(i32),
+    /// A single book, by ISBN-13. Invalidated like a query, since a write
+    /// to any book can change which book has an ISBN.
+    Isbn(String),
+    /// A page of the book listing
+    Page(BookQuery),
+    /// A full-text search. The text is normalized by `CacheKey::search`.
+    Search { text: String, limit: i64 },
+    /// The tag counts for a listing, without its paging
+    Facets(BookQuery),
+}
+
+impl CacheKey {
+    /// The key for a search, ignoring case and extra whitespace.
+    pub fn search(text: &str, limit: i64) -> Self {
+        let text = text
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ");
+        CacheKey::Search { text, limit }
+    }
+
+    /// Is this the result of a query, rather than a single book?
+    fn is_query(&self) -> bool {
+        !matches!(self, CacheKey::Book(_))
+    }
+}
+
+/// A cached value. The variant always matches the `CacheKey` variant.
+#[derive(Debug, Clone)]
+pub enum CacheValue {
+    Book(Box<Book>),
+    Page(Page<Book>),
+    Search(Vec<SearchHit>),
+    Facets(Vec<TagCount>),
+}
+
+/// Where a cache is in its history of invalidations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Generation {
+    /// When the cache was created, so that counts from before a restart
+    /// are never mistaken for current ones
+    pub epoch: u128,
+    /// How many times the cache has been invalidated
+    pub count: u64,
+    /// When the cache was last invalidated, or created
+    pub modified: SystemTime,
+}
+
+impl Generation {
+    fn new() -> Self {
+        let now = SystemTime::now();
+        Self {
+            epoch: now
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            count: 0,
+            modified: now,
+        }
+    }
+
+    /// Move on to the next generation.
+    fn advance(&mut self) {
+        self.count += 1;
+        self.modified = SystemTime::now();
+    }
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counters describing how well the cache is doing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found a live entry
+    pub hits: u64,
+    /// Lookups that found nothing, or only an expired entry
+    pub misses: u64,
+    /// Entries pushed out to make room for new ones
+    pub evictions: u64,
+    /// Entries dropped because their time to live ran out
+    pub expirations: u64,
+    /// Entries currently held
+    pub entries: u64,
+}
+
+/// Somewhere to keep books and query results between requests.
+#[async_trait]
+pub trait Cache: Send + Sync {
+    /// The cached value for `key`, if there is a live one.
+    async fn get(&self, key: &CacheKey) -> Option<CacheValue>;
+
+    /// Remember `value` for `key`, provided nothing has been invalidated
+    /// since `generation`, when the value was read from the database.
+    async fn put(&self, key: CacheKey, value: CacheValue, generation: Generation);
+
+    /// Forget one book, and every query result (any of which may include it).
+    /// Called when a book is changed or deleted.
+    async fn invalidate_book(&self, id: i32) {
+        self.invalidate_books(&[id]).await;
+    }
+
+    /// Forget several books and every query result, as a single
+    /// invalidation. Called after a batch of writes.
+    async fn invalidate_books(&self, ids: &[i32]);
+
+    /// Forget every query result, keeping individual books.
+    /// Called when a book is added.
+    async fn invalidate_queries(&self);
+
+    /// The current generation, which moves on with every invalidation.
+    fn generation(&self) -> Generation;
+
+    /// A snapshot of the counters.
+    fn stats(&self) -> CacheStats;
+}
+
+/// Build the cache selected by `config`.
+pub fn from_config(config: &CacheConfig) -> Arc<dyn Cache> {
+    match config.kind {
+        CacheKind::None => Arc::new(NoCache::default()),
+        CacheKind::Memory => Arc::new(LruCache::unbounded(config.book_ttl, config.query_ttl)),
+        CacheKind::Bounded => Arc::new(LruCache::new(
+            config.capacity,
+            config.book_ttl,
+            config.query_ttl,
+        )),
+    }
+}
+
+/// A cache that never holds anything, so every read goes to the database.
+/// It still counts misses and invalidations.
+#[derive(Default)]
+pub struct NoCache {
+    misses: AtomicU64,
+    generation: Mutex<Generation>,
+}
+
+#[async_trait]
+impl Cache for NoCache {
+    async fn get(&self, _key: &CacheKey) -> Option<CacheValue> {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    async fn put(&self, _key: CacheKey, _value: CacheValue, _generation: Generation) {}
+
+    async fn invalidate_books(&self, _ids: &[i32]) {
+        self.generation.lock().unwrap().advance();
+    }
+
+    async fn invalidate_queries(&self) {
+        self.generation.lock().unwrap().advance();
+    }
+
+    fn generation(&self) -> Generation {
+        *self.generation.lock().unwrap()
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        }
+    }
+}
+
+struct Entry {
+    value: CacheValue,
+    expires: Instant,
+}
+
+/// An in-memory cache that evicts the least recently used entry when it is
+/// full. Each entry also expires after a time to live: `book_ttl` for
+/// single books, and `query_ttl` for query results.
+pub struct LruCache {
+    /// The entries, and the generation they belong to
+    entries: Mutex<(lru::LruCache<CacheKey, Entry>, Generation)>,
+    book_ttl: Duration,
+    query_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+impl LruCache {
+    /// A cache holding at most `capacity` entries. A capacity of zero
+    /// stores nothing.
+    pub fn new(capacity: usize, book_ttl: Duration, query_ttl: Duration) -> Self {
+        match NonZeroUsize::new(capacity) {
+            Some(capacity) => Self::with_entries(lru::LruCache::new(capacity), book_ttl, query_ttl),
+            None => Self::new(1, Duration::ZERO, Duration::ZERO),
+        }
+    }
+
+    /// A cache with no size limit; entries leave only when they expire or
+    /// are invalidated.
+    pub fn unbounded(book_ttl: Duration, query_ttl: Duration) -> Self {
+        Self::with_entries(lru::LruCache::unbounded(), book_ttl, query_ttl)
+    }
+
+    fn with_entries(
+        entries: lru::LruCache<CacheKey, Entry>,
+        book_ttl: Duration,
+        query_ttl: Duration,
+    ) -> Self {
+        Self {
+            entries: Mutex::new((entries, Generation::new())),
+            book_ttl,
+            query_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    fn ttl(&self, key: &CacheKey) -> Duration {
+        if key.is_query() {
+            self.query_ttl
+        } else {
+            self.book_ttl
+        }
+    }
+
+    fn remove_queries(entries: &mut lru::LruCache<CacheKey, Entry>) {
+        let queries: Vec<CacheKey> = entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.is_query())
+            .cloned()
+            .collect();
+        for key in queries {
+            entries.pop(&key);
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for LruCache {
+    async fn get(&self, key: &CacheKey) -> Option<CacheValue> {
+        let (entries, _) = &mut *self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.pop(key);
+                self.expirations.fetch_add(1, Ordering::Relaxed);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: CacheKey, value: CacheValue, generation: Generation) {
+        let ttl = self.ttl(&key);
+        if ttl.is_zero() {
+            return;
+        }
+        let entry = Entry {
+            value,
+            expires: Instant::now() + ttl,
+        };
+        let (entries, current) = &mut *self.entries.lock().unwrap();
+        if generation != *current {
+            return;
+        }
+        // `push` hands back the old entry when replacing a key, which is
+        // not an eviction.
+        if let Some((old_key, _)) = entries.push(key.clone(), entry) {
+            if old_key != key {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn invalidate_books(&self, ids: &[i32]) {
+        let (entries, generation) = &mut *self.entries.lock().unwrap();
+        for id in ids {
+            entries.pop(&CacheKey::Book(*id));
+        }
+        Self::remove_queries(entries);
+        generation.advance();
+    }
+
+    async fn invalidate_queries(&self) {
+        let (entries, generation) = &mut *self.entries.lock().unwrap();
+        Self::remove_queries(entries);
+        generation.advance();
+    }
+
+    fn generation(&self) -> Generation {
+        self.entries.lock().unwrap().1
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().0.len() as u64,
+        }
+    }
+}
+
+/// How a finished load is passed on to the callers waiting for it.
+type Outcome = std::result::Result<CacheValue, Arc<Error>>;
+
+/// Coalesces concurrent cache misses, so that only one database read per
+/// key is in flight and everyone who asked for it shares the result.
+#[derive(Default)]
+pub struct SingleFlight {
+    /// Loads in progress. A load only serves callers that arrive in the
+    /// same cache generation; after an invalidation a new load starts.
+    flights: Mutex<HashMap<(CacheKey, Generation), broadcast::Sender<Outcome>>>,
+}
+
+/// Which part a caller plays in a load.
+enum Role {
+    /// Nobody else is loading this key, so this caller must.
+    Leader(broadcast::Sender<Outcome>),
+    /// Another caller is loading it; wait for them.
+    Follower(broadcast::Receiver<Outcome>),
+}
+
+/// Removes a flight from the table when its leader finishes, or gives up.
+struct Landing<'a> {
+    flights: &'a SingleFlight,
+    key: (CacheKey, Generation),
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.flights.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl SingleFlight {
+    /// Return the cached value for `key`, or run `load` to fetch it and
+    /// cache the result. If the same key is already being loaded, wait for
+    /// that instead of running `load`.
+    ///
+    /// ## Arguments
+    /// * `cache` - where to look first, and where to store what is loaded
+    /// * `key` - the entry wanted
+    /// * `load` - reads the value from the database
+    ///
+    /// ## Returns
+    /// * The value, or the error `load` failed with.
+    pub async fn get_or_load<F, Fut>(
+        &self,
+        cache: &dyn Cache,
+        key: CacheKey,
+        load: F,
+    ) -> Result<CacheValue>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CacheValue>>,
+    {
+        if let Some(value) = cache.get(&key).await {
+            return Ok(value);
+        }
+
+        let generation = cache.generation();
+        let flight = (key.clone(), generation);
+        let role = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&flight) {
+                Some(sender) => Role::Follower(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    flights.insert(flight.clone(), sender.clone());
+                    Role::Leader(sender)
+                }
+            }
+        };
+
+        match role {
+            Role::Leader(sender) => {
+                let landing = Landing {
+                    flights: self,
+                    key: flight,
+                };
+                let result = load().await;
+                if let Ok(value) = &result {
+                    cache.put(key, value.clone(), generation).await;
+                }
+                // Take the flight off the table before announcing the
+                // result, so nobody can subscribe after it has been sent.
+                drop(landing);
+                let outcome = match &result {
+                    Ok(value) => Ok(value.clone()),
+                    Err(err) => Err(Arc::new(err.duplicate())),
+                };
+                sender.send(outcome).ok();
+                result
+            }
+            Role::Follower(mut receiver) => match receiver.recv().await {
+                Ok(outcome) => outcome.map_err(|err| err.duplicate()),
+                // The leader was cancelled before it finished; do it ourselves.
+                Err(_) => load().await,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn page_key(offset: i64) -> CacheKey {
+        CacheKey::Page(BookQuery {
+            offset,
+            ..BookQuery::default()
+        })
+    }
+
+    fn page(total: i64) -> CacheValue {
+        CacheValue::Page(Page {
+            items: Vec::new(),
+            total,
+        })
+    }
+
+    fn book(id: i32) -> CacheValue {
+        CacheValue::Book(Box::new(Book {
+            id,
+            title: "Title".to_string(),
+            author: "Author".to_string(),
+            authors: Vec::new(),
+            version: 1,
+            created_at: chrono::Utc::now(),
+            created_by: "tester".to_string(),
+            updated_at: chrono::Utc::now(),
+            updated_by: "tester".to_string(),
+            deleted_at: None,
+            isbn13: None,
+            isbn10: None,
+            publisher: None,
+            published_on: None,
+            edition: None,
+            page_count: None,
+            language: None,
+            series: None,
+            series_volume: None,
+            tags: Vec::new(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn no_cache() {
+        let cache = NoCache::default();
+        cache.put(page_key(0), page(1), cache.generation()).await;
+        assert!(cache.get(&page_key(0)).await.is_none());
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn hits_and_misses() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        assert!(cache.get(&page_key(0)).await.is_none());
+        cache.put(page_key(0), page(1), cache.generation()).await;
+        cache.put(page_key(1), page(2), cache.generation()).await;
+        assert!(matches!(
+            cache.get(&page_key(0)).await,
+            Some(CacheValue::Page(Page { total: 1, .. }))
+        ));
+        assert!(cache.get(&page_key(1)).await.is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 2));
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = LruCache::new(2, MINUTE, MINUTE);
+        cache.put(page_key(0), page(0), cache.generation()).await;
+        cache.put(page_key(1), page(1), cache.generation()).await;
+        // Touch page 0, so page 1 is now the least recently used
+        cache.get(&page_key(0)).await;
+        cache.put(page_key(2), page(2), cache.generation()).await;
+        assert!(cache.get(&page_key(0)).await.is_some());
+        assert!(cache.get(&page_key(1)).await.is_none());
+        assert!(cache.get(&page_key(2)).await.is_some());
+        assert_eq!(cache.stats().evictions, 1);
+
+        // Replacing an entry is not an eviction
+        cache.put(page_key(2), page(3), cache.generation()).await;
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn entries_expire() {
+        let cache = LruCache::unbounded(MINUTE, Duration::from_millis(10));
+        cache.put(page_key(0), page(0), cache.generation()).await;
+        cache
+            .put(CacheKey::Book(1), book(1), cache.generation())
+            .await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(cache.get(&page_key(0)).await.is_none());
+        assert!(cache.get(&CacheKey::Book(1)).await.is_some());
+        assert_eq!(cache.stats().expirations, 1);
+    }
+
+    #[tokio::test]
+    async fn targeted_invalidation() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        cache
+            .put(CacheKey::Book(1), book(1), cache.generation())
+            .await;
+        cache
+            .put(CacheKey::Book(2), book(2), cache.generation())
+            .await;
+        cache.put(page_key(0), page(2), cache.generation()).await;
+        cache
+            .put(
+                CacheKey::search(" Rust  BRAIN", 10),
+                CacheValue::Search(Vec::new()),
+                cache.generation(),
+            )
+            .await;
+        assert!(cache
+            .get(&CacheKey::search("rust brain", 10))
+            .await
+            .is_some());
+
+        cache.invalidate_book(1).await;
+        assert!(cache.get(&CacheKey::Book(1)).await.is_none());
+        assert!(cache.get(&CacheKey::Book(2)).await.is_some());
+        assert!(cache.get(&page_key(0)).await.is_none());
+        assert!(cache
+            .get(&CacheKey::search("rust brain", 10))
+            .await
+            .is_none());
+
+        // A batch is a single invalidation, however many books it touches
+        cache
+            .put(CacheKey::Book(1), book(1), cache.generation())
+            .await;
+        let before = cache.generation();
+        cache.invalidate_books(&[1, 2]).await;
+        assert!(cache.get(&CacheKey::Book(1)).await.is_none());
+        assert!(cache.get(&CacheKey::Book(2)).await.is_none());
+        assert_eq!(before.count + 1, cache.generation().count);
+    }
+
+    #[tokio::test]
+    async fn stale_generation_is_not_stored() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        let generation = cache.generation();
+        cache.invalidate_queries().await;
+        cache.put(page_key(0), page(1), generation).await;
+        assert!(cache.get(&page_key(0)).await.is_none());
+        cache.put(page_key(0), page(1), cache.generation()).await;
+        assert!(cache.get(&page_key(0)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_load() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        let flights = SingleFlight::default();
+        let loads = AtomicU64::new(0);
+        let load = || async {
+            loads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(page(7))
+        };
+        let (a, b, c) = tokio::join!(
+            flights.get_or_load(&cache, page_key(0), load),
+            flights.get_or_load(&cache, page_key(0), load),
+            flights.get_or_load(&cache, page_key(0), load),
+        );
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!([a, b, c]
+            .iter()
+            .all(|r| matches!(r, Ok(CacheValue::Page(Page { total: 7, .. })))));
+        assert!(flights.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        let flights = SingleFlight::default();
+        let load = || async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Err(Error::NotFound("no such book".to_string()))
+        };
+        let (a, b) = tokio::join!(
+            flights.get_or_load(&cache, CacheKey::Book(9), load),
+            flights.get_or_load(&cache, CacheKey::Book(9), load),
+        );
+        assert!(matches!(a, Err(Error::NotFound(_))));
+        assert!(matches!(b, Err(Error::NotFound(_))));
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn invalidation_during_load_is_respected() {
+        let cache = LruCache::unbounded(MINUTE, MINUTE);
+        let flights = SingleFlight::default();
+        let load = || async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(page(1))
+        };
+        let (loaded, _) = tokio::join!(flights.get_or_load(&cache, page_key(0), load), async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            cache.invalidate_queries().await;
+        });
+        assert!(loaded.is_ok());
+        // The load started before the invalidation, so it wasn't cached
+        assert!(cache.get(&page_key(0)).await.is_none());
+    }
+}
@@ -0,0 +1,279 @@
+//! Conditional requests: `ETag` and `Last-Modified` validators,
+//! `304 Not Modified` responses for clients that already have the current
+//! representation, and `If-Match` preconditions on writes.
+//!
+//! List `ETag`s come from the cache generation rather than the content, so
+//! an unchanged listing can be answered without touching the database, and
+//! their `Last-Modified` is the time of the last change this process made.
+//! Item `ETag`s are a hash of the book, which includes its version, and
+//! their `Last-Modified` is the book's `updated_at`. `Last-Modified` is only
+//! accurate to the second; clients that need better should rely on
+//! `If-None-Match`, which takes precedence.
+
+use crate::cache::Generation;
+use crate::db::{Book, BookQuery};
+use crate::error::{Error, Result};
+use crate::state::AppState;
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::headers::{ETag, HeaderMapExt, IfMatch, IfModifiedSince, IfNoneMatch, LastModified};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::time::SystemTime;
+
+/// The validators describing one version of a representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: ETag,
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    /// Validators for a page of the listing at `path`. They change whenever
+    /// the cache generation does, which happens on every write.
+    pub fn for_list(generation: &Generation, path: &str, query: &BookQuery) -> Self {
+        let request = format!(
+            "{path}?{}&{}&{}&{}&{:?}&{:?}",
+            query.limit,
+            query.offset,
+            query.sort.as_str(),
+            query.order.as_str(),
+            query.updated_since,
+            query.filter
+        );
+        let tag = format!(
+            "\"{:x}-{:x}-{:016x}\"",
+            generation.epoch,
+            generation.count,
+            fnv1a(request.as_bytes())
+        );
+        Self {
+            etag: tag.parse().expect("list ETags are well-formed"),
+            last_modified: generation.modified,
+        }
+    }
+
+    /// Validators for a single book, from a hash of its JSON form.
+    pub fn for_book(book: &Book) -> Self {
+        Self {
+            etag: content_etag(book),
+            last_modified: book.updated_at.into(),
+        }
+    }
+}
+
+/// A strong `ETag` for a resource, from a hash of its JSON form.
+fn content_etag(body: &impl Serialize) -> ETag {
+    let json = serde_json::to_vec(body).unwrap_or_default();
+    format!("\"{:016x}\"", fnv1a(&json))
+        .parse()
+        .expect("content ETags are well-formed")
+}
+
+/// The 64-bit FNV-1a hash. Unlike `DefaultHasher` it is stable across
+/// builds, so `ETag`s survive a redeploy.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The conditional headers sent with a request. Malformed headers are
+/// ignored, as if they had not been sent.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    if_match: Option<IfMatch>,
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<IfModifiedSince>,
+}
+
+impl Conditions {
+    /// Does the client already have the representation `validators`
+    /// describes? `If-None-Match` wins over `If-Modified-Since` when both
+    /// are present (RFC 9110, section 13.2.2).
+    pub fn not_modified(&self, validators: &Validators) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            !if_none_match.precondition_passes(&validators.etag)
+        } else if let Some(if_modified_since) = &self.if_modified_since {
+            !if_modified_since.is_modified(validators.last_modified)
+        } else {
+            false
+        }
+    }
+
+    /// Answer with `304 Not Modified` if the client is up to date, otherwise
+    /// with `body`. Either way the validators are sent along.
+    pub fn respond<T>(&self, validators: Validators, body: T) -> Conditional<T> {
+        if self.not_modified(&validators) {
+            Conditional::NotModified(validators)
+        } else {
+            Conditional::Modified(validators, body)
+        }
+    }
+
+    /// Run `write` against book `id`, provided the client has said which
+    /// version it is changing: either with `If-Match`, or with `version`
+    /// from the request body.
+    ///
+    /// ## Arguments
+    /// * `state` - where to find the current book, to check `If-Match`
+    /// * `id` - the book being written
+    /// * `version` - the version given in the request body, if any
+    /// * `write` - performs the write, given the version it must change
+    ///
+    /// ## Returns
+    /// * Whatever `write` returns. Fails with `Error::PreconditionRequired`
+    ///   if no version was given, and `Error::PreconditionFailed` if the
+    ///   `If-Match` header is out of date. A stale body `version` is left
+    ///   to `write` to report, as `Error::Stale`.
+    pub async fn guard_write<T, F, Fut>(
+        &self,
+        state: &AppState,
+        id: i32,
+        version: Option<i64>,
+        write: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(i64) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let matched = match &self.if_match {
+            Some(if_match) => {
+                // Read past the cache: this must be the latest version.
+                let current = state.repo.book_by_id(id).await?;
+                if !if_match.precondition_passes(&Validators::for_book(&current).etag) {
+                    return Err(Error::PreconditionFailed(Box::new(current)));
+                }
+                Some(current.version)
+            }
+            None => None,
+        };
+        let Some(expected) = version.or(matched) else {
+            return Err(Error::PreconditionRequired(
+                "send If-Match with the book's ETag, or the version being changed".to_string(),
+            ));
+        };
+        // If the book changed between the If-Match check and the write,
+        // that is still a failed precondition.
+        write(expected).await.map_err(|err| match err {
+            Error::Stale(current) if version.is_none() => Error::PreconditionFailed(current),
+            err => err,
+        })
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Conditions {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        Ok(Self {
+            if_match: parts.headers.typed_get(),
+            if_none_match: parts.headers.typed_get(),
+            if_modified_since: parts.headers.typed_get(),
+        })
+    }
+}
+
+/// A response to a conditional GET.
+#[derive(Debug)]
+pub enum Conditional<T> {
+    /// The client's copy is current: `304` with no body.
+    NotModified(Validators),
+    /// The client needs the full representation.
+    Modified(Validators, T),
+}
+
+impl<T: IntoResponse> IntoResponse for Conditional<T> {
+    fn into_response(self) -> Response {
+        let (validators, mut response) = match self {
+            Conditional::NotModified(validators) => {
+                (validators, StatusCode::NOT_MODIFIED.into_response())
+            }
+            Conditional::Modified(validators, body) => (validators, body.into_response()),
+        };
+        let headers = response.headers_mut();
+        headers.typed_insert(validators.etag);
+        headers.typed_insert(LastModified::from(validators.last_modified));
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderMap;
+    use std::time::Duration;
+
+    fn conditions(headers: &[(&str, &str)]) -> Conditions {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(
+                axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        Conditions {
+            if_match: map.typed_get(),
+            if_none_match: map.typed_get(),
+            if_modified_since: map.typed_get(),
+        }
+    }
+
+    #[test]
+    fn list_validators_follow_the_generation() {
+        let generation = Generation::default();
+        let query = BookQuery::default();
+        let first = Validators::for_list(&generation, "/api/v1/books", &query);
+        assert_eq!(
+            first,
+            Validators::for_list(&generation, "/api/v1/books", &query)
+        );
+
+        let other_page = BookQuery {
+            offset: 50,
+            ..query.clone()
+        };
+        assert_ne!(
+            first.etag,
+            Validators::for_list(&generation, "/api/v1/books", &other_page).etag
+        );
+        let later = Generation {
+            count: generation.count + 1,
+            ..generation
+        };
+        assert_ne!(
+            first.etag,
+            Validators::for_list(&later, "/api/v1/books", &query).etag
+        );
+    }
+
+    #[test]
+    fn if_none_match_wins() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let validators = Validators {
+            etag: "\"abc\"".parse().unwrap(),
+            last_modified: modified,
+        };
+        let mut map = HeaderMap::new();
+        map.typed_insert(LastModified::from(modified));
+        let since = map["last-modified"].to_str().unwrap();
+
+        assert!(!conditions(&[]).not_modified(&validators));
+        assert!(conditions(&[("if-none-match", "\"abc\"")]).not_modified(&validators));
+        assert!(conditions(&[("if-none-match", "*")]).not_modified(&validators));
+        assert!(!conditions(&[("if-none-match", "\"xyz\"")]).not_modified(&validators));
+        assert!(conditions(&[("if-modified-since", since)]).not_modified(&validators));
+        assert!(
+            !conditions(&[("if-none-match", "\"xyz\""), ("if-modified-since", since)])
+                .not_modified(&validators)
+        );
+    }
+}
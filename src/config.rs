@@ -0,0 +1,333 @@
+//! Runtime configuration, read from environment variables.
+//!
+//! `main` loads `.env` first, so any of these can be set there instead.
+//! A value that can't be parsed stops the service from starting, naming the
+//! variable at fault.
+
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Settings for the database and its connection pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbConfig {
+    /// `DATABASE_URL`: where the database lives, e.g. `sqlite://books.db`
+    /// or `sqlite::memory:`. File databases are created if missing.
+    pub url: String,
+    /// `DATABASE_MAX_CONNECTIONS`: the most connections the pool will open.
+    pub max_connections: u32,
+    /// `DATABASE_MIN_CONNECTIONS`: connections the pool keeps open when idle.
+    pub min_connections: u32,
+    /// `DATABASE_ACQUIRE_TIMEOUT_SECS`: how long a request waits for a
+    /// free connection before failing.
+    pub acquire_timeout: Duration,
+    /// `DATABASE_IDLE_TIMEOUT_SECS`: how long an unused connection is kept
+    /// before being closed. Zero keeps idle connections forever.
+    pub idle_timeout: Option<Duration>,
+    /// `DATABASE_BUSY_TIMEOUT_MS`: how long SQLite waits on a locked
+    /// database before giving up.
+    pub busy_timeout: Duration,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite://books.db".to_string(),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DbConfig {
+    /// Read the configuration from the process environment.
+    /// Unset variables fall back to the defaults.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build the configuration from an arbitrary variable lookup.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        let idle_secs = parse(
+            &lookup,
+            "DATABASE_IDLE_TIMEOUT_SECS",
+            defaults.idle_timeout.map_or(0, |d| d.as_secs()),
+        )?;
+        Ok(Self {
+            url: lookup("DATABASE_URL").unwrap_or(defaults.url),
+            max_connections: parse(
+                &lookup,
+                "DATABASE_MAX_CONNECTIONS",
+                defaults.max_connections,
+            )?,
+            min_connections: parse(
+                &lookup,
+                "DATABASE_MIN_CONNECTIONS",
+                defaults.min_connections,
+            )?,
+            acquire_timeout: Duration::from_secs(parse(
+                &lookup,
+                "DATABASE_ACQUIRE_TIMEOUT_SECS",
+                defaults.acquire_timeout.as_secs(),
+            )?),
+            idle_timeout: (idle_secs > 0).then(|| Duration::from_secs(idle_secs)),
+            busy_timeout: Duration::from_millis(parse(
+                &lookup,
+                "DATABASE_BUSY_TIMEOUT_MS",
+                defaults.busy_timeout.as_millis() as u64,
+            )?),
+        })
+    }
+
+    /// Is this an in-memory database, which vanishes when its last
+    /// connection closes?
+    pub fn is_in_memory(&self) -> bool {
+        self.url.contains(":memory:") || self.url.contains("mode=memory")
+    }
+}
+
+/// Which kind of cache sits in front of the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// Don't cache; every read goes to the database.
+    None,
+    /// Cache every entry until it expires or is invalidated, without limit.
+    Memory,
+    /// Cache up to `CacheConfig::capacity` entries, evicting the least
+    /// recently used.
+    Bounded,
+}
+
+impl FromStr for CacheKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(CacheKind::None),
+            "memory" => Ok(CacheKind::Memory),
+            "bounded" | "lru" => Ok(CacheKind::Bounded),
+            _ => Err("expected none, memory, bounded or lru".to_string()),
+        }
+    }
+}
+
+/// Settings for the book cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// `BOOK_CACHE`: `none`, `memory` or `bounded` (also spelled `lru`).
+    pub kind: CacheKind,
+    /// `BOOK_CACHE_CAPACITY`: the most entries a bounded cache holds.
+    pub capacity: usize,
+    /// `BOOK_CACHE_TTL_SECS`: how long a single book stays cached.
+    pub book_ttl: Duration,
+    /// `BOOK_CACHE_QUERY_TTL_SECS`: how long a page or search result
+    /// stays cached.
+    pub query_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            kind: CacheKind::Bounded,
+            capacity: 1000,
+            book_ttl: Duration::from_secs(300),
+            query_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Read the configuration from the process environment.
+    /// Unset variables fall back to the defaults.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build the configuration from an arbitrary variable lookup.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            kind: parse(&lookup, "BOOK_CACHE", defaults.kind)?,
+            capacity: parse(&lookup, "BOOK_CACHE_CAPACITY", defaults.capacity)?,
+            book_ttl: Duration::from_secs(parse(
+                &lookup,
+                "BOOK_CACHE_TTL_SECS",
+                defaults.book_ttl.as_secs(),
+            )?),
+            query_ttl: Duration::from_secs(parse(
+                &lookup,
+                "BOOK_CACHE_QUERY_TTL_SECS",
+                defaults.query_ttl.as_secs(),
+            )?),
+        })
+    }
+}
+
+/// Settings for deleted books, and who may purge them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashConfig {
+    /// `TRASH_RETENTION_DAYS`: how long a deleted book stays restorable
+    /// before a purge may remove it for good.
+    pub retention: Duration,
+    /// `ADMIN_TOKEN`: the bearer token a purge must present. Nobody may
+    /// purge unless one is set.
+    pub admin_token: Option<String>,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(30 * 24 * 60 * 60),
+            admin_token: None,
+        }
+    }
+}
+
+impl TrashConfig {
+    /// Read the configuration from the process environment.
+    /// Unset variables fall back to the defaults.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build the configuration from an arbitrary variable lookup.
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let defaults = Self::default();
+        let days = parse(
+            &lookup,
+            "TRASH_RETENTION_DAYS",
+            defaults.retention.as_secs() / (24 * 60 * 60),
+        )?;
+        let admin_token = lookup("ADMIN_TOKEN")
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .or(defaults.admin_token);
+        Ok(Self {
+            retention: Duration::from_secs(days * 24 * 60 * 60),
+            admin_token,
+        })
+    }
+
+    /// Does `token` allow purging the trash? The comparison takes the same
+    /// time wherever the tokens differ, so it doesn't leak the admin token.
+    pub fn is_admin(&self, token: Option<&str>) -> bool {
+        let (Some(expected), Some(token)) = (&self.admin_token, token) else {
+            return false;
+        };
+        expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Parse the variable `name` if it is set, otherwise return `default`.
+fn parse<T: FromStr>(lookup: &impl Fn(&str) -> Option<String>, name: &str, default: T) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match lookup(name) {
+        None => Ok(default),
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("{name}={value:?}: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<DbConfig> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        DbConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(config(&[]).unwrap(), DbConfig::default());
+    }
+
+    #[test]
+    fn overrides() {
+        let config = config(&[
+            ("DATABASE_URL", "sqlite::memory:"),
+            ("DATABASE_MAX_CONNECTIONS", "4"),
+            ("DATABASE_ACQUIRE_TIMEOUT_SECS", "2"),
+            ("DATABASE_IDLE_TIMEOUT_SECS", "0"),
+            ("DATABASE_BUSY_TIMEOUT_MS", "250"),
+        ])
+        .unwrap();
+        assert!(config.is_in_memory());
+        assert_eq!(config.max_connections, 4);
+        assert_eq!(config.acquire_timeout, Duration::from_secs(2));
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.busy_timeout, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let err = config(&[("DATABASE_MAX_CONNECTIONS", "lots")]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("DATABASE_MAX_CONNECTIONS=\"lots\""));
+    }
+
+    #[test]
+    fn cache_config() {
+        let lookup = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(
+            CacheConfig::from_lookup(lookup(&[])).unwrap(),
+            CacheConfig::default()
+        );
+        let config = CacheConfig::from_lookup(lookup(&[
+            ("BOOK_CACHE", "None"),
+            ("BOOK_CACHE_CAPACITY", "10"),
+            ("BOOK_CACHE_QUERY_TTL_SECS", "5"),
+        ]))
+        .unwrap();
+        assert_eq!(config.kind, CacheKind::None);
+        assert_eq!(config.capacity, 10);
+        assert_eq!(config.book_ttl, Duration::from_secs(300));
+        assert_eq!(config.query_ttl, Duration::from_secs(5));
+        assert_eq!("LRU".parse(), Ok(CacheKind::Bounded));
+        assert!(CacheConfig::from_lookup(lookup(&[("BOOK_CACHE", "redis")])).is_err());
+    }
+
+    #[test]
+    fn trash_config() {
+        let config = TrashConfig::from_lookup(|_| None).unwrap();
+        assert_eq!(config, TrashConfig::default());
+        assert!(!config.is_admin(None));
+        assert!(!config.is_admin(Some("")));
+
+        let config = TrashConfig::from_lookup(|name| match name {
+            "TRASH_RETENTION_DAYS" => Some("7".to_string()),
+            "ADMIN_TOKEN" => Some(" s3cret ".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.retention, Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(config.is_admin(Some("s3cret")));
+        assert!(!config.is_admin(Some("s3cre")));
+        assert!(!config.is_admin(Some("s3creT")));
+        assert!(!config.is_admin(None));
+    }
+}
@@ -0,0 +1,948 @@
+//! Filter expressions for the book listing, such as
+//! `title contains "Rust" and created_at > 2024-01-01`.
+//!
+//! An expression compares fields of a book with values, and combines the
+//! comparisons with `and`, `or`, `not` and parentheses. `and` binds more
+//! tightly than `or`; keywords and field names ignore case.
+//!
+//! * Comparisons are `=`, `!=`, `<`, `<=`, `>` and `>=`. Text fields can
+//!   also be matched, ignoring case, with `contains`, `startswith` and
+//!   `endswith`, and any field can be tested with `is null` or
+//!   `is not null`. A missing value never matches a comparison.
+//! * Text values are quoted, with `\"` and `\\` for a quote and a
+//!   backslash. Numbers are whole numbers, dates are written `2024-01-31`,
+//!   and times are RFC 3339 (`2024-01-31T09:30:00Z`) or a date, meaning
+//!   midnight UTC.
+//!
+//! Only the fields in `Field` can be named, each of which maps onto a fixed
+//! piece of SQL. Values are always bound as query parameters, so nothing
+//! the caller writes is ever spliced into the SQL itself.
+
+use chrono::{DateTime, NaiveDate, NaiveTime, SecondsFormat, Utc};
+use serde::{Serialize, Serializer};
+use sqlx::{Database, Encode, QueryBuilder, Type};
+use std::fmt;
+
+/// The longest filter expression we accept, in characters.
+pub const MAX_FILTER_LEN: usize = 1000;
+
+/// The most comparisons one expression may make.
+const MAX_CONDITIONS: usize = 32;
+
+/// The deepest that `not` and parentheses may nest.
+const MAX_DEPTH: usize = 16;
+
+/// A field of a book that filters may name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    Id,
+    Title,
+    Author,
+    Isbn13,
+    Isbn10,
+    Publisher,
+    PublishedOn,
+    Edition,
+    PageCount,
+    Language,
+    Series,
+    SeriesVolume,
+    Version,
+    CreatedAt,
+    CreatedBy,
+    UpdatedAt,
+    UpdatedBy,
+}
+
+/// The type of a field, which decides the values it is compared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Integer,
+    Text,
+    Date,
+    Timestamp,
+}
+
+impl Field {
+    /// Every field, in the order they are listed in errors.
+    const ALL: [Field; 17] = [
+        Field::Id,
+        Field::Title,
+        Field::Author,
+        Field::Isbn13,
+        Field::Isbn10,
+        Field::Publisher,
+        Field::PublishedOn,
+        Field::Edition,
+        Field::PageCount,
+        Field::Language,
+        Field::Series,
+        Field::SeriesVolume,
+        Field::Version,
+        Field::CreatedAt,
+        Field::CreatedBy,
+        Field::UpdatedAt,
+        Field::UpdatedBy,
+    ];
+
+    /// The name used in expressions, which is also the name in JSON.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Title => "title",
+            Field::Author => "author",
+            Field::Isbn13 => "isbn13",
+            Field::Isbn10 => "isbn10",
+            Field::Publisher => "publisher",
+            Field::PublishedOn => "published_on",
+            Field::Edition => "edition",
+            Field::PageCount => "page_count",
+            Field::Language => "language",
+            Field::Series => "series",
+            Field::SeriesVolume => "series_volume",
+            Field::Version => "version",
+            Field::CreatedAt => "created_at",
+            Field::CreatedBy => "created_by",
+            Field::UpdatedAt => "updated_at",
+            Field::UpdatedBy => "updated_by",
+        }
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Id | Field::PageCount | Field::SeriesVolume | Field::Version => Kind::Integer,
+            Field::PublishedOn => Kind::Date,
+            Field::CreatedAt | Field::UpdatedAt => Kind::Timestamp,
+            _ => Kind::Text,
+        }
+    }
+
+    /// The SQL for the field, in a query over `books`. Only these fixed
+    /// strings are ever emitted, so they are safe to splice into SQL.
+    fn column(self) -> &'static str {
+        match self {
+            Field::Publisher => {
+                "(SELECT publishers.name FROM publishers WHERE publishers.id = books.publisher_id)"
+            }
+            Field::Series => "(SELECT series.name FROM series WHERE series.id = books.series_id)",
+            field => field.name(),
+        }
+    }
+
+    fn parse(name: &str) -> Option<Field> {
+        Field::ALL
+            .into_iter()
+            .find(|field| field.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// How a field is compared with a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Contains => "contains",
+            Op::StartsWith => "startswith",
+            Op::EndsWith => "endswith",
+        }
+    }
+
+    /// Does this op match text patterns, rather than compare values?
+    fn is_match(self) -> bool {
+        matches!(self, Op::Contains | Op::StartsWith | Op::EndsWith)
+    }
+}
+
+/// A value to compare a field with, of the field's kind.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Integer(i64),
+    Text(String),
+    Date(NaiveDate),
+    Timestamp(DateTime<Utc>),
+}
+
+/// A parsed filter expression.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FilterExpr {
+    /// `field op value`
+    Compare(Field, Op, Value),
+    /// `field is null`, or `field is not null` if the flag is false
+    IsNull(Field, bool),
+    Not(Box<FilterExpr>),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+}
+
+/// Why an expression couldn't be parsed, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    /// Where the problem is, counting characters from 1
+    pub position: usize,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at character {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+/// How a backend binds a time to compare with its stored timestamps.
+pub trait Timestamps: Database {
+    fn push_timestamp(sql: &mut QueryBuilder<'_, Self>, time: DateTime<Utc>);
+}
+
+impl FilterExpr {
+    /// Parse an expression.
+    ///
+    /// ## Arguments
+    /// * `text` - the expression, such as `title contains "Rust"`
+    ///
+    /// ## Returns
+    /// * The expression, or where and why it is invalid.
+    pub fn parse(text: &str) -> Result<FilterExpr, FilterError> {
+        let count = text.chars().count();
+        if count > MAX_FILTER_LEN {
+            return Err(FilterError {
+                position: MAX_FILTER_LEN + 1,
+                message: format!("must be at most {MAX_FILTER_LEN} characters"),
+            });
+        }
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            next: 0,
+            end: count,
+            depth: 0,
+            conditions: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(parser.error_at(token.position, "expected \"and\" or \"or\"")),
+        }
+    }
+
+    /// Add the expression to `sql` as a condition, binding every value as a
+    /// parameter.
+    pub fn push_sql<'a, DB>(&self, sql: &mut QueryBuilder<'a, DB>)
+    where
+        DB: Timestamps,
+        String: Encode<'a, DB> + Type<DB>,
+        i64: Encode<'a, DB> + Type<DB>,
+        NaiveDate: Encode<'a, DB> + Type<DB>,
+    {
+        match self {
+            FilterExpr::Compare(field, op, value) if op.is_match() => {
+                let Value::Text(text) = value else {
+                    unreachable!("the parser only matches text");
+                };
+                let escaped = text
+                    .to_lowercase()
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_");
+                let pattern = match op {
+                    Op::Contains => format!("%{escaped}%"),
+                    Op::StartsWith => format!("{escaped}%"),
+                    _ => format!("%{escaped}"),
+                };
+                sql.push("lower(")
+                    .push(field.column())
+                    .push(") LIKE ")
+                    .push_bind(pattern)
+                    .push(" ESCAPE '\\'");
+            }
+            FilterExpr::Compare(field, op, value) => {
+                let op = match op {
+                    Op::Ne => "<>",
+                    op => op.as_str(),
+                };
+                sql.push(field.column()).push(" ").push(op).push(" ");
+                match value {
+                    Value::Integer(n) => {
+                        sql.push_bind(*n);
+                    }
+                    Value::Text(text) => {
+                        sql.push_bind(text.clone());
+                    }
+                    Value::Date(date) => {
+                        sql.push_bind(*date);
+                    }
+                    Value::Timestamp(time) => DB::push_timestamp(sql, *time),
+                }
+            }
+            FilterExpr::IsNull(field, null) => {
+                sql.push(field.column())
+                    .push(if *null { " IS NULL" } else { " IS NOT NULL" });
+            }
+            FilterExpr::Not(expr) => {
+                sql.push("NOT (");
+                expr.push_sql(sql);
+                sql.push(")");
+            }
+            FilterExpr::And(exprs) | FilterExpr::Or(exprs) => {
+                let joiner = if matches!(self, FilterExpr::And(_)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                sql.push("(");
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        sql.push(joiner);
+                    }
+                    expr.push_sql(sql);
+                }
+                sql.push(")");
+            }
+        }
+    }
+}
+
+/// Written in a form that parses back to the same expression.
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Nested `and`s and `or`s are always parenthesized, so that they
+        // parse back with the same grouping.
+        let nested = |f: &mut fmt::Formatter<'_>, expr: &FilterExpr| match expr {
+            FilterExpr::And(_) | FilterExpr::Or(_) => write!(f, "({expr})"),
+            expr => write!(f, "{expr}"),
+        };
+        match self {
+            FilterExpr::Compare(field, op, value) => {
+                write!(f, "{} {} ", field.name(), op.as_str())?;
+                match value {
+                    Value::Integer(n) => write!(f, "{n}"),
+                    Value::Text(text) => {
+                        write!(f, "\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+                    }
+                    Value::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+                    Value::Timestamp(time) => {
+                        write!(f, "{}", time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                    }
+                }
+            }
+            FilterExpr::IsNull(field, true) => write!(f, "{} is null", field.name()),
+            FilterExpr::IsNull(field, false) => write!(f, "{} is not null", field.name()),
+            FilterExpr::Not(expr) => {
+                write!(f, "not ")?;
+                nested(f, expr)
+            }
+            FilterExpr::And(exprs) | FilterExpr::Or(exprs) => {
+                let joiner = if matches!(self, FilterExpr::And(_)) {
+                    " and "
+                } else {
+                    " or "
+                };
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{joiner}")?;
+                    }
+                    nested(f, expr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Serialized as its text, for the `filter` query parameter.
+impl Serialize for FilterExpr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    /// A field name, keyword, number, date or time
+    Word(String),
+    /// A quoted string, unescaped
+    Text(String),
+    /// `=`, `!=`, `<`, `<=`, `>` or `>=`
+    Symbol(&'static str),
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// Where the token starts, counting characters from 1
+    position: usize,
+}
+
+/// Can `c` appear in a bare word?
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '+')
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, FilterError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let position = i + 1;
+        let error = |message: String| FilterError { position, message };
+        let kind = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                TokenKind::Open
+            }
+            ')' => {
+                i += 1;
+                TokenKind::Close
+            }
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(error("the string is never closed".to_string())),
+                        Some('"') => break,
+                        Some('\\') => match chars.get(i + 1) {
+                            Some(&escaped @ ('"' | '\\')) => {
+                                value.push(escaped);
+                                i += 1;
+                            }
+                            _ => {
+                                return Err(FilterError {
+                                    position: i + 1,
+                                    message: "only \\\" and \\\\ may be escaped".to_string(),
+                                })
+                            }
+                        },
+                        Some(&c) => value.push(c),
+                    }
+                    i += 1;
+                }
+                i += 1;
+                TokenKind::Text(value)
+            }
+            '=' | '!' | '<' | '>' => {
+                let equals = chars.get(i + 1) == Some(&'=');
+                let symbol = match (c, equals) {
+                    ('=', _) => "=",
+                    ('!', true) => "!=",
+                    ('<', false) => "<",
+                    ('<', true) => "<=",
+                    ('>', false) => ">",
+                    ('>', true) => ">=",
+                    _ => return Err(error("expected \"!=\"".to_string())),
+                };
+                i += symbol.len();
+                TokenKind::Symbol(symbol)
+            }
+            c if is_word_char(c) => {
+                let start = i;
+                while i < chars.len() && is_word_char(chars[i]) {
+                    i += 1;
+                }
+                TokenKind::Word(chars[start..i].iter().collect())
+            }
+            c => return Err(error(format!("unexpected {c:?}"))),
+        };
+        tokens.push(Token { kind, position });
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+    /// The length of the expression, for errors at its end
+    end: usize,
+    depth: usize,
+    conditions: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    /// Where the next token starts, or just past the end.
+    fn position(&self) -> usize {
+        self.peek().map_or(self.end + 1, |token| token.position)
+    }
+
+    fn error_at(&self, position: usize, message: impl ToString) -> FilterError {
+        FilterError {
+            position,
+            message: message.to_string(),
+        }
+    }
+
+    fn error(&self, message: impl ToString) -> FilterError {
+        self.error_at(self.position(), message)
+    }
+
+    /// Take the next token if it is the keyword `keyword`.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                ..
+            }) if word.eq_ignore_ascii_case(keyword) => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `or := and ("or" and)*`
+    fn or(&mut self) -> Result<FilterExpr, FilterError> {
+        let mut exprs = vec![self.and()?];
+        while self.keyword("or") {
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            FilterExpr::Or(exprs)
+        })
+    }
+
+    /// `and := unary ("and" unary)*`
+    fn and(&mut self) -> Result<FilterExpr, FilterError> {
+        let mut exprs = vec![self.unary()?];
+        while self.keyword("and") {
+            exprs.push(self.unary()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.remove(0)
+        } else {
+            FilterExpr::And(exprs)
+        })
+    }
+
+    /// `unary := "not" unary | "(" or ")" | comparison`
+    fn unary(&mut self) -> Result<FilterExpr, FilterError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(format!("may nest at most {MAX_DEPTH} deep")));
+        }
+        self.depth += 1;
+        let expr = if self.keyword("not") {
+            FilterExpr::Not(Box::new(self.unary()?))
+        } else if matches!(self.peek().map(|t| &t.kind), Some(TokenKind::Open)) {
+            let open = self.position();
+            self.next += 1;
+            let expr = self.or()?;
+            match self.peek().map(|t| &t.kind) {
+                Some(TokenKind::Close) => self.next += 1,
+                Some(_) => return Err(self.error("expected \"and\", \"or\" or \")\"")),
+                None => return Err(self.error_at(open, "the \"(\" is never closed")),
+            }
+            expr
+        } else {
+            self.comparison()?
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    /// `comparison := field op value | field "is" ["not"] "null"`
+    fn comparison(&mut self) -> Result<FilterExpr, FilterError> {
+        let position = self.position();
+        let field = match self.peek().map(|t| &t.kind) {
+            Some(TokenKind::Word(word)) => Field::parse(word).ok_or_else(|| {
+                let fields: Vec<&str> = Field::ALL.iter().map(|f| f.name()).collect();
+                self.error(format!(
+                    "there is no field {word:?}; use one of {}",
+                    fields.join(", ")
+                ))
+            })?,
+            _ => return Err(self.error("expected a field name, \"not\" or \"(\"")),
+        };
+        self.next += 1;
+        self.conditions += 1;
+        if self.conditions > MAX_CONDITIONS {
+            return Err(self.error_at(
+                position,
+                format!("may make at most {MAX_CONDITIONS} comparisons"),
+            ));
+        }
+
+        if self.keyword("is") {
+            let null = !self.keyword("not");
+            if !self.keyword("null") {
+                return Err(self.error("expected \"null\""));
+            }
+            return Ok(FilterExpr::IsNull(field, null));
+        }
+        let op_position = self.position();
+        let op = match self.peek().map(|t| &t.kind) {
+            Some(TokenKind::Symbol(symbol)) => match *symbol {
+                "=" => Op::Eq,
+                "!=" => Op::Ne,
+                "<" => Op::Lt,
+                "<=" => Op::Le,
+                ">" => Op::Gt,
+                _ => Op::Ge,
+            },
+            Some(TokenKind::Word(word)) if word.eq_ignore_ascii_case("contains") => Op::Contains,
+            Some(TokenKind::Word(word)) if word.eq_ignore_ascii_case("startswith") => {
+                Op::StartsWith
+            }
+            Some(TokenKind::Word(word)) if word.eq_ignore_ascii_case("endswith") => Op::EndsWith,
+            _ => {
+                return Err(self.error(format!(
+                    "expected a comparison after {}, such as = or contains",
+                    field.name()
+                )))
+            }
+        };
+        self.next += 1;
+        if op.is_match() && field.kind() != Kind::Text {
+            return Err(self.error_at(
+                op_position,
+                format!(
+                    "{} only works on text, and {} isn't",
+                    op.as_str(),
+                    field.name()
+                ),
+            ));
+        }
+        let value = self.value(field)?;
+        Ok(FilterExpr::Compare(field, op, value))
+    }
+
+    /// A value of the kind `field` holds.
+    fn value(&mut self, field: Field) -> Result<Value, FilterError> {
+        let name = field.name();
+        let token = self.peek().map(|t| t.kind.clone());
+        let value = match (field.kind(), token) {
+            (Kind::Text, Some(TokenKind::Text(text))) => Some(Value::Text(text)),
+            (Kind::Integer, Some(TokenKind::Word(word))) => word.parse().ok().map(Value::Integer),
+            (Kind::Date, Some(TokenKind::Word(word))) => parse_date(&word).map(Value::Date),
+            (Kind::Timestamp, Some(TokenKind::Word(word))) => {
+                let time = DateTime::parse_from_rfc3339(&word)
+                    .ok()
+                    .map(|time| time.with_timezone(&Utc));
+                time.or_else(|| {
+                    parse_date(&word).map(|date| date.and_time(NaiveTime::MIN).and_utc())
+                })
+                .map(Value::Timestamp)
+            }
+            _ => None,
+        };
+        let Some(value) = value else {
+            return Err(self.error(match field.kind() {
+                Kind::Text => format!("{name} is compared with a quoted string, such as \"Rust\""),
+                Kind::Integer => format!("{name} is compared with a whole number"),
+                Kind::Date => format!("{name} is compared with a date, such as 2024-01-31"),
+                Kind::Timestamp => format!(
+                    "{name} is compared with a date, such as 2024-01-31, or an RFC 3339 time"
+                ),
+            }));
+        };
+        self.next += 1;
+        Ok(value)
+    }
+}
+
+/// A date written as `YYYY-MM-DD`.
+fn parse_date(word: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(word, "%Y-%m-%d").ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::Sqlite;
+
+    fn parse(text: &str) -> FilterExpr {
+        FilterExpr::parse(text).unwrap_or_else(|err| panic!("{text:?} {err}"))
+    }
+
+    fn error(text: &str) -> FilterError {
+        FilterExpr::parse(text).unwrap_err()
+    }
+
+    fn sql(expr: &FilterExpr) -> String {
+        let mut sql = QueryBuilder::<Sqlite>::new("");
+        expr.push_sql(&mut sql);
+        sql.into_sql()
+    }
+
+    #[test]
+    fn parses_comparisons() {
+        let expr = parse(r#"title contains "Rust" and created_at > 2024-01-01"#);
+        let midnight = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_time(NaiveTime::MIN)
+            .and_utc();
+        assert_eq!(
+            expr,
+            FilterExpr::And(vec![
+                FilterExpr::Compare(Field::Title, Op::Contains, Value::Text("Rust".to_string())),
+                FilterExpr::Compare(Field::CreatedAt, Op::Gt, Value::Timestamp(midnight)),
+            ])
+        );
+        assert_eq!(
+            parse("PAGE_COUNT>=100 AND NOT (series IS NULL)"),
+            FilterExpr::And(vec![
+                FilterExpr::Compare(Field::PageCount, Op::Ge, Value::Integer(100)),
+                FilterExpr::Not(Box::new(FilterExpr::IsNull(Field::Series, true))),
+            ])
+        );
+        assert_eq!(
+            parse(r#"author = "O\"Brien, \\Flann""#),
+            FilterExpr::Compare(
+                Field::Author,
+                Op::Eq,
+                Value::Text("O\"Brien, \\Flann".to_string())
+            )
+        );
+        assert!(matches!(
+            parse("updated_at <= 2024-01-31T09:30:00+01:00"),
+            FilterExpr::Compare(Field::UpdatedAt, Op::Le, Value::Timestamp(_))
+        ));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expr = parse("id = 1 or id = 2 and version = 3");
+        let FilterExpr::Or(exprs) = &expr else {
+            panic!("expected an or, got {expr:?}");
+        };
+        assert!(matches!(exprs[1], FilterExpr::And(_)));
+        let expr = parse("(id = 1 or id = 2) and version = 3");
+        assert!(matches!(expr, FilterExpr::And(_)));
+    }
+
+    #[test]
+    fn explains_errors() {
+        assert_eq!(error("").message, "expected a field name, \"not\" or \"(\"");
+        assert_eq!(error("colour = 1").position, 1);
+        assert!(error("colour = 1").message.contains("use one of id, title"));
+        assert_eq!(error("id = \"1\"").position, 6);
+        assert_eq!(
+            error("page_count contains \"1\"").message,
+            "contains only works on text, and page_count isn't"
+        );
+        assert_eq!(
+            error("title = \"Rust").message,
+            "the string is never closed"
+        );
+        assert_eq!(error("(id = 1").message, "the \"(\" is never closed");
+        assert_eq!(error("id = 1 id = 2").position, 8);
+        assert_eq!(error("id ~ 1").message, "unexpected '~'");
+        assert_eq!(error("published_on < 2024-02-30").position, 16);
+        assert!(error(&"(".repeat(MAX_DEPTH + 1)).message.contains("nest"));
+        let many = vec!["id = 1"; MAX_CONDITIONS + 1].join(" or ");
+        assert!(error(&many).message.contains("comparisons"));
+        assert!(error(&" ".repeat(MAX_FILTER_LEN + 1))
+            .message
+            .contains("at most"));
+    }
+
+    #[test]
+    fn compiles_to_parameterized_sql() {
+        let expr = parse(
+            r#"title startswith "50%_off" or not (page_count < 10 and publisher is not null)"#,
+        );
+        assert_eq!(
+            sql(&expr),
+            "(lower(title) LIKE ? ESCAPE '\\' OR NOT ((page_count < ? AND \
+             (SELECT publishers.name FROM publishers WHERE publishers.id = books.publisher_id) \
+             IS NOT NULL)))"
+        );
+        let injection = parse(r#"title = "x'; DROP TABLE books; --""#);
+        assert_eq!(sql(&injection), "title = ?");
+        assert_eq!(sql(&parse("isbn13 != \"1\"")), "isbn13 <> ?");
+    }
+
+    #[test]
+    fn displays_in_parseable_form() {
+        for text in [
+            r#"title contains "Rust" and created_at > 2024-01-01T00:00:00Z"#,
+            r#"not (id = 1 or author = "a \"b\" \\ c") and series is null"#,
+            "not not (page_count >= -5 and (version = 1 or version = 2))",
+        ] {
+            assert_eq!(parse(text).to_string(), text);
+        }
+    }
+
+    /// A small, seeded xorshift generator, so the fuzz tests are repeatable
+    /// without another dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len())]
+        }
+    }
+
+    /// A random, valid expression at most `depth` deep.
+    fn random_expr(rng: &mut Rng, depth: usize) -> FilterExpr {
+        let leaf = depth == 0 || rng.below(3) == 0;
+        if leaf {
+            let field = *rng.pick(&Field::ALL);
+            if rng.below(6) == 0 {
+                return FilterExpr::IsNull(field, rng.below(2) == 0);
+            }
+            let ops = [Op::Eq, Op::Ne, Op::Lt, Op::Le, Op::Gt, Op::Ge];
+            let value = match field.kind() {
+                Kind::Integer => Value::Integer(rng.next() as i64),
+                Kind::Date => Value::Date(
+                    NaiveDate::from_num_days_from_ce_opt(rng.below(800_000) as i32 + 1).unwrap(),
+                ),
+                Kind::Timestamp => Value::Timestamp(
+                    DateTime::from_timestamp(rng.below(4_000_000_000) as i64, 0).unwrap(),
+                ),
+                Kind::Text => {
+                    let chars = ['a', 'Z', ' ', '"', '\\', '%', '_', '\'', 'é', ';'];
+                    let len = rng.below(8);
+                    Value::Text((0..len).map(|_| *rng.pick(&chars)).collect())
+                }
+            };
+            let op = if field.kind() == Kind::Text && rng.below(2) == 0 {
+                *rng.pick(&[Op::Contains, Op::StartsWith, Op::EndsWith])
+            } else {
+                *rng.pick(&ops)
+            };
+            return FilterExpr::Compare(field, op, value);
+        }
+        match rng.below(3) {
+            0 => FilterExpr::Not(Box::new(random_expr(rng, depth - 1))),
+            kind => {
+                let exprs = (0..2 + rng.below(2))
+                    .map(|_| random_expr(rng, depth - 1))
+                    .collect();
+                if kind == 1 {
+                    FilterExpr::And(exprs)
+                } else {
+                    FilterExpr::Or(exprs)
+                }
+            }
+        }
+    }
+
+    /// Counts the comparisons in an expression.
+    fn conditions(expr: &FilterExpr) -> usize {
+        match expr {
+            FilterExpr::Compare(..) | FilterExpr::IsNull(..) => 1,
+            FilterExpr::Not(expr) => conditions(expr),
+            FilterExpr::And(exprs) | FilterExpr::Or(exprs) => exprs.iter().map(conditions).sum(),
+        }
+    }
+
+    #[test]
+    fn fuzz_round_trips() {
+        let mut rng = Rng(0x5eed_b00c);
+        for _ in 0..2000 {
+            let expr = random_expr(&mut rng, 4);
+            if conditions(&expr) > MAX_CONDITIONS {
+                continue;
+            }
+            let text = expr.to_string();
+            assert_eq!(FilterExpr::parse(&text), Ok(expr.clone()), "{text}");
+            // Every value becomes a parameter
+            let mut sql = QueryBuilder::<Sqlite>::new("");
+            expr.push_sql(&mut sql);
+            let sql = sql.into_sql();
+            assert!(!sql.contains('"') && !sql.contains(';'), "{sql}");
+        }
+    }
+
+    #[test]
+    fn fuzz_never_panics() {
+        let mut rng = Rng(0xf00d_cafe);
+        let pieces = [
+            "title",
+            "id",
+            "created_at",
+            "published_on",
+            "and",
+            "or",
+            "not",
+            "is",
+            "null",
+            "(",
+            ")",
+            "\"",
+            "\\",
+            "=",
+            "!",
+            "<",
+            ">=",
+            "contains",
+            "2024-01-01",
+            "-1",
+            "1e9",
+            "99999999999999999999",
+            "\"Rust\"",
+            " ",
+            "é",
+            "\u{0}",
+            "T",
+            ":",
+            "+",
+            "%",
+        ];
+        for _ in 0..5000 {
+            let text: String = (0..rng.below(16)).map(|_| *rng.pick(&pieces)).collect();
+            if let Err(err) = FilterExpr::parse(&text) {
+                assert!(
+                    (1..=text.chars().count() + 1).contains(&err.position),
+                    "{text:?} {err}"
+                );
+            }
+        }
+        // Mangle valid expressions one character at a time
+        for _ in 0..2000 {
+            let mut chars: Vec<char> = random_expr(&mut rng, 3).to_string().chars().collect();
+            let at = rng.below(chars.len() + 1);
+            match rng.below(3) {
+                0 if at < chars.len() => {
+                    chars.remove(at);
+                }
+                _ => chars.insert(at, *rng.pick(&['(', ')', '"', '\\', 'x', ' ', '=', '!'])),
+            }
+            let text: String = chars.into_iter().collect();
+            let _ = FilterExpr::parse(&text);
+        }
+    }
+}
@@ -0,0 +1,2593 @@
+//! The books database.
+//!
+//! Storage is reached through the `BookRepository` trait, with SQLite and
+//! PostgreSQL implementations chosen by the scheme of `DATABASE_URL`. The
+//! free functions in this module take the `AppState` and wrap its
+//! repository with the behavior that is the same for every backend:
+//! validation, and keeping the state's cache up to date. Handlers should
+//! call these rather than the repository directly.
+//!
+//! SQLite databases are normally a file on disk, created and migrated on
+//! start-up. An in-memory database (`sqlite::memory:`) is rebuilt from
+//! scratch each time, which is what the tests use.
+
+mod filter;
+mod postgres;
+mod sqlite;
+
+pub use filter::FilterExpr;
+pub use postgres::PostgresRepository;
+pub use sqlite::SqliteRepository;
+
+use crate::cache::{CacheKey, CacheValue};
+use crate::config::DbConfig;
+use crate::error::{Error, FieldError, Result};
+use crate::state::AppState;
+use crate::validation::{validate_isbn, validate_tag};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{FromRow, QueryBuilder};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Represents a book, taken from the books table.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct Book {
+    /// The book's primary key ID
+    pub id: i32,
+    /// The book's title
+    pub title: String,
+    /// The book's credits as one string, built from `authors`: each name
+    /// as "Surname, Forename", followed by the role in brackets unless the
+    /// person is an author, separated by "; "
+    pub author: String,
+    /// The people credited on the book, in order
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub authors: Vec<Credit>,
+    /// Starts at 1, and goes up by one with every change. Updates and
+    /// deletes must name the version they expect to change.
+    pub version: i64,
+    /// When the book was added
+    pub created_at: DateTime<Utc>,
+    /// Who added the book
+    pub created_by: String,
+    /// When the book was last changed
+    pub updated_at: DateTime<Utc>,
+    /// Who last changed the book
+    pub updated_by: String,
+    /// When the book was moved to the trash, if it has been deleted
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// The book's ISBN-13, if it has one
+    #[serde(default)]
+    pub isbn13: Option<String>,
+    /// The book's ISBN-10, if it has an ISBN from before ISBN-13 took over
+    #[serde(default)]
+    pub isbn10: Option<String>,
+    /// The name of the edition's publisher
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub publisher: Option<String>,
+    /// When the edition was published
+    #[serde(default)]
+    pub published_on: Option<NaiveDate>,
+    /// The edition statement, such as "2nd" or "Revised"
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// The number of pages
+    #[serde(default)]
+    pub page_count: Option<i32>,
+    /// The language the edition is written in, as an ISO 639 code such as
+    /// "en"
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The name of the series the book is part of
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub series: Option<String>,
+    /// The book's number in its series
+    #[serde(default)]
+    pub series_volume: Option<i32>,
+    /// The subjects and genres the book is filed under, by name
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// What a person did for a book.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthorRole {
+    #[default]
+    Author,
+    Editor,
+    Translator,
+    Illustrator,
+}
+
+impl AuthorRole {
+    /// Every role, in the order they are documented.
+    pub const ALL: [AuthorRole; 4] = [
+        AuthorRole::Author,
+        AuthorRole::Editor,
+        AuthorRole::Translator,
+        AuthorRole::Illustrator,
+    ];
+
+    /// The name used in JSON, display strings and the `role` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthorRole::Author => "author",
+            AuthorRole::Editor => "editor",
+            AuthorRole::Translator => "translator",
+            AuthorRole::Illustrator => "illustrator",
+        }
+    }
+
+    /// The role called `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|role| role.as_str().eq_ignore_ascii_case(name))
+    }
+
+    fn from_db(role: &str) -> Result<Self> {
+        Self::from_name(role).ok_or_else(|| {
+            Error::Database(sqlx::Error::Decode(
+                format!("unknown book_authors role {role:?}").into(),
+            ))
+        })
+    }
+}
+
+/// A person who can be credited on books, taken from the authors table.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, PartialEq, Eq, ToSchema)]
+pub struct Author {
+    /// The author's primary key ID
+    pub id: i32,
+    /// The author's name, as "Surname, Forename"
+    pub name: String,
+}
+
+/// The fields needed to create or rename an author.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct NewAuthor {
+    /// The author's name, as "Surname, Forename"
+    pub name: String,
+}
+
+/// A person credited on a book.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct Credit {
+    /// The author's ID
+    pub id: i32,
+    /// The author's name
+    pub name: String,
+    /// What they did for the book
+    pub role: AuthorRole,
+}
+
+/// A person to credit on a book that is being written: an existing author
+/// by ID, or by name, in which case an author with that name is found
+/// (ignoring case) or added.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct NewCredit {
+    /// The ID of an existing author
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    /// The author's name, as "Surname, Forename", if `id` isn't given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// What they did for the book (default `author`)
+    #[serde(default)]
+    pub role: AuthorRole,
+}
+
+/// Write credits the way `Book::author` shows them.
+///
+/// ## Arguments
+/// * `credits` - each person's name and role, in order
+///
+/// ## Returns
+/// * The names separated by "; ", each followed by its role in brackets
+///   unless that is `author`.
+pub fn credit_line<'a>(credits: impl IntoIterator<Item = (&'a str, AuthorRole)>) -> String {
+    credits
+        .into_iter()
+        .map(|(name, role)| match role {
+            AuthorRole::Author => name.to_string(),
+            role => format!("{name} ({})", role.as_str()),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The fields needed to create a book. The database assigns the ID.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct NewBook {
+    /// The book's title
+    pub title: String,
+    /// The book's credits, written as `Book::author` shows them: usually
+    /// just "Surname, Forename". Ignored if `authors` is given.
+    #[serde(default)]
+    pub author: String,
+    /// The people to credit, in order, instead of `author`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<NewCredit>,
+    /// The book's ISBN-10 or ISBN-13, if it has one. Stored as an ISBN-13.
+    #[serde(default)]
+    pub isbn: Option<String>,
+    /// The name of the edition's publisher. One of that name is found,
+    /// ignoring case, or added.
+    #[serde(default)]
+    pub publisher: Option<String>,
+    /// When the edition was published
+    #[serde(default)]
+    pub published_on: Option<NaiveDate>,
+    /// The edition statement, such as "2nd" or "Revised"
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// The number of pages
+    #[serde(default)]
+    pub page_count: Option<i32>,
+    /// The language, as an ISO 639 code such as "en"
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The name of the series the book is part of, found or added like the
+    /// publisher
+    #[serde(default)]
+    pub series: Option<String>,
+    /// The book's number in its series
+    #[serde(default)]
+    pub series_volume: Option<i32>,
+}
+
+/// New values for all of a book's fields.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct BookUpdate {
+    /// The book's title
+    pub title: String,
+    /// The book's credits, written as `Book::author` shows them. Ignored if
+    /// `authors` is given.
+    #[serde(default)]
+    pub author: String,
+    /// The people to credit, in order, instead of `author`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<NewCredit>,
+    /// The book's ISBN-10 or ISBN-13. If left out, the book has no ISBN.
+    /// The same goes for the edition details that follow.
+    #[serde(default)]
+    pub isbn: Option<String>,
+    /// The name of the edition's publisher. One of that name is found,
+    /// ignoring case, or added.
+    #[serde(default)]
+    pub publisher: Option<String>,
+    /// When the edition was published
+    #[serde(default)]
+    pub published_on: Option<NaiveDate>,
+    /// The edition statement, such as "2nd" or "Revised"
+    #[serde(default)]
+    pub edition: Option<String>,
+    /// The number of pages
+    #[serde(default)]
+    pub page_count: Option<i32>,
+    /// The language, as an ISO 639 code such as "en"
+    #[serde(default)]
+    pub language: Option<String>,
+    /// The name of the series the book is part of, found or added like the
+    /// publisher
+    #[serde(default)]
+    pub series: Option<String>,
+    /// The book's number in its series
+    #[serde(default)]
+    pub series_volume: Option<i32>,
+    /// The version being replaced. If it is no longer current the update
+    /// is refused. May be left out when sending `If-Match` instead.
+    #[serde(default)]
+    pub version: Option<i64>,
+}
+
+/// A partial update to a book. Fields left as `None` are not changed.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct BookPatch {
+    /// The new title, if it is changing
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The new credits, written as `Book::author` shows them, if they are
+    /// changing
+    #[serde(default)]
+    pub author: Option<String>,
+    /// The new credits, in order, if they are changing. Takes precedence
+    /// over `author`.
+    #[serde(default)]
+    pub authors: Option<Vec<NewCredit>>,
+    /// The new ISBN-10 or ISBN-13, if it is changing. `null` removes the
+    /// ISBN.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    pub isbn: Option<Option<String>>,
+    /// The new publisher, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    pub publisher: Option<Option<String>>,
+    /// The new publication date, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<NaiveDate>)]
+    pub published_on: Option<Option<NaiveDate>>,
+    /// The new edition statement, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    pub edition: Option<Option<String>>,
+    /// The new number of pages, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<i32>)]
+    pub page_count: Option<Option<i32>>,
+    /// The new language, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    pub language: Option<Option<String>>,
+    /// The new series, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    pub series: Option<Option<String>>,
+    /// The new number in the series, if it is changing. `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<i32>)]
+    pub series_volume: Option<Option<i32>>,
+    /// The version being changed. If it is no longer current the patch
+    /// is refused. May be left out when sending `If-Match` instead.
+    #[serde(default)]
+    pub version: Option<i64>,
+}
+
+impl From<&BookUpdate> for BookPatch {
+    fn from(update: &BookUpdate) -> Self {
+        Self {
+            title: Some(update.title.clone()),
+            author: Some(update.author.clone()),
+            authors: Some(update.authors.clone()),
+            isbn: Some(update.isbn.clone()),
+            publisher: Some(update.publisher.clone()),
+            published_on: Some(update.published_on),
+            edition: Some(update.edition.clone()),
+            page_count: Some(update.page_count),
+            language: Some(update.language.clone()),
+            series: Some(update.series.clone()),
+            series_volume: Some(update.series_volume),
+            version: update.version,
+        }
+    }
+}
+
+/// Deserialize a field that may be `null`, so that `Some(None)` means it
+/// was `null` and `None` (from `#[serde(default)]`) means it was left out.
+fn present<'de, T, D>(deserializer: D) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// The most operations accepted in one batch.
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// One write in a batch, tagged by `op`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOp {
+    /// Add a book
+    Create { book: NewBook },
+    /// Replace a book's fields. `book.version` is required.
+    Update { id: i32, book: BookUpdate },
+    /// Move a book to the trash. `version` is required.
+    Delete { id: i32, version: Option<i64> },
+}
+
+/// What a batch does when one of its operations fails.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchMode {
+    /// Apply every operation or none of them, stopping at the first failure
+    #[default]
+    Atomic,
+    /// Apply the operations that succeed, and report the ones that fail
+    Partial,
+}
+
+/// Several writes, applied in order in one transaction.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
+pub struct Batch {
+    /// How failures are handled (default `atomic`)
+    #[serde(default)]
+    pub mode: BatchMode,
+    /// The writes to make, at most `MAX_BATCH_SIZE` of them
+    pub operations: Vec<BatchOp>,
+}
+
+/// What happened to one operation of a batch.
+#[derive(Debug)]
+pub struct BatchItem {
+    /// The operation's position in the batch
+    pub index: usize,
+    /// The book as stored, `None` for a delete, or why the operation failed
+    pub outcome: Result<Option<Book>>,
+}
+
+/// What happened to a batch.
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// Whether the transaction was committed. An atomic batch with a
+    /// failure is rolled back, and nothing it did is kept.
+    pub committed: bool,
+    /// The operations that were attempted, in order. An atomic batch stops
+    /// at its first failure.
+    pub items: Vec<BatchItem>,
+}
+
+/// The number of books returned per page if the caller doesn't ask for a size.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// The largest page size a caller may request.
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// The most tags counted in a listing's facets.
+pub const MAX_FACETS: i64 = 100;
+
+/// Columns that a book listing may be sorted by.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Id,
+    #[default]
+    Title,
+    Author,
+    #[serde(rename = "updated_at")]
+    UpdatedAt,
+}
+
+impl SortField {
+    /// The query-string name of the field.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortField::Id => "id",
+            SortField::Title => "title",
+            SortField::Author => "author",
+            SortField::UpdatedAt => "updated_at",
+        }
+    }
+}
+
+/// Direction of a sorted listing.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    /// The query-string name of the direction.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+
+    fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Restricts a listing to books with matching edition details and tags.
+/// Every field that is set must match, and the books must carry every one
+/// of the tags; names and edition statements ignore case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct BookFilter {
+    /// The publisher's name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    /// The series' name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    /// The number in the series
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_volume: Option<i32>,
+    /// The edition statement
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+    /// The ISO 639 language code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Published on or after this date
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_from: Option<NaiveDate>,
+    /// Published on or before this date
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_to: Option<NaiveDate>,
+    /// At least this many pages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pages: Option<i32>,
+    /// At most this many pages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<i32>,
+    /// A filter expression the books must match
+    #[serde(rename = "filter", skip_serializing_if = "Option::is_none")]
+    pub expression: Option<FilterExpr>,
+    /// Tags the books must all carry, in lower case. These repeat in a
+    /// query string, so they aren't serialized with the other fields.
+    #[serde(skip)]
+    pub tags: Vec<String>,
+}
+
+impl BookFilter {
+    /// Add an `AND` condition to `sql` for each field that is set. Values
+    /// are always bound as parameters.
+    fn push_conditions<'a, DB>(&self, sql: &mut QueryBuilder<'a, DB>)
+    where
+        DB: filter::Timestamps,
+        String: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+        i32: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+        i64: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+        NaiveDate: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    {
+        if let Some(publisher) = &self.publisher {
+            sql.push(" AND publisher_id IN (SELECT id FROM publishers WHERE lower(name)=lower(")
+                .push_bind(publisher.clone())
+                .push("))");
+        }
+        if let Some(series) = &self.series {
+            sql.push(" AND series_id IN (SELECT id FROM series WHERE lower(name)=lower(")
+                .push_bind(series.clone())
+                .push("))");
+        }
+        if let Some(volume) = self.series_volume {
+            sql.push(" AND series_volume=").push_bind(volume);
+        }
+        if let Some(edition) = &self.edition {
+            sql.push(" AND lower(edition)=lower(")
+                .push_bind(edition.clone())
+                .push(")");
+        }
+        if let Some(language) = &self.language {
+            sql.push(" AND language=")
+                .push_bind(language.to_lowercase());
+        }
+        if let Some(from) = self.published_from {
+            sql.push(" AND published_on >= ").push_bind(from);
+        }
+        if let Some(to) = self.published_to {
+            sql.push(" AND published_on <= ").push_bind(to);
+        }
+        if let Some(min) = self.min_pages {
+            sql.push(" AND page_count >= ").push_bind(min);
+        }
+        if let Some(max) = self.max_pages {
+            sql.push(" AND page_count <= ").push_bind(max);
+        }
+        if let Some(expression) = &self.expression {
+            sql.push(" AND ");
+            expression.push_sql(sql);
+        }
+        for tag in &self.tags {
+            sql.push(
+                " AND id IN (SELECT book_tags.book_id FROM book_tags
+                             JOIN tags ON tags.id = book_tags.tag_id WHERE tags.name=",
+            )
+            .push_bind(tag.clone())
+            .push(")");
+        }
+    }
+}
+
+/// Describes which slice of the books table to return, and in what order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BookQuery {
+    /// Maximum number of books to return
+    pub limit: i64,
+    /// Number of books to skip before the first returned row
+    pub offset: i64,
+    /// Column to sort by
+    pub sort: SortField,
+    /// Sort direction
+    pub order: SortOrder,
+    /// Only include books changed at or after this time
+    pub updated_since: Option<DateTime<Utc>>,
+    /// Only include books with these edition details
+    pub filter: BookFilter,
+}
+
+impl Default for BookQuery {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_PAGE_SIZE,
+            offset: 0,
+            sort: SortField::default(),
+            order: SortOrder::default(),
+            updated_since: None,
+            filter: BookFilter::default(),
+        }
+    }
+}
+
+impl BookQuery {
+    /// Clamp the limit and offset into a range the database can safely
+    /// serve, and put the tags in lower case and in order, so that
+    /// equivalent queries are equal.
+    pub fn normalized(&self) -> Self {
+        let mut tags: Vec<String> = self
+            .filter
+            .tags
+            .iter()
+            .map(|tag| {
+                tag.split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .to_lowercase()
+            })
+            .collect();
+        tags.sort();
+        tags.dedup();
+        Self {
+            limit: self.limit.clamp(1, MAX_PAGE_SIZE),
+            offset: self.offset.max(0),
+            filter: BookFilter {
+                tags,
+                ..self.filter.clone()
+            },
+            ..self.clone()
+        }
+    }
+
+    /// The query for the facets of this one's results: the same books,
+    /// without the paging and sorting that don't change the counts.
+    fn for_facets(&self) -> Self {
+        Self {
+            updated_since: self.updated_since,
+            filter: self.filter.clone(),
+            ..Self::default()
+        }
+        .normalized()
+    }
+
+    /// Builds the ORDER BY clause. Only whitelisted column names are ever
+    /// emitted, so this is safe to splice into SQL.
+    fn order_by(&self) -> String {
+        let dir = self.order.sql();
+        match self.sort {
+            SortField::Id => format!("id {dir}"),
+            SortField::Title => format!("title {dir}, author {dir}, id {dir}"),
+            SortField::Author => format!("author {dir}, title {dir}, id {dir}"),
+            SortField::UpdatedAt => format!("updated_at {dir}, id {dir}"),
+        }
+    }
+}
+
+/// How many of the books matching a query carry a tag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema, FromRow)]
+pub struct TagCount {
+    /// The tag's name
+    pub tag: String,
+    /// The number of matching books with the tag
+    pub count: i64,
+}
+
+/// A single page of results, along with the total number of matching rows.
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    /// The rows on this page
+    pub items: Vec<T>,
+    /// The total number of rows available across all pages
+    pub total: i64,
+}
+
+/// A book matched by a full-text search.
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct SearchHit {
+    /// The matching book
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub book: Book,
+    /// BM25 relevance score; lower is a better match.
+    pub rank: f64,
+    /// The title as HTML, with matching terms wrapped in `<mark>` tags and
+    /// everything else escaped
+    pub title_highlight: String,
+    /// The author as HTML, with matching terms wrapped in `<mark>` tags and
+    /// everything else escaped
+    pub author_highlight: String,
+}
+
+/// What happened to a book in a `BookChange`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeAction {
+    Insert,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+}
+
+impl ChangeAction {
+    /// The name stored in the `action` column.
+    fn as_str(self) -> &'static str {
+        match self {
+            ChangeAction::Insert => "insert",
+            ChangeAction::Update => "update",
+            ChangeAction::Delete => "delete",
+            ChangeAction::Restore => "restore",
+            ChangeAction::Purge => "purge",
+        }
+    }
+
+    fn from_db(action: &str) -> Result<Self> {
+        match action {
+            "insert" => Ok(ChangeAction::Insert),
+            "update" => Ok(ChangeAction::Update),
+            "delete" => Ok(ChangeAction::Delete),
+            "restore" => Ok(ChangeAction::Restore),
+            "purge" => Ok(ChangeAction::Purge),
+            _ => Err(Error::Database(sqlx::Error::Decode(
+                format!("unknown book_history action {action:?}").into(),
+            ))),
+        }
+    }
+}
+
+/// One entry in a book's history, taken from the book_history table.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct BookChange {
+    /// The entry's primary key ID; later changes have larger IDs
+    pub id: i64,
+    /// The book that changed
+    pub book_id: i32,
+    /// Whether the book was added, changed, deleted, restored or purged
+    pub action: ChangeAction,
+    /// Who made the change
+    pub actor: String,
+    /// When the change was made
+    pub changed_at: DateTime<Utc>,
+    /// The book before the change; absent for an insert
+    pub before: Option<Book>,
+    /// The book after the change; absent for a purge
+    pub after: Option<Book>,
+}
+
+/// A row of the book_history table, with the snapshots still in JSON.
+#[derive(FromRow)]
+struct ChangeRow {
+    id: i64,
+    book_id: i32,
+    action: String,
+    actor: String,
+    changed_at: DateTime<Utc>,
+    before: Option<Json<Book>>,
+    after: Option<Json<Book>>,
+}
+
+impl ChangeRow {
+    fn into_change(self) -> Result<BookChange> {
+        Ok(BookChange {
+            id: self.id,
+            book_id: self.book_id,
+            action: ChangeAction::from_db(&self.action)?,
+            actor: self.actor,
+            changed_at: self.changed_at,
+            before: self.before.map(|json| json.0),
+            after: self.after.map(|json| json.0),
+        })
+    }
+}
+
+/// Storage for books. Implementations run the queries and nothing else:
+/// they expect input that has already been validated, and don't cache.
+/// Deleted books are kept in the trash until purged, and every method
+/// other than `trash`, `restore_book` and `purge_books` ignores them.
+/// Every write also records a `BookChange` in the same transaction, so the
+/// history can't miss a change or keep one that was rolled back.
+#[async_trait]
+pub trait BookRepository: Send + Sync {
+    /// Retrieves a page of books, and counts every book the query matches.
+    /// The query has already been normalized.
+    async fn all_books(&self, query: &BookQuery) -> Result<Page<Book>>;
+
+    /// Retrieves a page of deleted books, and counts every deleted book the
+    /// query matches. The query has already been normalized.
+    async fn trash(&self, query: &BookQuery) -> Result<Page<Book>>;
+
+    /// Retrieves up to `limit` live books with IDs above `after`, in ID
+    /// order, for walking the whole catalog a page at a time.
+    async fn books_after(&self, after: i32, limit: i64) -> Result<Vec<Book>>;
+
+    /// Retrieves a single book, or `Error::NotFound`.
+    async fn book_by_id(&self, id: i32) -> Result<Book>;
+
+    /// Retrieves the live book with an ISBN-13, or `Error::NotFound`.
+    async fn book_by_isbn(&self, isbn13: &str) -> Result<Book>;
+
+    /// Live books with exactly this title and author, ignoring case.
+    async fn books_by_title_author(&self, title: &str, author: &str) -> Result<Vec<Book>>;
+
+    /// Full-text search over titles and authors, best (lowest rank) first.
+    /// The highlights are plain text, with each match between `MATCH_START`
+    /// and `MATCH_END`.
+    async fn search_books(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>>;
+
+    /// Every recorded change to book `id`, oldest first. Empty if the book
+    /// has never existed.
+    async fn book_history(&self, id: i32) -> Result<Vec<BookChange>>;
+
+    /// The book as it stood at time `as_of`, rebuilt from its history, or
+    /// `Error::NotFound` if it didn't exist then.
+    async fn book_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Book>;
+
+    /// Inserts a book, credited to `actor`, returning it as stored.
+    async fn add_book(&self, book: &NewBook, actor: &str) -> Result<Book>;
+
+    /// Replaces a book's fields on behalf of `actor`, returning it as
+    /// stored. Fails with `Error::NotFound`, or `Error::Stale` if
+    /// `update.version` is given and isn't current.
+    async fn update_book(&self, id: i32, update: &BookUpdate, actor: &str) -> Result<Book>;
+
+    /// Changes the fields that are present in `patch`. Fails like
+    /// `update_book`.
+    async fn patch_book(&self, id: i32, patch: &BookPatch, actor: &str) -> Result<Book>;
+
+    /// Moves a book to the trash on behalf of `actor`. Fails with
+    /// `Error::NotFound`, or `Error::Stale` if `version` is given and isn't
+    /// current.
+    async fn delete_book(&self, id: i32, version: Option<i64>, actor: &str) -> Result<()>;
+
+    /// Takes a book back out of the trash on behalf of `actor`, returning
+    /// it as stored, or `Error::NotFound` if it isn't in the trash.
+    async fn restore_book(&self, id: i32, actor: &str) -> Result<Book>;
+
+    /// Permanently removes every book deleted before `deleted_before`, on
+    /// behalf of `actor`. Returns how many were removed.
+    async fn purge_books(&self, deleted_before: DateTime<Utc>, actor: &str) -> Result<u64>;
+
+    /// Retrieves a page of authors, by name, and counts them all.
+    async fn authors(&self, limit: i64, offset: i64) -> Result<Page<Author>>;
+
+    /// Retrieves a single author, or `Error::NotFound`.
+    async fn author_by_id(&self, id: i32) -> Result<Author>;
+
+    /// Retrieves a page of the live books crediting author `id`, and counts
+    /// them all. The query has already been normalized.
+    async fn books_by_author(&self, id: i32, query: &BookQuery) -> Result<Page<Book>>;
+
+    /// Inserts an author, or fails with `Error::Conflict` if the name is
+    /// taken.
+    async fn add_author(&self, author: &NewAuthor) -> Result<Author>;
+
+    /// Renames an author, and rewrites the `author` line of every book that
+    /// credits them, in the trash or not, on behalf of `actor`. Returns the
+    /// author as stored and the IDs of the books that changed. Fails with
+    /// `Error::NotFound`, or `Error::Conflict` if the name is taken.
+    async fn rename_author(
+        &self,
+        id: i32,
+        author: &NewAuthor,
+        actor: &str,
+    ) -> Result<(Author, Vec<i32>)>;
+
+    /// Removes an author. Fails with `Error::NotFound`, or `Error::Conflict`
+    /// if any book, in the trash or not, still credits them.
+    async fn delete_author(&self, id: i32) -> Result<()>;
+
+    /// Counts the live books matching `query` that carry each tag, most
+    /// common first, ignoring its paging. Returns at most `MAX_FACETS`.
+    async fn tag_facets(&self, query: &BookQuery) -> Result<Vec<TagCount>>;
+
+    /// Files live book `id` under `tag`, adding the tag if it is new, on
+    /// behalf of `actor`, and returns the book as stored. Tagging a book
+    /// again changes nothing. Fails with `Error::NotFound`.
+    async fn tag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book>;
+
+    /// Takes `tag` off live book `id` on behalf of `actor`, and returns the
+    /// book as stored. Fails with `Error::NotFound` if there is no such
+    /// book or it doesn't carry the tag.
+    async fn untag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book>;
+
+    /// Applies `ops` in order, in one transaction, on behalf of `actor`,
+    /// and returns the outcome of each: the book as stored, or `None` for a
+    /// delete. If `atomic`, stops at the first failure and rolls back
+    /// everything; otherwise rolls back just the failed operations and
+    /// commits the rest.
+    async fn batch(
+        &self,
+        ops: &[BatchOp],
+        actor: &str,
+        atomic: bool,
+    ) -> Result<Vec<Result<Option<Book>>>>;
+}
+
+/// The error for a failed write that gives a book `isbn`: a unique
+/// violation means another live book already has it.
+fn write_error(err: sqlx::Error, isbn: Option<&str>) -> Error {
+    match (&err, isbn) {
+        (sqlx::Error::Database(db_err), Some(isbn)) if db_err.is_unique_violation() => {
+            Error::Conflict(format!("another book already has ISBN {isbn}"))
+        }
+        _ => err.into(),
+    }
+}
+
+/// The error for a failed write that names an author `name`: a unique
+/// violation means another author is already called that.
+fn author_error(err: sqlx::Error, name: &str) -> Error {
+    match &err {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            Error::Conflict(format!("there is already an author called {name}"))
+        }
+        _ => err.into(),
+    }
+}
+
+/// A shareable handle to whichever repository is in use.
+pub type Repository = Arc<dyn BookRepository>;
+
+/// Connect to the database configured in the environment. Run any migrations.
+///
+/// ## Returns
+/// * A ready-to-use repository.
+pub async fn init_db() -> anyhow::Result<Repository> {
+    Ok(connect(&DbConfig::from_env()?).await?)
+}
+
+/// Connect to a database, picking the backend from the URL scheme.
+/// Run any migrations.
+///
+/// ## Arguments
+/// * `config` - the database location and pool settings. `postgres://` and
+///   `postgresql://` URLs use PostgreSQL; `sqlite:` URLs use SQLite.
+///
+/// ## Returns
+/// * A ready-to-use repository.
+pub async fn connect(config: &DbConfig) -> Result<Repository> {
+    let scheme = config.url.split(':').next().unwrap_or_default();
+    match scheme {
+        "postgres" | "postgresql" => Ok(Arc::new(PostgresRepository::connect(config).await?)),
+        "sqlite" => Ok(Arc::new(SqliteRepository::connect(config).await?)),
+        _ => Err(Error::Database(sqlx::Error::Configuration(
+            format!("unsupported database URL scheme {scheme:?}").into(),
+        ))),
+    }
+}
+
+/// Create a fresh, migrated in-memory database for a test.
+#[cfg(test)]
+pub async fn test_db() -> Repository {
+    let config = DbConfig {
+        url: "sqlite::memory:".to_string(),
+        ..DbConfig::default()
+    };
+    connect(&config).await.unwrap()
+}
+
+/// Look `key` up in the state's cache, loading it with `load` on a miss.
+/// Concurrent misses for the same key share a single load.
+async fn cached<F, Fut>(state: &AppState, key: CacheKey, load: F) -> Result<CacheValue>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<CacheValue>>,
+{
+    state.flights.get_or_load(&*state.cache, key, load).await
+}
+
+/// Retrieves a page of books, sorted as requested.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use.
+/// * `query` - which page to return, how to sort it, and optionally how
+///   recently the books must have changed.
+///
+/// ## Returns
+/// * A page of books and the count of all matching books, or an error.
+pub async fn all_books(state: &AppState, query: &BookQuery) -> Result<Page<Book>> {
+    let query = query.normalized();
+    let loaded = cached(state, CacheKey::Page(query.clone()), || async {
+        Ok(CacheValue::Page(state.repo.all_books(&query).await?))
+    });
+    match loaded.await? {
+        CacheValue::Page(page) => Ok(page),
+        _ => unreachable!("page keys hold pages"),
+    }
+}
+
+/// Counts how many of the books a listing matches carry each tag, for
+/// browsing by subject.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use.
+/// * `query` - the listing; its paging and sorting are ignored.
+///
+/// ## Returns
+/// * Up to `MAX_FACETS` tags with their counts, most common first, or an
+///   error.
+pub async fn book_facets(state: &AppState, query: &BookQuery) -> Result<Vec<TagCount>> {
+    let query = query.for_facets();
+    let loaded = cached(state, CacheKey::Facets(query.clone()), || async {
+        Ok(CacheValue::Facets(state.repo.tag_facets(&query).await?))
+    });
+    match loaded.await? {
+        CacheValue::Facets(facets) => Ok(facets),
+        _ => unreachable!("facet keys hold facets"),
+    }
+}
+
+/// Retrieves a page of the books in the trash. The trash isn't cached.
+///
+/// ## Arguments
+/// * `state` - the repository to use.
+/// * `query` - which page to return, how to sort it, and optionally how
+///   recently the books must have changed.
+///
+/// ## Returns
+/// * A page of deleted books and the count of all matching deleted books,
+///   or an error.
+pub async fn trash(state: &AppState, query: &BookQuery) -> Result<Page<Book>> {
+    state.repo.trash(&query.normalized()).await
+}
+
+/// Retrieves a single book, by ID
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book to retrieve
+///
+/// ## Returns
+/// * The book, or `Error::NotFound` if there is no such book.
+pub async fn book_by_id(state: &AppState, id: i32) -> Result<Book> {
+    let loaded = cached(state, CacheKey::Book(id), || async {
+        Ok(CacheValue::Book(Box::new(state.repo.book_by_id(id).await?)))
+    });
+    match loaded.await? {
+        CacheValue::Book(book) => Ok(*book),
+        _ => unreachable!("book keys hold books"),
+    }
+}
+
+/// Retrieves a single book, by ISBN
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `isbn` - the book's ISBN-10 or ISBN-13, with or without hyphens
+///
+/// ## Returns
+/// * The book, `Error::Validation` if `isbn` isn't a valid ISBN, or
+///   `Error::NotFound` if no live book has it.
+pub async fn book_by_isbn(state: &AppState, isbn: &str) -> Result<Book> {
+    let isbn = validate_isbn(isbn)?;
+    let loaded = cached(state, CacheKey::Isbn(isbn.clone()), || async {
+        Ok(CacheValue::Book(Box::new(
+            state.repo.book_by_isbn(&isbn).await?,
+        )))
+    });
+    match loaded.await? {
+        CacheValue::Book(book) => Ok(*book),
+        _ => unreachable!("ISBN keys hold books"),
+    }
+}
+
+/// Marks the start of a match in the highlights a repository returns.
+/// Validation keeps control characters out of titles and names, so the
+/// markers can't be confused with the text.
+pub const MATCH_START: &str = "\u{2}";
+
+/// Marks the end of a match in the highlights a repository returns.
+pub const MATCH_END: &str = "\u{3}";
+
+/// Turn a highlight from a repository into HTML: escape the text, and wrap
+/// each match in `<mark>` tags.
+fn highlight_html(highlight: &str) -> String {
+    let mut html = String::with_capacity(highlight.len());
+    for c in highlight.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            '\u{2}' => html.push_str("<mark>"),
+            '\u{3}' => html.push_str("</mark>"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Full-text search over book titles and authors, best matches first.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `text` - the words to search for; every word must match, as a prefix
+/// * `limit` - the maximum number of results to return
+///
+/// ## Returns
+/// * The matching books with their rank and highlighted fields. An empty
+///   search returns no results.
+pub async fn search_books(state: &AppState, text: &str, limit: i64) -> Result<Vec<SearchHit>> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let loaded = cached(state, CacheKey::search(text, limit), || async {
+        let mut hits = state.repo.search_books(text, limit).await?;
+        for hit in &mut hits {
+            hit.title_highlight = highlight_html(&hit.title_highlight);
+            hit.author_highlight = highlight_html(&hit.author_highlight);
+        }
+        Ok(CacheValue::Search(hits))
+    });
+    match loaded.await? {
+        CacheValue::Search(hits) => Ok(hits),
+        _ => unreachable!("search keys hold search results"),
+    }
+}
+
+/// Retrieves everything that has happened to a book, oldest first.
+/// History is never cached.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `id` - the primary key of the book
+///
+/// ## Returns
+/// * The book's changes, including its deletion if it has been deleted,
+///   or `Error::NotFound` if there has never been a book with that ID.
+pub async fn book_history(state: &AppState, id: i32) -> Result<Vec<BookChange>> {
+    let history = state.repo.book_history(id).await?;
+    if history.is_empty() {
+        return Err(Error::NotFound(format!("book {id} not found")));
+    }
+    Ok(history)
+}
+
+/// Retrieves a book as it was at some time in the past.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `id` - the primary key of the book
+/// * `as_of` - the moment to look at
+///
+/// ## Returns
+/// * The book as of that moment, or `Error::NotFound` if it hadn't been
+///   added yet or had already been deleted.
+pub async fn book_as_of(state: &AppState, id: i32, as_of: DateTime<Utc>) -> Result<Book> {
+    state.repo.book_as_of(id, as_of).await
+}
+
+/// Finds the books that already have a title and author, for spotting
+/// duplicates. Not cached.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `book` - the title and author to look for, after normalization
+///
+/// ## Returns
+/// * The matching books, compared without regard to case, or
+///   `Error::Validation` if the title or author are unacceptable.
+pub async fn books_like(state: &AppState, book: &NewBook) -> Result<Vec<Book>> {
+    let book = book.validated()?;
+    state
+        .repo
+        .books_by_title_author(&book.title, &book.author)
+        .await
+}
+
+/// Adds a book to the database.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `book` - the title and author of the book to add
+/// * `actor` - who is adding it
+///
+/// ## Returns
+/// * The newly created book, or `Error::Validation` if the title or
+///   author are unacceptable.
+pub async fn add_book(state: &AppState, book: &NewBook, actor: &str) -> Result<Book> {
+    let book = state.repo.add_book(&book.validated()?, actor).await?;
+    state.cache.invalidate_queries().await;
+    Ok(book)
+}
+
+/// Update a book
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book to replace
+/// * `update` - the new title and author, and the version they replace.
+///   Without a version the update is unconditional.
+/// * `actor` - who is making the change
+///
+/// ## Returns
+/// * The book as stored, `Error::Validation` if the new values are
+///   unacceptable, `Error::NotFound` if there is no book with that ID, or
+///   `Error::Stale` if the version isn't current.
+pub async fn update_book(
+    state: &AppState,
+    id: i32,
+    update: &BookUpdate,
+    actor: &str,
+) -> Result<Book> {
+    let updated = state
+        .repo
+        .update_book(id, &update.validated()?, actor)
+        .await?;
+    state.cache.invalidate_book(id).await;
+    Ok(updated)
+}
+
+/// Apply a partial update to a book
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book to change
+/// * `patch` - the fields to change; `None` fields keep their current value.
+///   Without a version the patch is unconditional.
+/// * `actor` - who is making the change
+///
+/// ## Returns
+/// * The book as stored, `Error::Validation` if a new value is
+///   unacceptable, `Error::NotFound` if there is no book with that ID, or
+///   `Error::Stale` if the version isn't current.
+pub async fn patch_book(state: &AppState, id: i32, patch: &BookPatch, actor: &str) -> Result<Book> {
+    let updated = state
+        .repo
+        .patch_book(id, &patch.validated()?, actor)
+        .await?;
+    state.cache.invalidate_book(id).await;
+    Ok(updated)
+}
+
+/// Move a book to the trash. It can be restored with `restore_book`
+/// until the trash is purged.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book to delete
+/// * `version` - the version being deleted. `None` deletes unconditionally.
+/// * `actor` - who is deleting it
+///
+/// ## Returns
+/// * `Error::NotFound` if there is no book with that ID, or `Error::Stale`
+///   if the version isn't current.
+pub async fn delete_book(
+    state: &AppState,
+    id: i32,
+    version: Option<i64>,
+    actor: &str,
+) -> Result<()> {
+    state.repo.delete_book(id, version, actor).await?;
+    state.cache.invalidate_book(id).await;
+    Ok(())
+}
+
+/// Take a book back out of the trash
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the deleted book
+/// * `actor` - who is restoring it
+///
+/// ## Returns
+/// * The restored book, or `Error::NotFound` if there is no book with
+///   that ID in the trash.
+pub async fn restore_book(state: &AppState, id: i32, actor: &str) -> Result<Book> {
+    let restored = state.repo.restore_book(id, actor).await?;
+    state.cache.invalidate_book(id).await;
+    Ok(restored)
+}
+
+/// Apply a batch of writes in a single transaction, invalidating the
+/// cache once for the whole batch.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `batch` - the writes, and whether they must all succeed together
+/// * `actor` - who is making the changes
+///
+/// ## Returns
+/// * Whether the batch was committed, and the outcome of each operation
+///   that was attempted. Invalid operations fail just like ones the
+///   database refuses. `Error::Validation` if the batch is too large.
+pub async fn batch(state: &AppState, batch: &Batch, actor: &str) -> Result<BatchOutcome> {
+    if batch.operations.len() > MAX_BATCH_SIZE {
+        return Err(FieldError::new(
+            "operations",
+            format!("must not have more than {MAX_BATCH_SIZE} entries"),
+        )
+        .into());
+    }
+    let atomic = batch.mode == BatchMode::Atomic;
+    let mut items = Vec::new();
+    let mut valid = Vec::new();
+    for (index, op) in batch.operations.iter().enumerate() {
+        match op.validated() {
+            Ok(op) => valid.push((index, op)),
+            Err(err) if atomic => {
+                return Ok(BatchOutcome {
+                    committed: false,
+                    items: vec![BatchItem {
+                        index,
+                        outcome: Err(err),
+                    }],
+                })
+            }
+            Err(err) => items.push(BatchItem {
+                index,
+                outcome: Err(err),
+            }),
+        }
+    }
+
+    let ops: Vec<BatchOp> = valid.iter().map(|(_, op)| op.clone()).collect();
+    let outcomes = state.repo.batch(&ops, actor, atomic).await?;
+    let committed = !(atomic && outcomes.iter().any(Result::is_err));
+    let mut changed = Vec::new();
+    let mut wrote = false;
+    for ((index, op), outcome) in valid.into_iter().zip(outcomes) {
+        if outcome.is_ok() {
+            wrote = true;
+            if let BatchOp::Update { id, .. } | BatchOp::Delete { id, .. } = op {
+                changed.push(id);
+            }
+        }
+        items.push(BatchItem { index, outcome });
+    }
+    items.sort_by_key(|item| item.index);
+    if committed && wrote {
+        state.cache.invalidate_books(&changed).await;
+    }
+    Ok(BatchOutcome { committed, items })
+}
+
+/// Permanently remove the books that have been in the trash for longer
+/// than the configured retention period. Only admins may purge.
+///
+/// ## Arguments
+/// * `state` - the repository and trash settings to use
+/// * `token` - the admin token the caller presented, if any
+/// * `actor` - who is purging, for the history
+///
+/// ## Returns
+/// * The number of books removed, or `Error::Forbidden` if `token` isn't
+///   the admin token.
+pub async fn purge_books(state: &AppState, token: Option<&str>, actor: &str) -> Result<u64> {
+    if !state.trash.is_admin(token) {
+        return Err(Error::Forbidden(
+            "purging the trash needs the admin token".to_string(),
+        ));
+    }
+    let retention = chrono::Duration::from_std(state.trash.retention)
+        .map_err(|e| Error::Database(sqlx::Error::Configuration(e.into())))?;
+    state.repo.purge_books(Utc::now() - retention, actor).await
+}
+
+/// Retrieves a page of authors, sorted by name. Authors aren't cached.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `limit` - the most authors to return, up to `MAX_PAGE_SIZE`
+/// * `offset` - how many authors to skip
+///
+/// ## Returns
+/// * A page of authors and the count of all authors, or an error.
+pub async fn authors(state: &AppState, limit: i64, offset: i64) -> Result<Page<Author>> {
+    state
+        .repo
+        .authors(limit.clamp(1, MAX_PAGE_SIZE), offset.max(0))
+        .await
+}
+
+/// Retrieves a single author, by ID
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `id` - the primary key of the author
+///
+/// ## Returns
+/// * The author, or `Error::NotFound` if there is no such author.
+pub async fn author_by_id(state: &AppState, id: i32) -> Result<Author> {
+    state.repo.author_by_id(id).await
+}
+
+/// Retrieves a page of the books crediting an author, in any role.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `id` - the primary key of the author
+/// * `query` - which page to return, how to sort it, and optionally how
+///   recently the books must have changed.
+///
+/// ## Returns
+/// * A page of books and the count of all matching books, or
+///   `Error::NotFound` if there is no such author.
+pub async fn books_by_author(state: &AppState, id: i32, query: &BookQuery) -> Result<Page<Book>> {
+    state.repo.author_by_id(id).await?;
+    state.repo.books_by_author(id, &query.normalized()).await
+}
+
+/// Adds an author, who can then be credited on books by ID.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `author` - the author's name
+///
+/// ## Returns
+/// * The newly created author, `Error::Validation` if the name is
+///   unacceptable, or `Error::Conflict` if another author has it.
+pub async fn add_author(state: &AppState, author: &NewAuthor) -> Result<Author> {
+    state.repo.add_author(&author.validated()?).await
+}
+
+/// Renames an author, which changes the `author` line of their books.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the author
+/// * `author` - the author's new name
+/// * `actor` - who is making the change, as recorded on the books
+///
+/// ## Returns
+/// * The author as stored, `Error::Validation` if the name is
+///   unacceptable, `Error::NotFound` if there is no such author, or
+///   `Error::Conflict` if another author has the name.
+pub async fn rename_author(
+    state: &AppState,
+    id: i32,
+    author: &NewAuthor,
+    actor: &str,
+) -> Result<Author> {
+    let (author, changed) = state
+        .repo
+        .rename_author(id, &author.validated()?, actor)
+        .await?;
+    if !changed.is_empty() {
+        state.cache.invalidate_books(&changed).await;
+    }
+    Ok(author)
+}
+
+/// Removes an author who is no longer credited on any book.
+///
+/// ## Arguments
+/// * `state` - the repository to use
+/// * `id` - the primary key of the author
+///
+/// ## Returns
+/// * `Error::NotFound` if there is no such author, or `Error::Conflict` if
+///   a book, even one in the trash, still credits them.
+pub async fn delete_author(state: &AppState, id: i32) -> Result<()> {
+    state.repo.delete_author(id).await
+}
+
+/// Files a book under a tag, such as a subject or genre.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book
+/// * `tag` - the tag's name; it is stored in lower case
+/// * `actor` - who is making the change
+///
+/// ## Returns
+/// * The book as stored, `Error::Validation` if the tag is unacceptable, or
+///   `Error::NotFound` if there is no such live book.
+pub async fn tag_book(state: &AppState, id: i32, tag: &str, actor: &str) -> Result<Book> {
+    let tag = validate_tag(tag)?;
+    let tagged = state.repo.tag_book(id, &tag, actor).await?;
+    state.cache.invalidate_book(id).await;
+    Ok(tagged)
+}
+
+/// Takes a tag off a book.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `id` - the primary key of the book
+/// * `tag` - the tag's name, in any case
+/// * `actor` - who is making the change
+///
+/// ## Returns
+/// * The book as stored, or `Error::NotFound` if there is no such live
+///   book or it doesn't carry the tag.
+pub async fn untag_book(state: &AppState, id: i32, tag: &str, actor: &str) -> Result<Book> {
+    let tag = validate_tag(tag)?;
+    let untagged = state.repo.untag_book(id, &tag, actor).await?;
+    state.cache.invalidate_book(id).await;
+    Ok(untagged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::CacheConfig;
+    use std::future::Future;
+
+    /// Runs `test` against a fresh in-memory SQLite database and, if
+    /// `TEST_POSTGRES_URL` is set (directly or in `.env`), against a fresh
+    /// database created on that PostgreSQL server and dropped afterwards.
+    /// Each run gets its own empty cache.
+    async fn for_each_backend<F, Fut>(test: F)
+    where
+        F: Fn(AppState) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        test(crate::state::test_state().await).await;
+
+        dotenv::dotenv().ok();
+        if let Ok(url) = std::env::var("TEST_POSTGRES_URL") {
+            let (repo, database) = postgres::create_test_database(&url).await;
+            test(AppState::new(
+                Arc::new(repo),
+                crate::cache::from_config(&CacheConfig::default()),
+            ))
+            .await;
+            postgres::drop_test_database(&url, &database).await;
+        }
+    }
+
+    /// Who the tests make their changes as.
+    const ACTOR: &str = "tester";
+
+    fn new_book(title: &str, author: &str) -> NewBook {
+        NewBook {
+            title: title.to_string(),
+            author: author.to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn get_all() {
+        for_each_backend(|state| async move {
+            let all_rows = all_books(&state, &BookQuery::default()).await.unwrap();
+            assert!(!all_rows.items.is_empty());
+            assert_eq!(all_rows.total, all_rows.items.len() as i64);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn get_page() {
+        for_each_backend(|state| async move {
+            let query = BookQuery {
+                limit: 1,
+                offset: 1,
+                sort: SortField::Id,
+                order: SortOrder::Desc,
+                ..BookQuery::default()
+            };
+            let page = all_books(&state, &query).await.unwrap();
+            assert_eq!(1, page.items.len());
+            assert_eq!(2, page.total);
+            assert_eq!(1, page.items[0].id);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn get_one() {
+        for_each_backend(|state| async move {
+            let book = book_by_id(&state, 1).await.unwrap();
+            assert_eq!(1, book.id);
+            assert_eq!("Hands-on Rust", book.title);
+            assert_eq!("Wolverson, Herbert", book.author);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn get_missing() {
+        for_each_backend(|state| async move {
+            let err = book_by_id(&state, 9999).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn search() {
+        for_each_backend(|state| async move {
+            let hits = search_books(&state, "brain", 10).await.unwrap();
+            assert_eq!(1, hits.len());
+            assert_eq!("Rust Brain Teasers", hits[0].book.title);
+            assert_eq!("Rust <mark>Brain</mark> Teasers", hits[0].title_highlight);
+
+            // Prefix matching, and both books match
+            let hits = search_books(&state, "wolv", 10).await.unwrap();
+            assert_eq!(2, hits.len());
+
+            // The rest of the text is escaped, so it can't inject markup
+            let title = "Tips & <script>Rust</script>";
+            add_book(&state, &new_book(title, "O'Hacker, Al"), ACTOR)
+                .await
+                .unwrap();
+            let hits = search_books(&state, "tips", 10).await.unwrap();
+            assert_eq!(1, hits.len());
+            assert_eq!(title, hits[0].book.title);
+            assert_eq!(
+                "<mark>Tips</mark> &amp; &lt;script&gt;Rust&lt;/script&gt;",
+                hits[0].title_highlight
+            );
+            assert_eq!("O&#39;Hacker, Al", hits[0].author_highlight);
+
+            // FTS5 syntax in user input is treated as text, not an error
+            let hits = search_books(&state, "\"rust AND (", 10).await.unwrap();
+            assert!(hits.is_empty());
+            assert!(search_books(&state, "   ", 10).await.unwrap().is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn search_follows_writes() {
+        for_each_backend(|state| async move {
+            let new_id = add_book(&state, &new_book("Zymurgy Handbook", "Brewer, Ann"), ACTOR)
+                .await
+                .unwrap()
+                .id;
+            assert_eq!(1, search_books(&state, "zymurgy", 10).await.unwrap().len());
+
+            let update = BookUpdate {
+                title: "Fermentation Handbook".to_string(),
+                author: "Brewer, Ann".to_string(),
+                authors: Vec::new(),
+                isbn: None,
+                version: None,
+                ..BookUpdate::default()
+            };
+            update_book(&state, new_id, &update, ACTOR).await.unwrap();
+            assert!(search_books(&state, "zymurgy", 10)
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                1,
+                search_books(&state, "fermentation", 10)
+                    .await
+                    .unwrap()
+                    .len()
+            );
+
+            delete_book(&state, new_id, None, ACTOR).await.unwrap();
+            assert!(search_books(&state, "fermentation", 10)
+                .await
+                .unwrap()
+                .is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_create() {
+        for_each_backend(|state| async move {
+            let created = add_book(&state, &new_book(" Test  Book ", "Author,Test"), ACTOR)
+                .await
+                .unwrap();
+            assert_eq!("Test Book", created.title);
+            let stored = book_by_id(&state, created.id).await.unwrap();
+            assert_eq!(created.id, stored.id);
+            assert_eq!("Test Book", stored.title);
+            assert_eq!("Author, Test", stored.author);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_create_invalid() {
+        for_each_backend(|state| async move {
+            let err = add_book(&state, &new_book("", "Test Author"), ACTOR)
+                .await
+                .unwrap_err();
+            let Error::Validation(errors) = err else {
+                panic!("expected a validation error");
+            };
+            assert_eq!(2, errors.len());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        for_each_backend(|state| async move {
+            let book = book_by_id(&state, 2).await.unwrap();
+            let update = BookUpdate {
+                title: "Updated Book".to_string(),
+                author: book.author.clone(),
+                authors: Vec::new(),
+                isbn: None,
+                version: Some(book.version),
+                ..BookUpdate::default()
+            };
+            update_book(&state, 2, &update, ACTOR).await.unwrap();
+            let updated_book = book_by_id(&state, 2).await.unwrap();
+            assert_eq!("Updated Book", updated_book.title);
+            assert_eq!(book.version + 1, updated_book.version);
+            assert!(updated_book.updated_at >= book.updated_at);
+
+            let err = update_book(&state, 9999, &update, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_patch() {
+        for_each_backend(|state| async move {
+            let patch = BookPatch {
+                title: Some("Patched Book".to_string()),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, 1, &patch, ACTOR).await.unwrap();
+            assert_eq!("Patched Book", patched.title);
+            assert_eq!("Wolverson, Herbert", patched.author);
+
+            let patch = BookPatch {
+                author: Some("".to_string()),
+                ..BookPatch::default()
+            };
+            let err = patch_book(&state, 1, &patch, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Validation(_)));
+
+            let err = patch_book(&state, 9999, &BookPatch::default(), ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        for_each_backend(|state| async move {
+            let new_id = add_book(&state, &new_book("DeleteMe", "Author, Test"), ACTOR)
+                .await
+                .unwrap()
+                .id;
+            let _new_book = book_by_id(&state, new_id).await.unwrap();
+            delete_book(&state, new_id, None, ACTOR).await.unwrap();
+            let all_books = all_books(&state, &BookQuery::default()).await.unwrap();
+            assert!(!all_books.items.iter().any(|b| b.title == "DeleteMe"));
+
+            let err = delete_book(&state, new_id, None, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn audit_columns() {
+        for_each_backend(|state| async move {
+            let seed = book_by_id(&state, 1).await.unwrap();
+            assert_eq!("unknown", seed.created_by);
+
+            // Make sure the new book isn't stamped in the same instant as
+            // the seed data
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            let created = add_book(&state, &new_book("Audited", "Author, Test"), ACTOR)
+                .await
+                .unwrap();
+            assert_eq!(ACTOR, created.created_by);
+            assert_eq!(ACTOR, created.updated_by);
+            assert_eq!(created.created_at, created.updated_at);
+
+            let since = BookQuery {
+                updated_since: Some(created.updated_at),
+                sort: SortField::UpdatedAt,
+                ..BookQuery::default()
+            };
+            let page = all_books(&state, &since).await.unwrap();
+            assert_eq!(1, page.total);
+            assert_eq!(created.id, page.items[0].id);
+
+            let patch = BookPatch {
+                title: Some("Hands-on Rust, 2nd edition".to_string()),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, 1, &patch, "editor").await.unwrap();
+            assert_eq!("editor", patched.updated_by);
+            assert_eq!(seed.created_by, patched.created_by);
+            assert_eq!(seed.created_at, patched.created_at);
+
+            // The changed seed book is now newer than the added one
+            let page = all_books(&state, &since).await.unwrap();
+            let ids: Vec<i32> = page.items.iter().map(|b| b.id).collect();
+            assert_eq!(vec![created.id, 1], ids);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stale_versions_are_refused() {
+        for_each_backend(|state| async move {
+            let book = add_book(&state, &new_book("Versioned", "Author, Test"), ACTOR)
+                .await
+                .unwrap();
+            assert_eq!(1, book.version);
+            let patch = BookPatch {
+                title: Some("Second".to_string()),
+                version: Some(1),
+                ..BookPatch::default()
+            };
+            assert_eq!(
+                2,
+                patch_book(&state, book.id, &patch, ACTOR)
+                    .await
+                    .unwrap()
+                    .version
+            );
+
+            // Another writer still holding version 1 loses, and learns the
+            // current state of the book
+            let update = BookUpdate {
+                title: "Clobbered".to_string(),
+                author: "Author, Test".to_string(),
+                authors: Vec::new(),
+                isbn: None,
+                version: Some(1),
+                ..BookUpdate::default()
+            };
+            let Error::Stale(current) = update_book(&state, book.id, &update, ACTOR)
+                .await
+                .unwrap_err()
+            else {
+                panic!("expected a stale version error");
+            };
+            assert_eq!("Second", current.title);
+            assert_eq!(2, current.version);
+            let err = patch_book(&state, book.id, &patch, ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Stale(_)));
+            let err = delete_book(&state, book.id, Some(1), ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Stale(_)));
+
+            delete_book(&state, book.id, Some(2), ACTOR).await.unwrap();
+            let err = delete_book(&state, book.id, Some(2), ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn history() {
+        for_each_backend(|state| async move {
+            // Seed data starts with a single entry
+            let seed = book_history(&state, 1).await.unwrap();
+            assert_eq!(1, seed.len());
+            assert_eq!(ChangeAction::Insert, seed[0].action);
+            assert_eq!("Hands-on Rust", seed[0].after.as_ref().unwrap().title);
+
+            let added = add_book(&state, &new_book("First", "Author, Test"), ACTOR)
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            let patch = BookPatch {
+                title: Some("Second".to_string()),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, added.id, &patch, "editor")
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            delete_book(&state, added.id, None, "remover")
+                .await
+                .unwrap();
+
+            let history = book_history(&state, added.id).await.unwrap();
+            let actions: Vec<_> = history
+                .iter()
+                .map(|c| (c.action, c.actor.as_str()))
+                .collect();
+            assert_eq!(
+                vec![
+                    (ChangeAction::Insert, ACTOR),
+                    (ChangeAction::Update, "editor"),
+                    (ChangeAction::Delete, "remover"),
+                ],
+                actions
+            );
+            assert!(history[0].before.is_none());
+            assert_eq!("First", history[1].before.as_ref().unwrap().title);
+            assert_eq!("Second", history[1].after.as_ref().unwrap().title);
+            assert_eq!(2, history[2].before.as_ref().unwrap().version);
+            assert!(history[2].after.as_ref().unwrap().deleted_at.is_some());
+
+            // Refused writes leave no trace
+            let stale = BookPatch {
+                version: Some(0),
+                ..patch.clone()
+            };
+            let err = patch_book(&state, 2, &stale, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Stale(_)));
+            assert_eq!(1, book_history(&state, 2).await.unwrap().len());
+
+            let as_of = |time| book_as_of(&state, added.id, time);
+            assert_eq!("First", as_of(added.created_at).await.unwrap().title);
+            assert_eq!("Second", as_of(patched.updated_at).await.unwrap().title);
+            let before_added = added.created_at - chrono::Duration::milliseconds(1);
+            assert!(matches!(as_of(before_added).await, Err(Error::NotFound(_))));
+            assert!(matches!(as_of(Utc::now()).await, Err(Error::NotFound(_))));
+
+            let err = book_history(&state, 9999).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn trash_and_restore() {
+        for_each_backend(|state| async move {
+            let book = add_book(&state, &new_book("Binned", "Author, Test"), ACTOR)
+                .await
+                .unwrap();
+            delete_book(&state, book.id, Some(1), "remover")
+                .await
+                .unwrap();
+
+            // Gone from everywhere but the trash
+            let err = book_by_id(&state, book.id).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+            let page = all_books(&state, &BookQuery::default()).await.unwrap();
+            assert!(!page.items.iter().any(|b| b.id == book.id));
+            assert!(search_books(&state, "binned", 10).await.unwrap().is_empty());
+            let err = patch_book(&state, book.id, &BookPatch::default(), ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+            let binned = trash(&state, &BookQuery::default()).await.unwrap();
+            assert_eq!(1, binned.total);
+            assert_eq!("remover", binned.items[0].updated_by);
+            assert!(binned.items[0].deleted_at.is_some());
+
+            // Only books in the trash can be restored
+            let err = restore_book(&state, 1, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+            let restored = restore_book(&state, book.id, "restorer").await.unwrap();
+            assert!(restored.deleted_at.is_none());
+            assert_eq!(3, restored.version);
+            assert_eq!("Binned", book_by_id(&state, book.id).await.unwrap().title);
+            assert_eq!(1, search_books(&state, "binned", 10).await.unwrap().len());
+            assert_eq!(0, trash(&state, &BookQuery::default()).await.unwrap().total);
+            let last = book_history(&state, book.id).await.unwrap().pop().unwrap();
+            assert_eq!(ChangeAction::Restore, last.action);
+            assert_eq!("restorer", last.actor);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn walk_catalog() {
+        for_each_backend(|state| async move {
+            let mut ids = Vec::new();
+            for title in ["Walk one", "Walk two", "Walk three"] {
+                let book = add_book(&state, &new_book(title, "Author, Test"), ACTOR)
+                    .await
+                    .unwrap();
+                ids.push(book.id);
+            }
+            delete_book(&state, ids[1], Some(1), ACTOR).await.unwrap();
+
+            let first = state.repo.books_after(0, 3).await.unwrap();
+            let first: Vec<i32> = first.iter().map(|book| book.id).collect();
+            assert_eq!(first, [1, 2, ids[0]]);
+            let rest = state.repo.books_after(ids[0], 3).await.unwrap();
+            assert_eq!(rest.len(), 1);
+            assert_eq!(rest[0].id, ids[2]);
+            assert_eq!(rest[0].authors[0].name, "Author, Test");
+            assert!(state.repo.books_after(ids[2], 3).await.unwrap().is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn isbns() {
+        for_each_backend(|state| async move {
+            let book = NewBook {
+                isbn: Some("1-68050-816-4".to_string()),
+                ..new_book("Hands-on Rust", "Wolverson, Herbert")
+            };
+            let added = add_book(&state, &book, ACTOR).await.unwrap();
+            assert_eq!(added.isbn13.as_deref(), Some("9781680508161"));
+            assert_eq!(added.isbn10.as_deref(), Some("1680508164"));
+            for isbn in ["978-1-68050-816-1", "1680508164"] {
+                assert_eq!(added.id, book_by_isbn(&state, isbn).await.unwrap().id);
+            }
+            let err = book_by_isbn(&state, "9781680508162").await.unwrap_err();
+            assert!(matches!(err, Error::Validation(_)));
+            let err = book_by_isbn(&state, "9791090636071").await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+
+            // No two live books may share an ISBN, in either form
+            let err = add_book(&state, &book, ACTOR).await.unwrap_err();
+            let Error::Conflict(message) = err else {
+                panic!("expected a conflict, got {err:?}");
+            };
+            assert!(message.contains("9781680508161"));
+            let patch = BookPatch {
+                isbn: Some(Some("9781680508161".to_string())),
+                ..BookPatch::default()
+            };
+            let err = patch_book(&state, 2, &patch, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Conflict(_)));
+
+            // A 979 ISBN has no ISBN-10, and null removes an ISBN
+            let patch = BookPatch {
+                isbn: Some(Some("979-10-90636-07-1".to_string())),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, 2, &patch, ACTOR).await.unwrap();
+            assert_eq!(patched.isbn13.as_deref(), Some("9791090636071"));
+            assert_eq!(patched.isbn10, None);
+            let unchanged = patch_book(&state, 2, &BookPatch::default(), ACTOR)
+                .await
+                .unwrap();
+            assert_eq!(unchanged.isbn13, patched.isbn13);
+            let patch = BookPatch {
+                isbn: Some(None),
+                ..BookPatch::default()
+            };
+            let cleared = patch_book(&state, 2, &patch, ACTOR).await.unwrap();
+            assert_eq!((cleared.isbn13, cleared.isbn10), (None, None));
+
+            // A book in the trash gives up its ISBN until it is restored
+            delete_book(&state, added.id, None, ACTOR).await.unwrap();
+            let readded = add_book(&state, &book, ACTOR).await.unwrap();
+            assert_eq!(
+                readded.id,
+                book_by_isbn(&state, "1680508164").await.unwrap().id
+            );
+            let err = restore_book(&state, added.id, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Conflict(_)));
+            let history = book_history(&state, added.id).await.unwrap();
+            assert_eq!(
+                history[0].after.as_ref().unwrap().isbn13.as_deref(),
+                Some("9781680508161")
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn authors_and_credits() {
+        for_each_backend(|state| async move {
+            // Existing books were credited to their authors by the migration
+            let seeded = book_by_id(&state, 1).await.unwrap();
+            assert_eq!(seeded.authors.len(), 1);
+            assert_eq!(seeded.authors[0].name, seeded.author);
+
+            let translator = add_author(
+                &state,
+                &NewAuthor {
+                    name: "Hulse,Michael".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(translator.name, "Hulse, Michael");
+            let err = add_author(
+                &state,
+                &NewAuthor {
+                    name: "HULSE, michael".to_string(),
+                },
+            )
+            .await
+            .unwrap_err();
+            assert!(matches!(err, Error::Conflict(_)));
+
+            // Credit one author by name, which adds them, and one by ID
+            let book = NewBook {
+                authors: vec![
+                    NewCredit {
+                        name: Some("Sebald, W. G.".to_string()),
+                        ..NewCredit::default()
+                    },
+                    NewCredit {
+                        id: Some(translator.id),
+                        role: AuthorRole::Translator,
+                        ..NewCredit::default()
+                    },
+                ],
+                ..new_book("The Rings of Saturn", "")
+            };
+            let added = add_book(&state, &book, ACTOR).await.unwrap();
+            assert_eq!(added.author, "Sebald, W. G.; Hulse, Michael (translator)");
+            assert_eq!(added.authors[1].id, translator.id);
+            assert_eq!(
+                book_by_id(&state, added.id).await.unwrap().authors,
+                added.authors
+            );
+            let sebald = added.authors[0].id;
+
+            // The string form finds the same authors, ignoring case
+            let again = add_book(
+                &state,
+                &new_book("Vertigo", "sebald, w. g.; Hulse, Michael (Translator)"),
+                ACTOR,
+            )
+            .await
+            .unwrap();
+            assert_eq!(again.authors, added.authors);
+            let missing = NewBook {
+                authors: vec![NewCredit {
+                    id: Some(9999),
+                    ..NewCredit::default()
+                }],
+                ..new_book("Nobody's", "")
+            };
+            let err = add_book(&state, &missing, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Validation(_)));
+
+            let query = BookQuery::default();
+            let page = books_by_author(&state, translator.id, &query)
+                .await
+                .unwrap();
+            assert_eq!(page.total, 2);
+            assert_eq!(page.items[0].title, "The Rings of Saturn");
+            let err = books_by_author(&state, 9999, &query).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+
+            // A rename rewrites the author line of every credited book,
+            // even one in the trash, as a recorded update
+            delete_book(&state, again.id, None, ACTOR).await.unwrap();
+            let renamed = rename_author(
+                &state,
+                sebald,
+                &NewAuthor {
+                    name: "Sebald, Winfried Georg".to_string(),
+                },
+                "renamer",
+            )
+            .await
+            .unwrap();
+            assert_eq!(renamed.id, sebald);
+            let book = book_by_id(&state, added.id).await.unwrap();
+            assert_eq!(
+                book.author,
+                "Sebald, Winfried Georg; Hulse, Michael (translator)"
+            );
+            assert_eq!(book.version, added.version + 1);
+            assert_eq!(book.updated_by, "renamer");
+            let history = book_history(&state, added.id).await.unwrap();
+            let change = history.last().unwrap();
+            assert_eq!(change.action, ChangeAction::Update);
+            assert_eq!(change.before.as_ref().unwrap().authors, added.authors);
+            assert_eq!(change.after.as_ref().unwrap().author, book.author);
+            assert_eq!(change.after.as_ref().unwrap().authors, book.authors);
+            let trashed = restore_book(&state, again.id, ACTOR).await.unwrap();
+            assert_eq!(trashed.author, book.author);
+
+            // Authors can't be removed while they are credited
+            let err = delete_author(&state, translator.id).await.unwrap_err();
+            assert!(matches!(err, Error::Conflict(_)));
+            let patch = BookPatch {
+                author: Some("Sebald, Winfried Georg".to_string()),
+                ..BookPatch::default()
+            };
+            for id in [added.id, again.id] {
+                let patched = patch_book(&state, id, &patch, ACTOR).await.unwrap();
+                assert_eq!(patched.authors.len(), 1);
+            }
+            delete_author(&state, translator.id).await.unwrap();
+            let err = author_by_id(&state, translator.id).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+            let page = authors(&state, 500, 0).await.unwrap();
+            assert_eq!(page.total, page.items.len() as i64);
+            assert!(page.items.iter().any(|a| a.id == sebald));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn editions() {
+        for_each_backend(|state| async move {
+            let book = NewBook {
+                publisher: Some("Pragmatic  Bookshelf".to_string()),
+                published_on: NaiveDate::from_ymd_opt(2021, 7, 20),
+                edition: Some("1st edition".to_string()),
+                page_count: Some(342),
+                language: Some("EN".to_string()),
+                series: Some("The Pragmatic Programmers".to_string()),
+                series_volume: Some(3),
+                ..new_book("Hands-on Rust", "Wolverson, Herbert")
+            };
+            let added = add_book(&state, &book, ACTOR).await.unwrap();
+            assert_eq!(added.publisher.as_deref(), Some("Pragmatic Bookshelf"));
+            assert_eq!(added.published_on, book.published_on);
+            assert_eq!(added.page_count, Some(342));
+            assert_eq!(added.language.as_deref(), Some("en"));
+            assert_eq!(added.series_volume, Some(3));
+            let stored = book_by_id(&state, added.id).await.unwrap();
+            assert_eq!(stored.publisher, added.publisher);
+            assert_eq!(stored.series, added.series);
+
+            // Publishers and series are found by name, ignoring case
+            let other = NewBook {
+                publisher: Some("pragmatic bookshelf".to_string()),
+                page_count: Some(90),
+                ..new_book("Pamphlet", "Author, Test")
+            };
+            let other = add_book(&state, &other, ACTOR).await.unwrap();
+            assert_eq!(other.publisher, added.publisher);
+            let invalid = NewBook {
+                page_count: Some(0),
+                language: Some("English".to_string()),
+                ..new_book("Invalid", "Author, Test")
+            };
+            let Error::Validation(errors) = add_book(&state, &invalid, ACTOR).await.unwrap_err()
+            else {
+                panic!("expected a validation error");
+            };
+            assert_eq!(errors.len(), 2);
+
+            // A patch changes only the fields it mentions, and null clears
+            let patch = BookPatch {
+                series: Some(None),
+                series_volume: Some(None),
+                edition: Some(Some("2nd edition".to_string())),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, added.id, &patch, ACTOR).await.unwrap();
+            assert_eq!(patched.series, None);
+            assert_eq!(patched.series_volume, None);
+            assert_eq!(patched.edition.as_deref(), Some("2nd edition"));
+            assert_eq!(patched.publisher, added.publisher);
+            assert_eq!(patched.page_count, added.page_count);
+            let history = book_history(&state, added.id).await.unwrap();
+            let before = history.last().unwrap().before.as_ref().unwrap();
+            assert_eq!(before.series, added.series);
+
+            // The list can be filtered by any of them
+            let filtered = |filter: BookFilter| {
+                let state = state.clone();
+                async move {
+                    let query = BookQuery {
+                        filter,
+                        ..BookQuery::default()
+                    };
+                    all_books(&state, &query)
+                        .await
+                        .unwrap()
+                        .items
+                        .into_iter()
+                        .map(|book| book.id)
+                        .collect::<Vec<_>>()
+                }
+            };
+            let publisher = BookFilter {
+                publisher: Some("PRAGMATIC BOOKSHELF".to_string()),
+                ..BookFilter::default()
+            };
+            assert_eq!(filtered(publisher).await, vec![added.id, other.id]);
+            let long = BookFilter {
+                min_pages: Some(100),
+                language: Some("en".to_string()),
+                ..BookFilter::default()
+            };
+            assert_eq!(filtered(long).await, vec![added.id]);
+            let recent = BookFilter {
+                published_from: NaiveDate::from_ymd_opt(2021, 7, 20),
+                published_to: NaiveDate::from_ymd_opt(2021, 12, 31),
+                edition: Some("2ND EDITION".to_string()),
+                ..BookFilter::default()
+            };
+            assert_eq!(filtered(recent).await, vec![added.id]);
+            let series = BookFilter {
+                series: Some("The Pragmatic Programmers".to_string()),
+                ..BookFilter::default()
+            };
+            assert!(filtered(series).await.is_empty());
+            let unknown = BookFilter {
+                publisher: Some("Nobody".to_string()),
+                ..BookFilter::default()
+            };
+            assert!(filtered(unknown).await.is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn tags_and_facets() {
+        for_each_backend(|state| async move {
+            let rust = add_book(&state, &new_book("Rust in Action", "McNamara, Tim"), ACTOR)
+                .await
+                .unwrap();
+            let games = add_book(&state, &new_book("Game Engine", "Gregory, Jason"), ACTOR)
+                .await
+                .unwrap();
+
+            // Tags are stored in lower case, and tagging twice is a no-op
+            let tagged = tag_book(&state, 1, " Rust ", "tagger").await.unwrap();
+            assert_eq!(tagged.tags, vec!["rust"]);
+            assert_eq!(tagged.updated_by, "tagger");
+            let again = tag_book(&state, 1, "RUST", ACTOR).await.unwrap();
+            assert_eq!(again.version, tagged.version);
+            let tagged = tag_book(&state, 1, "Games", ACTOR).await.unwrap();
+            assert_eq!(tagged.tags, vec!["games", "rust"]);
+            assert_eq!(book_by_id(&state, 1).await.unwrap().tags, tagged.tags);
+            tag_book(&state, rust.id, "rust", ACTOR).await.unwrap();
+            tag_book(&state, games.id, "games", ACTOR).await.unwrap();
+            let err = tag_book(&state, 1, "  ", ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Validation(_)));
+            let err = tag_book(&state, 9999, "rust", ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+
+            // Each change to a book's tags is a recorded update, and other
+            // changes keep them
+            let history = book_history(&state, 1).await.unwrap();
+            let change = history.last().unwrap();
+            assert_eq!(change.action, ChangeAction::Update);
+            assert_eq!(change.before.as_ref().unwrap().tags, vec!["rust"]);
+            let patch = BookPatch {
+                title: Some("Hands-on Rust, 2nd edition".to_string()),
+                ..BookPatch::default()
+            };
+            let patched = patch_book(&state, 1, &patch, ACTOR).await.unwrap();
+            assert_eq!(patched.tags, tagged.tags);
+
+            // Listing by tag needs every tag, and the facets count tags
+            // across every matching book
+            let query = |tags: &[&str]| BookQuery {
+                limit: 1,
+                filter: BookFilter {
+                    tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                    ..BookFilter::default()
+                },
+                ..BookQuery::default()
+            };
+            let page = all_books(&state, &query(&["rust"])).await.unwrap();
+            assert_eq!(page.total, 2);
+            let page = all_books(&state, &query(&["Rust", "games"])).await.unwrap();
+            assert_eq!(page.total, 1);
+            assert_eq!(page.items[0].id, 1);
+            let facets = book_facets(&state, &query(&["rust"])).await.unwrap();
+            assert_eq!(
+                facets,
+                vec![
+                    TagCount {
+                        tag: "rust".to_string(),
+                        count: 2
+                    },
+                    TagCount {
+                        tag: "games".to_string(),
+                        count: 1
+                    },
+                ]
+            );
+            let facets = book_facets(&state, &BookQuery::default()).await.unwrap();
+            assert_eq!(facets[0].count, 2);
+            assert_eq!(facets[1].count, 2);
+
+            // Untagging and the trash both change the counts
+            let untagged = untag_book(&state, rust.id, "RUST", ACTOR).await.unwrap();
+            assert!(untagged.tags.is_empty());
+            let err = untag_book(&state, rust.id, "rust", ACTOR)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+            delete_book(&state, games.id, None, ACTOR).await.unwrap();
+            let facets = book_facets(&state, &BookQuery::default()).await.unwrap();
+            assert_eq!(
+                facets
+                    .iter()
+                    .map(|facet| (facet.tag.as_str(), facet.count))
+                    .collect::<Vec<_>>(),
+                vec![("games", 1), ("rust", 1)]
+            );
+            let restored = restore_book(&state, games.id, ACTOR).await.unwrap();
+            assert_eq!(restored.tags, vec!["games"]);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn filter_expressions() {
+        for_each_backend(|state| async move {
+            let book = NewBook {
+                publisher: Some("No Starch Press".to_string()),
+                page_count: Some(560),
+                ..new_book("The Rust Programming Language, 100% safe", "Klabnik, Steve")
+            };
+            let added = add_book(&state, &book, ACTOR).await.unwrap();
+            let matching = |text: &str| {
+                let state = state.clone();
+                let query = BookQuery {
+                    sort: SortField::Id,
+                    filter: BookFilter {
+                        expression: Some(FilterExpr::parse(text).unwrap()),
+                        ..BookFilter::default()
+                    },
+                    ..BookQuery::default()
+                };
+                async move {
+                    all_books(&state, &query)
+                        .await
+                        .unwrap()
+                        .items
+                        .into_iter()
+                        .map(|book| book.id)
+                        .collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(
+                matching(r#"title contains "RUST""#).await,
+                vec![1, 2, added.id]
+            );
+            assert_eq!(matching(r#"title contains "100%""#).await, vec![added.id]);
+            assert!(matching(r#"title contains "1_0""#).await.is_empty());
+            assert_eq!(
+                matching(r#"title startswith "hands" or publisher = "No Starch Press""#).await,
+                vec![1, added.id]
+            );
+            assert_eq!(
+                matching("page_count > 500 and publisher is not null").await,
+                vec![added.id]
+            );
+            assert_eq!(
+                matching(&format!("created_at >= {}", added.created_at.to_rfc3339())).await,
+                vec![added.id]
+            );
+            assert_eq!(
+                matching("not (created_at > 2000-01-01)").await,
+                Vec::<i32>::new()
+            );
+            assert_eq!(
+                matching(r#"not (author endswith "steve") and id <= 2"#).await,
+                vec![1, 2]
+            );
+            assert_eq!(
+                matching(r#"title = "x'); DELETE FROM books; --""#).await,
+                Vec::<i32>::new()
+            );
+            assert_eq!(
+                all_books(&state, &BookQuery::default())
+                    .await
+                    .unwrap()
+                    .total,
+                3
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn purge() {
+        for_each_backend(|state| async move {
+            let state = state.with_trash(crate::config::TrashConfig {
+                retention: std::time::Duration::ZERO,
+                admin_token: Some("s3cret".to_string()),
+            });
+            let book = add_book(&state, &new_book("Purged", "Author, Test"), ACTOR)
+                .await
+                .unwrap();
+            delete_book(&state, book.id, None, ACTOR).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+            let err = purge_books(&state, None, "admin").await.unwrap_err();
+            assert!(matches!(err, Error::Forbidden(_)));
+            let err = purge_books(&state, Some("guess"), "admin")
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::Forbidden(_)));
+            assert_eq!(
+                1,
+                purge_books(&state, Some("s3cret"), "admin").await.unwrap()
+            );
+            assert_eq!(0, trash(&state, &BookQuery::default()).await.unwrap().total);
+            let err = restore_book(&state, book.id, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)));
+
+            // The history outlives the book
+            let last = book_history(&state, book.id).await.unwrap().pop().unwrap();
+            assert_eq!(ChangeAction::Purge, last.action);
+            assert_eq!("admin", last.actor);
+            assert!(last.after.is_none());
+            assert_eq!(
+                0,
+                purge_books(&state, Some("s3cret"), "admin").await.unwrap()
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        for_each_backend(|state| async move {
+            let create = |title: &str| BatchOp::Create {
+                book: new_book(title, "Author, Test"),
+            };
+            let rename = |id: i32, title: &str, version: i64| BatchOp::Update {
+                id,
+                book: BookUpdate {
+                    title: title.to_string(),
+                    author: "Author, Test".to_string(),
+                    authors: Vec::new(),
+                    isbn: None,
+                    version: Some(version),
+                    ..BookUpdate::default()
+                },
+            };
+            let count = |state: AppState| async move {
+                all_books(&state, &BookQuery::default())
+                    .await
+                    .unwrap()
+                    .total
+            };
+            let books = count(state.clone()).await;
+
+            // One failure undoes the whole atomic batch
+            let atomic = Batch {
+                mode: BatchMode::Atomic,
+                operations: vec![
+                    create("Batch one"),
+                    rename(1, "Renamed", 1),
+                    rename(9999, "Missing", 1),
+                    create("Never reached"),
+                ],
+            };
+            let outcome = batch(&state, &atomic, ACTOR).await.unwrap();
+            assert!(!outcome.committed);
+            assert_eq!(3, outcome.items.len());
+            assert!(matches!(outcome.items[2].outcome, Err(Error::NotFound(_))));
+            assert_eq!(books, count(state.clone()).await);
+            assert_eq!("Hands-on Rust", book_by_id(&state, 1).await.unwrap().title);
+
+            // A partial batch keeps what worked, with a single invalidation
+            let generation = state.cache.generation();
+            let partial = Batch {
+                mode: BatchMode::Partial,
+                operations: vec![
+                    create("Batch one"),
+                    rename(1, "Renamed", 1),
+                    create(""),
+                    rename(2, "Stale", 7),
+                    BatchOp::Delete {
+                        id: 2,
+                        version: Some(1),
+                    },
+                ],
+            };
+            let outcome = batch(&state, &partial, ACTOR).await.unwrap();
+            assert!(outcome.committed);
+            let indices: Vec<_> = outcome.items.iter().map(|item| item.index).collect();
+            assert_eq!(vec![0, 1, 2, 3, 4], indices);
+            assert!(matches!(
+                outcome.items[2].outcome,
+                Err(Error::Validation(_))
+            ));
+            assert!(matches!(outcome.items[3].outcome, Err(Error::Stale(_))));
+            assert!(matches!(outcome.items[4].outcome, Ok(None)));
+            assert_eq!(generation.count + 1, state.cache.generation().count);
+            assert_eq!("Renamed", book_by_id(&state, 1).await.unwrap().title);
+            assert!(book_by_id(&state, 2).await.is_err());
+            assert_eq!(books, count(state.clone()).await);
+
+            // Refused operations leave nothing in the history
+            let history = book_history(&state, 2).await.unwrap();
+            let actions: Vec<_> = history.iter().map(|change| change.action).collect();
+            assert_eq!(vec![ChangeAction::Insert, ChangeAction::Delete], actions);
+
+            let too_many = Batch {
+                mode: BatchMode::Partial,
+                operations: vec![create("Too many"); MAX_BATCH_SIZE + 1],
+            };
+            let err = batch(&state, &too_many, ACTOR).await.unwrap_err();
+            assert!(matches!(err, Error::Validation(_)));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,981 @@
+//! PostgreSQL implementation of `BookRepository`.
+
+use super::filter::Timestamps;
+use super::{
+    author_error, credit_line, write_error, Author, AuthorRole, BatchOp, Book, BookChange,
+    BookPatch, BookQuery, BookRepository, BookUpdate, ChangeAction, ChangeRow, Credit, NewAuthor,
+    NewBook, NewCredit, Page, SearchHit, TagCount, MATCH_END, MATCH_START, MAX_FACETS,
+    MAX_PAGE_SIZE,
+};
+use crate::config::DbConfig;
+use crate::error::{Error, FieldError, Result};
+use crate::isbn;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Connection, PgConnection, PgPool, Postgres, QueryBuilder, Row, Transaction};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Books stored in PostgreSQL. Migrations live in `migrations/postgres`.
+pub struct PostgresRepository {
+    pool: PgPool,
+}
+
+impl PostgresRepository {
+    /// Create a connection pool with the given settings. Run any migrations.
+    /// The database itself must already exist.
+    ///
+    /// ## Arguments
+    /// * `config` - the database location and pool settings.
+    pub async fn connect(config: &DbConfig) -> Result<Self> {
+        let options = PgConnectOptions::from_str(&config.url)?;
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .min_connections(config.min_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .idle_timeout(config.idle_timeout)
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!("migrations/postgres").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+impl Timestamps for Postgres {
+    fn push_timestamp(sql: &mut QueryBuilder<'_, Self>, time: DateTime<Utc>) {
+        sql.push_bind(time);
+    }
+}
+
+/// Turns free text typed by a user into a `to_tsquery` expression.
+/// Only letters and digits survive (so tsquery operators are never
+/// passed through), every word is prefix-matched, and all words must match.
+fn ts_query_expression(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("{}:*", term.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" & ")
+}
+
+#[async_trait]
+impl BookRepository for PostgresRepository {
+    async fn all_books(&self, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, false, None).await
+    }
+
+    async fn trash(&self, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, true, None).await
+    }
+
+    async fn books_after(&self, after: i32, limit: i64) -> Result<Vec<Book>> {
+        let mut tx = self.begin_read().await?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books WHERE id > $1 AND deleted_at IS NULL ORDER BY id LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(books)
+    }
+
+    async fn book_by_id(&self, id: i32) -> Result<Book> {
+        let mut tx = self.begin_read().await?;
+        let mut book =
+            sqlx::query_as::<_, Book>("SELECT * FROM books WHERE id=$1 AND deleted_at IS NULL")
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| Error::NotFound(format!("book {id} not found")))?;
+        attach_details(&mut tx, vec![&mut book]).await?;
+        tx.commit().await?;
+        Ok(book)
+    }
+
+    async fn book_by_isbn(&self, isbn13: &str) -> Result<Book> {
+        let mut tx = self.begin_read().await?;
+        let mut book =
+            sqlx::query_as::<_, Book>("SELECT * FROM books WHERE isbn13=$1 AND deleted_at IS NULL")
+                .bind(isbn13)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| Error::NotFound(format!("no book has ISBN {isbn13}")))?;
+        attach_details(&mut tx, vec![&mut book]).await?;
+        tx.commit().await?;
+        Ok(book)
+    }
+
+    async fn books_by_title_author(&self, title: &str, author: &str) -> Result<Vec<Book>> {
+        let mut tx = self.begin_read().await?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books
+             WHERE lower(title)=lower($1) AND lower(author)=lower($2) AND deleted_at IS NULL
+             ORDER BY id",
+        )
+        .bind(title)
+        .bind(author)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(books)
+    }
+
+    async fn search_books(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>> {
+        let expression = ts_query_expression(text);
+        if expression.is_empty() {
+            return Ok(Vec::new());
+        }
+        // ts_rank is "higher is better"; negate it so that, as with
+        // SQLite's bm25, lower ranks are better matches.
+        let mut tx = self.begin_read().await?;
+        let mut hits = sqlx::query_as::<_, SearchHit>(
+            "SELECT books.*, (-ts_rank(books.search_vector, query))::float8 AS rank,
+                    ts_headline('simple', books.title, query, $3) AS title_highlight,
+                    ts_headline('simple', books.author, query, $3) AS author_highlight
+             FROM books, to_tsquery('simple', $1) AS query
+             WHERE books.search_vector @@ query AND books.deleted_at IS NULL
+             ORDER BY rank
+             LIMIT $2",
+        )
+        .bind(expression)
+        .bind(limit.clamp(1, MAX_PAGE_SIZE))
+        .bind(format!(
+            "StartSel={MATCH_START}, StopSel={MATCH_END}, HighlightAll=true"
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, hits.iter_mut().map(|hit| &mut hit.book).collect()).await?;
+        tx.commit().await?;
+        Ok(hits)
+    }
+
+    async fn book_history(&self, id: i32) -> Result<Vec<BookChange>> {
+        sqlx::query_as::<_, ChangeRow>(
+            "SELECT * FROM book_history WHERE book_id=$1 ORDER BY changed_at, id",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(ChangeRow::into_change)
+        .collect()
+    }
+
+    async fn book_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Book> {
+        let after: Option<Option<Json<Book>>> = sqlx::query_scalar(
+            "SELECT after FROM book_history WHERE book_id=$1 AND changed_at <= $2
+             ORDER BY changed_at DESC, id DESC LIMIT 1",
+        )
+        .bind(id)
+        .bind(as_of)
+        .fetch_optional(&self.pool)
+        .await?;
+        after
+            .flatten()
+            .map(|book| book.0)
+            .filter(|book| book.deleted_at.is_none())
+            .ok_or_else(|| Error::NotFound(format!("book {id} did not exist at {as_of}")))
+    }
+
+    async fn add_book(&self, book: &NewBook, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let added = insert(&mut tx, book, actor).await?;
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    async fn update_book(&self, id: i32, update: &BookUpdate, actor: &str) -> Result<Book> {
+        self.patch_book(id, &update.into(), actor).await
+    }
+
+    async fn patch_book(&self, id: i32, patch: &BookPatch, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let patched = change(&mut tx, id, patch, actor).await?;
+        tx.commit().await?;
+        Ok(patched)
+    }
+
+    async fn delete_book(&self, id: i32, version: Option<i64>, actor: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        soft_delete(&mut tx, id, version, actor).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn restore_book(&self, id: i32, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let before = lock_book(&mut tx, id, true).await?;
+        let after = sqlx::query_as::<_, Book>(
+            "UPDATE books SET deleted_at=NULL,
+                              version=version+1, updated_at=now(), updated_by=$2
+             WHERE id=$1 RETURNING *",
+        )
+        .bind(id)
+        .bind(actor)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| write_error(e, before.isbn13.as_deref()))?;
+        let after = with_details(after, &before);
+        let changed_at = Some(after.updated_at);
+        record(
+            &mut tx,
+            ChangeAction::Restore,
+            actor,
+            changed_at,
+            Some(&before),
+            Some(&after),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn purge_books(&self, deleted_before: DateTime<Utc>, actor: &str) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        // The credits go with the books, so read them first.
+        let mut purged = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books WHERE deleted_at IS NOT NULL AND deleted_at < $1 FOR UPDATE",
+        )
+        .bind(deleted_before)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, purged.iter_mut().collect()).await?;
+        let ids: Vec<i32> = purged.iter().map(|book| book.id).collect();
+        sqlx::query("DELETE FROM books WHERE id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+        for book in &purged {
+            record(&mut tx, ChangeAction::Purge, actor, None, Some(book), None).await?;
+        }
+        tx.commit().await?;
+        Ok(purged.len() as u64)
+    }
+
+    async fn authors(&self, limit: i64, offset: i64) -> Result<Page<Author>> {
+        let total: i64 = sqlx::query("SELECT COUNT(*) FROM authors")
+            .fetch_one(&self.pool)
+            .await?
+            .get(0);
+        let items = sqlx::query_as::<_, Author>(
+            "SELECT * FROM authors ORDER BY lower(name), id LIMIT $1 OFFSET $2",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+
+    async fn author_by_id(&self, id: i32) -> Result<Author> {
+        sqlx::query_as::<_, Author>("SELECT * FROM authors WHERE id=$1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("author {id} not found")))
+    }
+
+    async fn books_by_author(&self, id: i32, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, false, Some(id)).await
+    }
+
+    async fn add_author(&self, author: &NewAuthor) -> Result<Author> {
+        sqlx::query_as::<_, Author>("INSERT INTO authors (name) VALUES ($1) RETURNING *")
+            .bind(&author.name)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| author_error(e, &author.name))
+    }
+
+    async fn rename_author(
+        &self,
+        id: i32,
+        author: &NewAuthor,
+        actor: &str,
+    ) -> Result<(Author, Vec<i32>)> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT id FROM authors WHERE id=$1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("author {id} not found")))?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books
+             WHERE id IN (SELECT book_id FROM book_authors WHERE author_id=$1)
+             ORDER BY id FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        let renamed =
+            sqlx::query_as::<_, Author>("UPDATE authors SET name=$1 WHERE id=$2 RETURNING *")
+                .bind(&author.name)
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| author_error(e, &author.name))?;
+
+        let mut changed = Vec::new();
+        for before in books {
+            let mut credits = before.authors.clone();
+            for credit in credits.iter_mut().filter(|c| c.id == id) {
+                credit.name = renamed.name.clone();
+            }
+            let line = credit_line(credits.iter().map(|c| (c.name.as_str(), c.role)));
+            if line == before.author {
+                continue;
+            }
+            let after = sqlx::query_as::<_, Book>(
+                "UPDATE books SET author=$1, version=version+1, updated_at=now(), updated_by=$2
+                 WHERE id=$3 RETURNING *",
+            )
+            .bind(&line)
+            .bind(actor)
+            .bind(before.id)
+            .fetch_one(&mut *tx)
+            .await?;
+            let after = Book {
+                authors: credits,
+                ..with_details(after, &before)
+            };
+            let changed_at = Some(after.updated_at);
+            record(
+                &mut tx,
+                ChangeAction::Update,
+                actor,
+                changed_at,
+                Some(&before),
+                Some(&after),
+            )
+            .await?;
+            changed.push(before.id);
+        }
+        tx.commit().await?;
+        Ok((renamed, changed))
+    }
+
+    async fn delete_author(&self, id: i32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // Lock the author, so that no credit can be added until we are done.
+        sqlx::query("SELECT id FROM authors WHERE id=$1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("author {id} not found")))?;
+        let credited: i64 =
+            sqlx::query("SELECT COUNT(DISTINCT book_id) FROM book_authors WHERE author_id=$1")
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await?
+                .get(0);
+        if credited > 0 {
+            return Err(Error::Conflict(format!(
+                "author {id} is credited on {credited} books, counting any in the trash"
+            )));
+        }
+        sqlx::query("DELETE FROM authors WHERE id=$1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn tag_facets(&self, query: &BookQuery) -> Result<Vec<TagCount>> {
+        let mut sql = QueryBuilder::new(
+            "SELECT tags.name AS tag, COUNT(*) AS count
+             FROM book_tags JOIN tags ON tags.id = book_tags.tag_id
+             WHERE book_tags.book_id IN (SELECT id FROM books WHERE ",
+        );
+        push_conditions(&mut sql, query, false, None);
+        sql.push(") GROUP BY tags.name ORDER BY count DESC, tags.name LIMIT ")
+            .push_bind(MAX_FACETS);
+        Ok(sql.build_query_as().fetch_all(&self.pool).await?)
+    }
+
+    async fn tag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let before = lock_book(&mut tx, id, false).await?;
+        if before.tags.iter().any(|t| t == tag) {
+            return Ok(before);
+        }
+        let (tag_id, _) = find_or_add(&mut tx, "tags", Some(tag))
+            .await?
+            .expect("a name was given");
+        sqlx::query("INSERT INTO book_tags (book_id, tag_id) VALUES ($1, $2)")
+            .bind(id)
+            .bind(tag_id)
+            .execute(&mut *tx)
+            .await?;
+        let after = finish_retag(&mut tx, &before, actor).await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn untag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let before = lock_book(&mut tx, id, false).await?;
+        if !before.tags.iter().any(|t| t == tag) {
+            return Err(Error::NotFound(format!("book {id} is not tagged {tag}")));
+        }
+        sqlx::query(
+            "DELETE FROM book_tags
+             WHERE book_id=$1 AND tag_id IN (SELECT id FROM tags WHERE lower(name)=lower($2))",
+        )
+        .bind(id)
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?;
+        let after = finish_retag(&mut tx, &before, actor).await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn batch(
+        &self,
+        ops: &[BatchOp],
+        actor: &str,
+        atomic: bool,
+    ) -> Result<Vec<Result<Option<Book>>>> {
+        let mut tx = self.pool.begin().await?;
+        let mut outcomes = Vec::with_capacity(ops.len());
+        for op in ops {
+            // Each operation gets a savepoint: PostgreSQL refuses to run
+            // anything more in a transaction after an error, until it is
+            // rolled back to before the failure.
+            let mut savepoint = tx.begin().await?;
+            let outcome = match op {
+                BatchOp::Create { book } => insert(&mut savepoint, book, actor).await.map(Some),
+                BatchOp::Update { id, book } => change(&mut savepoint, *id, &book.into(), actor)
+                    .await
+                    .map(Some),
+                BatchOp::Delete { id, version } => {
+                    soft_delete(&mut savepoint, *id, *version, actor)
+                        .await
+                        .map(|()| None)
+                }
+            };
+            let failed = outcome.is_err();
+            if failed {
+                savepoint.rollback().await?;
+            } else {
+                savepoint.commit().await?;
+            }
+            outcomes.push(outcome);
+            if failed && atomic {
+                tx.rollback().await?;
+                return Ok(outcomes);
+            }
+        }
+        tx.commit().await?;
+        Ok(outcomes)
+    }
+}
+
+impl PostgresRepository {
+    /// Start a read-only transaction in which every statement sees the same
+    /// snapshot, so books and the details read alongside them agree. The
+    /// default, read committed, takes a fresh snapshot per statement.
+    async fn begin_read(&self) -> Result<Transaction<'_, Postgres>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *tx)
+            .await?;
+        Ok(tx)
+    }
+
+    /// A page of either the live books or, if `in_trash`, the deleted ones,
+    /// optionally only those crediting `author`.
+    async fn page(
+        &self,
+        query: &BookQuery,
+        in_trash: bool,
+        author: Option<i32>,
+    ) -> Result<Page<Book>> {
+        let mut tx = self.begin_read().await?;
+        let mut sql = QueryBuilder::new("SELECT COUNT(*) FROM books WHERE ");
+        push_conditions(&mut sql, query, in_trash, author);
+        let total: i64 = sql.build().fetch_one(&mut *tx).await?.get(0);
+        let mut sql = QueryBuilder::new("SELECT * FROM books WHERE ");
+        push_conditions(&mut sql, query, in_trash, author);
+        sql.push(" ORDER BY ")
+            .push(query.order_by())
+            .push(" LIMIT ")
+            .push_bind(query.limit)
+            .push(" OFFSET ")
+            .push_bind(query.offset);
+        let mut items = sql.build_query_as::<Book>().fetch_all(&mut *tx).await?;
+        attach_details(&mut tx, items.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(Page { items, total })
+    }
+}
+
+/// Add the conditions selecting a page of books (see `page`) to `sql`.
+fn push_conditions(
+    sql: &mut QueryBuilder<'_, Postgres>,
+    query: &BookQuery,
+    in_trash: bool,
+    author: Option<i32>,
+) {
+    sql.push(if in_trash {
+        "deleted_at IS NOT NULL"
+    } else {
+        "deleted_at IS NULL"
+    });
+    if let Some(since) = query.updated_since {
+        sql.push(" AND updated_at >= ").push_bind(since);
+    }
+    if let Some(author) = author {
+        sql.push(" AND id IN (SELECT book_id FROM book_authors WHERE author_id=")
+            .push_bind(author)
+            .push(")");
+    }
+    query.filter.push_conditions(sql);
+}
+
+/// Insert a book, and record it in the history.
+async fn insert(conn: &mut PgConnection, book: &NewBook, actor: &str) -> Result<Book> {
+    let credits = resolve_credits(conn, &book.authors).await?;
+    let line = credit_line(credits.iter().map(|c| (c.name.as_str(), c.role)));
+    let publisher = find_or_add(conn, "publishers", book.publisher.as_deref()).await?;
+    let series = find_or_add(conn, "series", book.series.as_deref()).await?;
+    let added = sqlx::query_as::<_, Book>(
+        "INSERT INTO books (title, author, isbn13, isbn10,
+                            publisher_id, published_on, edition, page_count, language,
+                            series_id, series_volume, created_by, updated_by)
+         VALUES ($1, $2, $4, $5, $6, $7, $8, $9, $10, $11, $12, $3, $3) RETURNING *",
+    )
+    .bind(&book.title)
+    .bind(&line)
+    .bind(actor)
+    .bind(&book.isbn)
+    .bind(book.isbn.as_deref().and_then(isbn::to_isbn10))
+    .bind(publisher.as_ref().map(|(id, _)| *id))
+    .bind(book.published_on)
+    .bind(&book.edition)
+    .bind(book.page_count)
+    .bind(&book.language)
+    .bind(series.as_ref().map(|(id, _)| *id))
+    .bind(book.series_volume)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| write_error(e, book.isbn.as_deref()))?;
+    set_credits(conn, added.id, &credits).await?;
+    let added = Book {
+        authors: credits,
+        publisher: publisher.map(|(_, name)| name),
+        series: series.map(|(_, name)| name),
+        ..added
+    };
+    let changed_at = Some(added.created_at);
+    record(
+        conn,
+        ChangeAction::Insert,
+        actor,
+        changed_at,
+        None,
+        Some(&added),
+    )
+    .await?;
+    Ok(added)
+}
+
+/// Apply `patch` to a live book, and record it in the history.
+async fn change(conn: &mut PgConnection, id: i32, patch: &BookPatch, actor: &str) -> Result<Book> {
+    let before = lock_book(conn, id, false).await?;
+    let isbn = patch.isbn.clone().flatten();
+    let credits = match &patch.authors {
+        Some(authors) => Some(resolve_credits(conn, authors).await?),
+        None => None,
+    };
+    let line = credits
+        .as_ref()
+        .map(|credits| credit_line(credits.iter().map(|c| (c.name.as_str(), c.role))));
+    let publisher = match &patch.publisher {
+        Some(name) => Some(find_or_add(conn, "publishers", name.as_deref()).await?),
+        None => None,
+    };
+    let series = match &patch.series {
+        Some(name) => Some(find_or_add(conn, "series", name.as_deref()).await?),
+        None => None,
+    };
+    let Some(after) = sqlx::query_as::<_, Book>(
+        "UPDATE books SET title=COALESCE($1, title), author=COALESCE($2, author),
+                          isbn13=CASE WHEN $6 THEN $7 ELSE isbn13 END,
+                          isbn10=CASE WHEN $6 THEN $8 ELSE isbn10 END,
+                          publisher_id=CASE WHEN $9 THEN $10 ELSE publisher_id END,
+                          published_on=CASE WHEN $11 THEN $12 ELSE published_on END,
+                          edition=CASE WHEN $13 THEN $14 ELSE edition END,
+                          page_count=CASE WHEN $15 THEN $16 ELSE page_count END,
+                          language=CASE WHEN $17 THEN $18 ELSE language END,
+                          series_id=CASE WHEN $19 THEN $20 ELSE series_id END,
+                          series_volume=CASE WHEN $21 THEN $22 ELSE series_volume END,
+                          version=version+1, updated_at=now(), updated_by=$5
+         WHERE id=$3 AND ($4::BIGINT IS NULL OR version=$4) RETURNING *",
+    )
+    .bind(&patch.title)
+    .bind(&line)
+    .bind(id)
+    .bind(patch.version)
+    .bind(actor)
+    .bind(patch.isbn.is_some())
+    .bind(&isbn)
+    .bind(isbn.as_deref().and_then(isbn::to_isbn10))
+    .bind(publisher.is_some())
+    .bind(
+        publisher
+            .as_ref()
+            .and_then(|p| p.as_ref().map(|(id, _)| *id)),
+    )
+    .bind(patch.published_on.is_some())
+    .bind(patch.published_on.flatten())
+    .bind(patch.edition.is_some())
+    .bind(patch.edition.clone().flatten())
+    .bind(patch.page_count.is_some())
+    .bind(patch.page_count.flatten())
+    .bind(patch.language.is_some())
+    .bind(patch.language.clone().flatten())
+    .bind(series.is_some())
+    .bind(series.as_ref().and_then(|s| s.as_ref().map(|(id, _)| *id)))
+    .bind(patch.series_volume.is_some())
+    .bind(patch.series_volume.flatten())
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| write_error(e, isbn.as_deref()))?
+    else {
+        return Err(Error::Stale(Box::new(before)));
+    };
+    let authors = match credits {
+        Some(credits) => {
+            set_credits(conn, id, &credits).await?;
+            credits
+        }
+        None => before.authors.clone(),
+    };
+    let after = Book {
+        authors,
+        publisher: publisher.map_or(before.publisher.clone(), |p| p.map(|(_, name)| name)),
+        series: series.map_or(before.series.clone(), |s| s.map(|(_, name)| name)),
+        ..with_details(after, &before)
+    };
+    let changed_at = Some(after.updated_at);
+    record(
+        conn,
+        ChangeAction::Update,
+        actor,
+        changed_at,
+        Some(&before),
+        Some(&after),
+    )
+    .await?;
+    Ok(after)
+}
+
+/// Move a live book to the trash, and record it in the history.
+async fn soft_delete(
+    conn: &mut PgConnection,
+    id: i32,
+    version: Option<i64>,
+    actor: &str,
+) -> Result<()> {
+    let before = lock_book(conn, id, false).await?;
+    let Some(after) = sqlx::query_as::<_, Book>(
+        "UPDATE books SET deleted_at=now(),
+                          version=version+1, updated_at=now(), updated_by=$3
+         WHERE id=$1 AND ($2::BIGINT IS NULL OR version=$2) RETURNING *",
+    )
+    .bind(id)
+    .bind(version)
+    .bind(actor)
+    .fetch_optional(&mut *conn)
+    .await?
+    else {
+        return Err(Error::Stale(Box::new(before)));
+    };
+    let after = with_details(after, &before);
+    let changed_at = Some(after.updated_at);
+    record(
+        conn,
+        ChangeAction::Delete,
+        actor,
+        changed_at,
+        Some(&before),
+        Some(&after),
+    )
+    .await
+}
+
+/// Lock book `id` for the rest of the transaction, and return it as it is
+/// now. The book must be live or, if `in_trash`, deleted.
+async fn lock_book(conn: &mut PgConnection, id: i32, in_trash: bool) -> Result<Book> {
+    let deleted = if in_trash { "IS NOT NULL" } else { "IS NULL" };
+    let mut book = sqlx::query_as::<_, Book>(&format!(
+        "SELECT * FROM books WHERE id=$1 AND deleted_at {deleted} FOR UPDATE"
+    ))
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("book {id} not found")))?;
+    attach_details(conn, vec![&mut book]).await?;
+    Ok(book)
+}
+
+/// Bump the version of a book whose tags have just changed, and record the
+/// change in the history.
+async fn finish_retag(conn: &mut PgConnection, before: &Book, actor: &str) -> Result<Book> {
+    let mut after = sqlx::query_as::<_, Book>(
+        "UPDATE books SET version=version+1, updated_at=now(), updated_by=$2
+         WHERE id=$1 RETURNING *",
+    )
+    .bind(before.id)
+    .bind(actor)
+    .fetch_one(&mut *conn)
+    .await?;
+    attach_details(conn, vec![&mut after]).await?;
+    let changed_at = Some(after.updated_at);
+    record(
+        conn,
+        ChangeAction::Update,
+        actor,
+        changed_at,
+        Some(before),
+        Some(&after),
+    )
+    .await?;
+    Ok(after)
+}
+
+/// Look up or add the authors to credit, in order.
+async fn resolve_credits(conn: &mut PgConnection, credits: &[NewCredit]) -> Result<Vec<Credit>> {
+    let mut resolved = Vec::with_capacity(credits.len());
+    for credit in credits {
+        let author = match (credit.id, &credit.name) {
+            (Some(id), _) => sqlx::query_as::<_, Author>("SELECT * FROM authors WHERE id=$1")
+                .bind(id)
+                .fetch_optional(&mut *conn)
+                .await?
+                .ok_or_else(|| FieldError::new("authors", format!("there is no author {id}")))?,
+            (None, Some(name)) => {
+                let (id, name) = find_or_add(conn, "authors", Some(name))
+                    .await?
+                    .expect("a name was given");
+                Author { id, name }
+            }
+            (None, None) => unreachable!("validated credits have an ID or a name"),
+        };
+        resolved.push(Credit {
+            id: author.id,
+            name: author.name,
+            role: credit.role,
+        });
+    }
+    Ok(resolved)
+}
+
+/// The ID and name of the row in `table` (authors, publishers, series or
+/// tags) called `name`, ignoring case, adding one if there is none. `None`
+/// if there is no name.
+async fn find_or_add(
+    conn: &mut PgConnection,
+    table: &'static str,
+    name: Option<&str>,
+) -> Result<Option<(i32, String)>> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    sqlx::query(&format!(
+        "INSERT INTO {table} (name) VALUES ($1) ON CONFLICT DO NOTHING"
+    ))
+    .bind(name)
+    .execute(&mut *conn)
+    .await?;
+    let sql = format!("SELECT id, name FROM {table} WHERE lower(name)=lower($1)");
+    Ok(Some(
+        sqlx::query_as::<_, (i32, String)>(&sql)
+            .bind(name)
+            .fetch_one(&mut *conn)
+            .await?,
+    ))
+}
+
+/// Replace a book's credits.
+async fn set_credits(conn: &mut PgConnection, book_id: i32, credits: &[Credit]) -> Result<()> {
+    sqlx::query("DELETE FROM book_authors WHERE book_id=$1")
+        .bind(book_id)
+        .execute(&mut *conn)
+        .await?;
+    for (position, credit) in credits.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO book_authors (book_id, author_id, position, role) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(book_id)
+        .bind(credit.id)
+        .bind(position as i32)
+        .bind(credit.role.as_str())
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Fill in the details of each book that are kept in other tables: its
+/// `authors`, `publisher`, `series` and `tags`.
+async fn attach_details(conn: &mut PgConnection, books: Vec<&mut Book>) -> Result<()> {
+    if books.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i32> = books.iter().map(|book| book.id).collect();
+    let rows = sqlx::query(
+        "SELECT book_authors.book_id, authors.id, authors.name, book_authors.role
+         FROM book_authors JOIN authors ON authors.id = book_authors.author_id
+         WHERE book_authors.book_id = ANY($1)
+         ORDER BY book_authors.book_id, book_authors.position",
+    )
+    .bind(&ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut credits: HashMap<i32, Vec<Credit>> = HashMap::new();
+    for row in rows {
+        credits.entry(row.get(0)).or_default().push(Credit {
+            id: row.get(1),
+            name: row.get(2),
+            role: AuthorRole::from_db(row.get(3))?,
+        });
+    }
+    let mut names: HashMap<i32, (Option<String>, Option<String>)> =
+        sqlx::query_as::<_, (i32, Option<String>, Option<String>)>(
+            "SELECT books.id, publishers.name, series.name
+             FROM books LEFT JOIN publishers ON publishers.id = books.publisher_id
+                        LEFT JOIN series ON series.id = books.series_id
+             WHERE books.id = ANY($1)
+               AND (books.publisher_id IS NOT NULL OR books.series_id IS NOT NULL)",
+        )
+        .bind(&ids)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(id, publisher, series)| (id, (publisher, series)))
+        .collect();
+    let rows = sqlx::query_as::<_, (i32, String)>(
+        "SELECT book_tags.book_id, tags.name
+         FROM book_tags JOIN tags ON tags.id = book_tags.tag_id
+         WHERE book_tags.book_id = ANY($1)
+         ORDER BY tags.name",
+    )
+    .bind(&ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+    for (id, tag) in rows {
+        tags.entry(id).or_default().push(tag);
+    }
+    for book in books {
+        book.authors = credits.remove(&book.id).unwrap_or_default();
+        (book.publisher, book.series) = names.remove(&book.id).unwrap_or_default();
+        book.tags = tags.remove(&book.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// The book as written by a change that leaves the details kept in other
+/// tables alone, with those details copied from `before`.
+fn with_details(after: Book, before: &Book) -> Book {
+    Book {
+        authors: before.authors.clone(),
+        publisher: before.publisher.clone(),
+        series: before.series.clone(),
+        tags: before.tags.clone(),
+        ..after
+    }
+}
+
+/// Add an entry to book_history, stamped with `changed_at` or, if that is
+/// `None`, the current time. One of `before` and `after` must be present;
+/// the book's ID is taken from it.
+async fn record(
+    conn: &mut PgConnection,
+    action: ChangeAction,
+    actor: &str,
+    changed_at: Option<DateTime<Utc>>,
+    before: Option<&Book>,
+    after: Option<&Book>,
+) -> Result<()> {
+    let id = after.or(before).expect("a change has a before or after").id;
+    sqlx::query(
+        "INSERT INTO book_history (book_id, action, actor, changed_at, before, after)
+         VALUES ($1, $2, $3, COALESCE($6, now()), $4, $5)",
+    )
+    .bind(id)
+    .bind(action.as_str())
+    .bind(actor)
+    .bind(before.map(Json))
+    .bind(after.map(Json))
+    .bind(changed_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Create an empty, migrated database on the server at `admin_url`,
+/// for a single test. Returns the repository and the database's name.
+#[cfg(test)]
+pub(super) async fn create_test_database(admin_url: &str) -> (PostgresRepository, String) {
+    use sqlx::{Connection, PgConnection};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let database = format!(
+        "books_test_{}_{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+    let mut admin = PgConnection::connect(admin_url).await.unwrap();
+    sqlx::query(&format!("CREATE DATABASE {database}"))
+        .execute(&mut admin)
+        .await
+        .unwrap();
+    let (server, _) = admin_url.rsplit_once('/').unwrap();
+    let config = DbConfig {
+        url: format!("{server}/{database}"),
+        ..DbConfig::default()
+    };
+    (
+        PostgresRepository::connect(&config).await.unwrap(),
+        database,
+    )
+}
+
+/// Remove a database made by `create_test_database`.
+#[cfg(test)]
+pub(super) async fn drop_test_database(admin_url: &str, database: &str) {
+    use sqlx::{Connection, PgConnection};
+
+    let mut admin = PgConnection::connect(admin_url).await.unwrap();
+    sqlx::query(&format!("DROP DATABASE {database} WITH (FORCE)"))
+        .execute(&mut admin)
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tsquery_is_sanitized() {
+        assert_eq!(ts_query_expression("Rust brain"), "rust:* & brain:*");
+        assert_eq!(
+            ts_query_expression("\"rust AND ( !x"),
+            "rust:* & and:* & x:*"
+        );
+        assert_eq!(ts_query_expression(" :*& "), "");
+    }
+}
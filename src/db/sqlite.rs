@@ -0,0 +1,1024 @@
+//! SQLite implementation of `BookRepository`.
+
+use super::filter::Timestamps;
+use super::{
+    author_error, credit_line, write_error, Author, AuthorRole, BatchOp, Book, BookChange,
+    BookPatch, BookQuery, BookRepository, BookUpdate, ChangeAction, ChangeRow, Credit, NewAuthor,
+    NewBook, NewCredit, Page, SearchHit, TagCount, MATCH_END, MATCH_START, MAX_FACETS,
+    MAX_PAGE_SIZE,
+};
+use crate::config::DbConfig;
+use crate::error::{Error, FieldError, Result};
+use crate::isbn;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::types::Json;
+use sqlx::{Connection, QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Books stored in SQLite. Migrations live in `migrations/sqlite`.
+pub struct SqliteRepository {
+    pool: SqlitePool,
+}
+
+impl SqliteRepository {
+    /// Create a connection pool with the given settings, creating the
+    /// database file if needed. Run any migrations.
+    ///
+    /// File databases use write-ahead logging, so readers don't block the
+    /// writer, with `synchronous=NORMAL` (safe in WAL mode) and foreign keys
+    /// enforced.
+    ///
+    /// ## Arguments
+    /// * `config` - the database location and pool settings.
+    pub async fn connect(config: &DbConfig) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(&config.url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(config.busy_timeout)
+            .foreign_keys(true);
+        // An in-memory database is dropped when its last connection closes,
+        // so always keep one open.
+        let min_connections = if config.is_in_memory() {
+            config.min_connections.max(1)
+        } else {
+            config.min_connections
+        };
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .min_connections(min_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .idle_timeout(config.idle_timeout)
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!("migrations/sqlite").run(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+/// The current time as stored in `created_at` and `updated_at`: RFC 3339
+/// in UTC, with milliseconds, so that timestamps sort and compare correctly
+/// as text.
+const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+
+/// Format a time the way `NOW` does, for comparing with stored timestamps.
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+impl Timestamps for Sqlite {
+    fn push_timestamp(sql: &mut QueryBuilder<'_, Self>, time: DateTime<Utc>) {
+        sql.push_bind(timestamp(time));
+    }
+}
+
+/// Turns free text typed by a user into an FTS5 match expression.
+/// Every word is quoted (so FTS5 operators and punctuation are treated
+/// as literal text) and prefix-matched, and all words must match.
+fn fts_match_expression(text: &str) -> String {
+    text.split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[async_trait]
+impl BookRepository for SqliteRepository {
+    async fn all_books(&self, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, false, None).await
+    }
+
+    async fn trash(&self, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, true, None).await
+    }
+
+    async fn books_after(&self, after: i32, limit: i64) -> Result<Vec<Book>> {
+        let mut tx = self.pool.begin().await?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books WHERE id > $1 AND deleted_at IS NULL ORDER BY id LIMIT $2",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(books)
+    }
+
+    async fn book_by_id(&self, id: i32) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let mut book =
+            sqlx::query_as::<_, Book>("SELECT * FROM books WHERE id=$1 AND deleted_at IS NULL")
+                .bind(id)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| Error::NotFound(format!("book {id} not found")))?;
+        attach_details(&mut tx, vec![&mut book]).await?;
+        tx.commit().await?;
+        Ok(book)
+    }
+
+    async fn book_by_isbn(&self, isbn13: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let mut book =
+            sqlx::query_as::<_, Book>("SELECT * FROM books WHERE isbn13=$1 AND deleted_at IS NULL")
+                .bind(isbn13)
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| Error::NotFound(format!("no book has ISBN {isbn13}")))?;
+        attach_details(&mut tx, vec![&mut book]).await?;
+        tx.commit().await?;
+        Ok(book)
+    }
+
+    async fn books_by_title_author(&self, title: &str, author: &str) -> Result<Vec<Book>> {
+        let mut tx = self.pool.begin().await?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books
+             WHERE lower(title)=lower($1) AND lower(author)=lower($2) AND deleted_at IS NULL
+             ORDER BY id",
+        )
+        .bind(title)
+        .bind(author)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(books)
+    }
+
+    async fn search_books(&self, text: &str, limit: i64) -> Result<Vec<SearchHit>> {
+        let expression = fts_match_expression(text);
+        if expression.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut tx = self.pool.begin().await?;
+        let mut hits = sqlx::query_as::<_, SearchHit>(
+            "SELECT books.*, bm25(books_fts) AS rank,
+                    highlight(books_fts, 0, $3, $4) AS title_highlight,
+                    highlight(books_fts, 1, $3, $4) AS author_highlight
+             FROM books_fts JOIN books ON books.id = books_fts.rowid
+             WHERE books_fts MATCH $1 AND books.deleted_at IS NULL
+             ORDER BY rank
+             LIMIT $2",
+        )
+        .bind(expression)
+        .bind(limit.clamp(1, MAX_PAGE_SIZE))
+        .bind(MATCH_START)
+        .bind(MATCH_END)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, hits.iter_mut().map(|hit| &mut hit.book).collect()).await?;
+        tx.commit().await?;
+        Ok(hits)
+    }
+
+    async fn book_history(&self, id: i32) -> Result<Vec<BookChange>> {
+        sqlx::query_as::<_, ChangeRow>(
+            "SELECT * FROM book_history WHERE book_id=$1 ORDER BY changed_at, id",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(ChangeRow::into_change)
+        .collect()
+    }
+
+    async fn book_as_of(&self, id: i32, as_of: DateTime<Utc>) -> Result<Book> {
+        let after: Option<Option<Json<Book>>> = sqlx::query_scalar(
+            "SELECT after FROM book_history WHERE book_id=$1 AND changed_at <= $2
+             ORDER BY changed_at DESC, id DESC LIMIT 1",
+        )
+        .bind(id)
+        .bind(timestamp(as_of))
+        .fetch_optional(&self.pool)
+        .await?;
+        after
+            .flatten()
+            .map(|book| book.0)
+            .filter(|book| book.deleted_at.is_none())
+            .ok_or_else(|| Error::NotFound(format!("book {id} did not exist at {as_of}")))
+    }
+
+    async fn add_book(&self, book: &NewBook, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let added = insert(&mut tx, book, actor).await?;
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    async fn update_book(&self, id: i32, update: &BookUpdate, actor: &str) -> Result<Book> {
+        self.patch_book(id, &update.into(), actor).await
+    }
+
+    async fn patch_book(&self, id: i32, patch: &BookPatch, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let patched = change(&mut tx, id, patch, actor).await?;
+        tx.commit().await?;
+        Ok(patched)
+    }
+
+    async fn delete_book(&self, id: i32, version: Option<i64>, actor: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        soft_delete(&mut tx, id, version, actor).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn restore_book(&self, id: i32, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        let (change, before) =
+            begin_change(&mut tx, id, ChangeAction::Restore, actor, true).await?;
+        let sql = format!(
+            "UPDATE books SET deleted_at=NULL,
+                              version=version+1, updated_at={NOW}, updated_by=$2
+             WHERE id=$1 RETURNING *"
+        );
+        let after = sqlx::query_as::<_, Book>(&sql)
+            .bind(id)
+            .bind(actor)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| write_error(e, before.isbn13.as_deref()))?;
+        let after = with_details(after, &before);
+        finish_change(&mut tx, change, &before, &after).await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn purge_books(&self, deleted_before: DateTime<Utc>, actor: &str) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        // The credits go with the books, so read them first.
+        lock(&mut tx).await?;
+        let mut purged = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books WHERE deleted_at IS NOT NULL AND deleted_at < $1",
+        )
+        .bind(timestamp(deleted_before))
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, purged.iter_mut().collect()).await?;
+        sqlx::query("DELETE FROM books WHERE deleted_at IS NOT NULL AND deleted_at < $1")
+            .bind(timestamp(deleted_before))
+            .execute(&mut *tx)
+            .await?;
+        for book in &purged {
+            record(&mut tx, ChangeAction::Purge, actor, None, Some(book), None).await?;
+        }
+        tx.commit().await?;
+        Ok(purged.len() as u64)
+    }
+
+    async fn authors(&self, limit: i64, offset: i64) -> Result<Page<Author>> {
+        let total: i64 = sqlx::query("SELECT COUNT(*) FROM authors")
+            .fetch_one(&self.pool)
+            .await?
+            .get(0);
+        let items = sqlx::query_as::<_, Author>(
+            "SELECT * FROM authors ORDER BY lower(name), id LIMIT $1 OFFSET $2",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Page { items, total })
+    }
+
+    async fn author_by_id(&self, id: i32) -> Result<Author> {
+        sqlx::query_as::<_, Author>("SELECT * FROM authors WHERE id=$1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("author {id} not found")))
+    }
+
+    async fn books_by_author(&self, id: i32, query: &BookQuery) -> Result<Page<Book>> {
+        self.page(query, false, Some(id)).await
+    }
+
+    async fn add_author(&self, author: &NewAuthor) -> Result<Author> {
+        sqlx::query_as::<_, Author>("INSERT INTO authors (name) VALUES ($1) RETURNING *")
+            .bind(&author.name)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| author_error(e, &author.name))
+    }
+
+    async fn rename_author(
+        &self,
+        id: i32,
+        author: &NewAuthor,
+        actor: &str,
+    ) -> Result<(Author, Vec<i32>)> {
+        let mut tx = self.pool.begin().await?;
+        lock(&mut tx).await?;
+        sqlx::query("SELECT id FROM authors WHERE id=$1")
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("author {id} not found")))?;
+        let mut books = sqlx::query_as::<_, Book>(
+            "SELECT * FROM books
+             WHERE id IN (SELECT book_id FROM book_authors WHERE author_id=$1) ORDER BY id",
+        )
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+        attach_details(&mut tx, books.iter_mut().collect()).await?;
+        let renamed =
+            sqlx::query_as::<_, Author>("UPDATE authors SET name=$1 WHERE id=$2 RETURNING *")
+                .bind(&author.name)
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| author_error(e, &author.name))?;
+
+        let mut changed = Vec::new();
+        for before in books {
+            let mut credits = before.authors.clone();
+            for credit in credits.iter_mut().filter(|c| c.id == id) {
+                credit.name = renamed.name.clone();
+            }
+            let line = credit_line(credits.iter().map(|c| (c.name.as_str(), c.role)));
+            if line == before.author {
+                continue;
+            }
+            let sql = format!(
+                "UPDATE books SET author=$1, version=version+1, updated_at={NOW}, updated_by=$2
+                 WHERE id=$3 RETURNING *"
+            );
+            let after = sqlx::query_as::<_, Book>(&sql)
+                .bind(&line)
+                .bind(actor)
+                .bind(before.id)
+                .fetch_one(&mut *tx)
+                .await?;
+            let after = Book {
+                authors: credits,
+                ..with_details(after, &before)
+            };
+            let changed_at = Some(after.updated_at);
+            record(
+                &mut tx,
+                ChangeAction::Update,
+                actor,
+                changed_at,
+                Some(&before),
+                Some(&after),
+            )
+            .await?;
+            changed.push(before.id);
+        }
+        tx.commit().await?;
+        Ok((renamed, changed))
+    }
+
+    async fn delete_author(&self, id: i32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM authors
+             WHERE id=$1 AND NOT EXISTS (SELECT 1 FROM book_authors WHERE author_id=$1)",
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            let credited: i64 =
+                sqlx::query("SELECT COUNT(DISTINCT book_id) FROM book_authors WHERE author_id=$1")
+                    .bind(id)
+                    .fetch_one(&mut *tx)
+                    .await?
+                    .get(0);
+            return Err(if credited > 0 {
+                Error::Conflict(format!(
+                    "author {id} is credited on {credited} books, counting any in the trash"
+                ))
+            } else {
+                Error::NotFound(format!("author {id} not found"))
+            });
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn tag_facets(&self, query: &BookQuery) -> Result<Vec<TagCount>> {
+        let mut sql = QueryBuilder::new(
+            "SELECT tags.name AS tag, COUNT(*) AS count
+             FROM book_tags JOIN tags ON tags.id = book_tags.tag_id
+             WHERE book_tags.book_id IN (SELECT id FROM books WHERE ",
+        );
+        push_conditions(&mut sql, query, false, None);
+        sql.push(") GROUP BY tags.name ORDER BY count DESC, tags.name LIMIT ")
+            .push_bind(MAX_FACETS);
+        Ok(sql.build_query_as().fetch_all(&self.pool).await?)
+    }
+
+    async fn tag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        lock(&mut tx).await?;
+        let before = read_book(&mut tx, id, false).await?;
+        if before.tags.iter().any(|t| t == tag) {
+            return Ok(before);
+        }
+        let (tag_id, _) = find_or_add(&mut tx, "tags", Some(tag))
+            .await?
+            .expect("a name was given");
+        sqlx::query("INSERT INTO book_tags (book_id, tag_id) VALUES ($1, $2)")
+            .bind(id)
+            .bind(tag_id)
+            .execute(&mut *tx)
+            .await?;
+        let after = finish_retag(&mut tx, &before, actor).await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn untag_book(&self, id: i32, tag: &str, actor: &str) -> Result<Book> {
+        let mut tx = self.pool.begin().await?;
+        lock(&mut tx).await?;
+        let before = read_book(&mut tx, id, false).await?;
+        if !before.tags.iter().any(|t| t == tag) {
+            return Err(Error::NotFound(format!("book {id} is not tagged {tag}")));
+        }
+        sqlx::query(
+            "DELETE FROM book_tags
+             WHERE book_id=$1 AND tag_id IN (SELECT id FROM tags WHERE lower(name)=lower($2))",
+        )
+        .bind(id)
+        .bind(tag)
+        .execute(&mut *tx)
+        .await?;
+        let after = finish_retag(&mut tx, &before, actor).await?;
+        tx.commit().await?;
+        Ok(after)
+    }
+
+    async fn batch(
+        &self,
+        ops: &[BatchOp],
+        actor: &str,
+        atomic: bool,
+    ) -> Result<Vec<Result<Option<Book>>>> {
+        let mut tx = self.pool.begin().await?;
+        let mut outcomes = Vec::with_capacity(ops.len());
+        for op in ops {
+            // Each operation gets a savepoint, so a failure can be undone
+            // without losing the operations before it.
+            let mut savepoint = tx.begin().await?;
+            let outcome = match op {
+                BatchOp::Create { book } => insert(&mut savepoint, book, actor).await.map(Some),
+                BatchOp::Update { id, book } => change(&mut savepoint, *id, &book.into(), actor)
+                    .await
+                    .map(Some),
+                BatchOp::Delete { id, version } => {
+                    soft_delete(&mut savepoint, *id, *version, actor)
+                        .await
+                        .map(|()| None)
+                }
+            };
+            let failed = outcome.is_err();
+            if failed {
+                savepoint.rollback().await?;
+            } else {
+                savepoint.commit().await?;
+            }
+            outcomes.push(outcome);
+            if failed && atomic {
+                tx.rollback().await?;
+                return Ok(outcomes);
+            }
+        }
+        tx.commit().await?;
+        Ok(outcomes)
+    }
+}
+
+impl SqliteRepository {
+    /// A page of either the live books or, if `in_trash`, the deleted ones,
+    /// optionally only those crediting `author`.
+    async fn page(
+        &self,
+        query: &BookQuery,
+        in_trash: bool,
+        author: Option<i32>,
+    ) -> Result<Page<Book>> {
+        let mut tx = self.pool.begin().await?;
+        let mut sql = QueryBuilder::new("SELECT COUNT(*) FROM books WHERE ");
+        push_conditions(&mut sql, query, in_trash, author);
+        let total: i64 = sql.build().fetch_one(&mut *tx).await?.get(0);
+        let mut sql = QueryBuilder::new("SELECT * FROM books WHERE ");
+        push_conditions(&mut sql, query, in_trash, author);
+        sql.push(" ORDER BY ")
+            .push(query.order_by())
+            .push(" LIMIT ")
+            .push_bind(query.limit)
+            .push(" OFFSET ")
+            .push_bind(query.offset);
+        let mut items = sql.build_query_as::<Book>().fetch_all(&mut *tx).await?;
+        attach_details(&mut tx, items.iter_mut().collect()).await?;
+        tx.commit().await?;
+        Ok(Page { items, total })
+    }
+}
+
+/// Add the conditions selecting a page of books (see `page`) to `sql`.
+fn push_conditions(
+    sql: &mut QueryBuilder<'_, Sqlite>,
+    query: &BookQuery,
+    in_trash: bool,
+    author: Option<i32>,
+) {
+    sql.push(if in_trash {
+        "deleted_at IS NOT NULL"
+    } else {
+        "deleted_at IS NULL"
+    });
+    if let Some(since) = query.updated_since {
+        sql.push(" AND updated_at >= ");
+        Sqlite::push_timestamp(sql, since);
+    }
+    if let Some(author) = author {
+        sql.push(" AND id IN (SELECT book_id FROM book_authors WHERE author_id=")
+            .push_bind(author)
+            .push(")");
+    }
+    query.filter.push_conditions(sql);
+}
+
+/// Insert a book, and record it in the history.
+async fn insert(conn: &mut SqliteConnection, book: &NewBook, actor: &str) -> Result<Book> {
+    let credits = resolve_credits(conn, &book.authors).await?;
+    let line = credit_line(credits.iter().map(|c| (c.name.as_str(), c.role)));
+    let publisher = find_or_add(conn, "publishers", book.publisher.as_deref()).await?;
+    let series = find_or_add(conn, "series", book.series.as_deref()).await?;
+    let sql = format!(
+        "INSERT INTO books (title, author, isbn13, isbn10,
+                            publisher_id, published_on, edition, page_count, language,
+                            series_id, series_volume,
+                            created_at, created_by, updated_at, updated_by)
+         VALUES ($1, $2, $4, $5, $6, $7, $8, $9, $10, $11, $12, {NOW}, $3, {NOW}, $3)
+         RETURNING *"
+    );
+    let added = sqlx::query_as::<_, Book>(&sql)
+        .bind(&book.title)
+        .bind(&line)
+        .bind(actor)
+        .bind(&book.isbn)
+        .bind(book.isbn.as_deref().and_then(isbn::to_isbn10))
+        .bind(publisher.as_ref().map(|(id, _)| *id))
+        .bind(book.published_on)
+        .bind(&book.edition)
+        .bind(book.page_count)
+        .bind(&book.language)
+        .bind(series.as_ref().map(|(id, _)| *id))
+        .bind(book.series_volume)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| write_error(e, book.isbn.as_deref()))?;
+    set_credits(conn, added.id, &credits).await?;
+    let added = Book {
+        authors: credits,
+        publisher: publisher.map(|(_, name)| name),
+        series: series.map(|(_, name)| name),
+        ..added
+    };
+    let changed_at = Some(added.created_at);
+    record(
+        conn,
+        ChangeAction::Insert,
+        actor,
+        changed_at,
+        None,
+        Some(&added),
+    )
+    .await?;
+    Ok(added)
+}
+
+/// Apply `patch` to a live book, and record it in the history.
+async fn change(
+    conn: &mut SqliteConnection,
+    id: i32,
+    patch: &BookPatch,
+    actor: &str,
+) -> Result<Book> {
+    let (change, before) = begin_change(conn, id, ChangeAction::Update, actor, false).await?;
+    let credits = match &patch.authors {
+        Some(authors) => Some(resolve_credits(conn, authors).await?),
+        None => None,
+    };
+    let line = credits
+        .as_ref()
+        .map(|credits| credit_line(credits.iter().map(|c| (c.name.as_str(), c.role))));
+    let publisher = match &patch.publisher {
+        Some(name) => Some(find_or_add(conn, "publishers", name.as_deref()).await?),
+        None => None,
+    };
+    let series = match &patch.series {
+        Some(name) => Some(find_or_add(conn, "series", name.as_deref()).await?),
+        None => None,
+    };
+    let sql = format!(
+        "UPDATE books SET title=COALESCE($1, title), author=COALESCE($2, author),
+                          isbn13=CASE WHEN $6 THEN $7 ELSE isbn13 END,
+                          isbn10=CASE WHEN $6 THEN $8 ELSE isbn10 END,
+                          publisher_id=CASE WHEN $9 THEN $10 ELSE publisher_id END,
+                          published_on=CASE WHEN $11 THEN $12 ELSE published_on END,
+                          edition=CASE WHEN $13 THEN $14 ELSE edition END,
+                          page_count=CASE WHEN $15 THEN $16 ELSE page_count END,
+                          language=CASE WHEN $17 THEN $18 ELSE language END,
+                          series_id=CASE WHEN $19 THEN $20 ELSE series_id END,
+                          series_volume=CASE WHEN $21 THEN $22 ELSE series_volume END,
+                          version=version+1, updated_at={NOW}, updated_by=$5
+         WHERE id=$3 AND ($4 IS NULL OR version=$4) RETURNING *"
+    );
+    let isbn = patch.isbn.clone().flatten();
+    let Some(after) = sqlx::query_as::<_, Book>(&sql)
+        .bind(&patch.title)
+        .bind(&line)
+        .bind(id)
+        .bind(patch.version)
+        .bind(actor)
+        .bind(patch.isbn.is_some())
+        .bind(&isbn)
+        .bind(isbn.as_deref().and_then(isbn::to_isbn10))
+        .bind(publisher.is_some())
+        .bind(
+            publisher
+                .as_ref()
+                .and_then(|p| p.as_ref().map(|(id, _)| *id)),
+        )
+        .bind(patch.published_on.is_some())
+        .bind(patch.published_on.flatten())
+        .bind(patch.edition.is_some())
+        .bind(patch.edition.clone().flatten())
+        .bind(patch.page_count.is_some())
+        .bind(patch.page_count.flatten())
+        .bind(patch.language.is_some())
+        .bind(patch.language.clone().flatten())
+        .bind(series.is_some())
+        .bind(series.as_ref().and_then(|s| s.as_ref().map(|(id, _)| *id)))
+        .bind(patch.series_volume.is_some())
+        .bind(patch.series_volume.flatten())
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| write_error(e, isbn.as_deref()))?
+    else {
+        return Err(Error::Stale(Box::new(before)));
+    };
+    let authors = match credits {
+        Some(credits) => {
+            set_credits(conn, id, &credits).await?;
+            credits
+        }
+        None => before.authors.clone(),
+    };
+    let after = Book {
+        authors,
+        publisher: publisher.map_or(before.publisher.clone(), |p| p.map(|(_, name)| name)),
+        series: series.map_or(before.series.clone(), |s| s.map(|(_, name)| name)),
+        ..with_details(after, &before)
+    };
+    finish_change(conn, change, &before, &after).await?;
+    Ok(after)
+}
+
+/// Move a live book to the trash, and record it in the history.
+async fn soft_delete(
+    conn: &mut SqliteConnection,
+    id: i32,
+    version: Option<i64>,
+    actor: &str,
+) -> Result<()> {
+    let (change, before) = begin_change(conn, id, ChangeAction::Delete, actor, false).await?;
+    let sql = format!(
+        "UPDATE books SET deleted_at={NOW},
+                          version=version+1, updated_at={NOW}, updated_by=$3
+         WHERE id=$1 AND ($2 IS NULL OR version=$2) RETURNING *"
+    );
+    let Some(after) = sqlx::query_as::<_, Book>(&sql)
+        .bind(id)
+        .bind(version)
+        .bind(actor)
+        .fetch_optional(&mut *conn)
+        .await?
+    else {
+        return Err(Error::Stale(Box::new(before)));
+    };
+    let after = with_details(after, &before);
+    finish_change(conn, change, &before, &after).await
+}
+
+/// Start changing book `id`, which must be live or, if `in_trash`,
+/// deleted. Returns the history entry to complete with `finish_change`,
+/// and the book as it is now.
+///
+/// The history entry is written before the book is read: a transaction
+/// that reads first fails outright, rather than waiting, if another
+/// writer commits before it takes the write lock.
+async fn begin_change(
+    conn: &mut SqliteConnection,
+    id: i32,
+    action: ChangeAction,
+    actor: &str,
+    in_trash: bool,
+) -> Result<(i64, Book)> {
+    let sql = format!(
+        "INSERT INTO book_history (book_id, action, actor, changed_at)
+         VALUES ($1, $2, $3, {NOW}) RETURNING id"
+    );
+    let change: i64 = sqlx::query_scalar(&sql)
+        .bind(id)
+        .bind(action.as_str())
+        .bind(actor)
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok((change, read_book(conn, id, in_trash).await?))
+}
+
+/// Book `id` as it is now. The book must be live or, if `in_trash`,
+/// deleted.
+async fn read_book(conn: &mut SqliteConnection, id: i32, in_trash: bool) -> Result<Book> {
+    let deleted = if in_trash { "IS NOT NULL" } else { "IS NULL" };
+    let mut book = sqlx::query_as::<_, Book>(&format!(
+        "SELECT * FROM books WHERE id=$1 AND deleted_at {deleted}"
+    ))
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("book {id} not found")))?;
+    attach_details(&mut *conn, vec![&mut book]).await?;
+    Ok(book)
+}
+
+/// Bump the version of a book whose tags have just changed, and record the
+/// change in the history.
+async fn finish_retag(conn: &mut SqliteConnection, before: &Book, actor: &str) -> Result<Book> {
+    let sql = format!(
+        "UPDATE books SET version=version+1, updated_at={NOW}, updated_by=$2
+         WHERE id=$1 RETURNING *"
+    );
+    let mut after = sqlx::query_as::<_, Book>(&sql)
+        .bind(before.id)
+        .bind(actor)
+        .fetch_one(&mut *conn)
+        .await?;
+    attach_details(&mut *conn, vec![&mut after]).await?;
+    let changed_at = Some(after.updated_at);
+    record(
+        conn,
+        ChangeAction::Update,
+        actor,
+        changed_at,
+        Some(before),
+        Some(&after),
+    )
+    .await?;
+    Ok(after)
+}
+
+/// Take the database's write lock, so that the transaction can read before
+/// it writes without failing if another writer commits first (see
+/// `begin_change`). Any write takes the lock, even one that changes nothing.
+async fn lock(conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query("UPDATE authors SET id=id WHERE 0")
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Look up or add the authors to credit, in order.
+async fn resolve_credits(
+    conn: &mut SqliteConnection,
+    credits: &[NewCredit],
+) -> Result<Vec<Credit>> {
+    let mut resolved = Vec::with_capacity(credits.len());
+    for credit in credits {
+        let author = match (credit.id, &credit.name) {
+            (Some(id), _) => sqlx::query_as::<_, Author>("SELECT * FROM authors WHERE id=$1")
+                .bind(id)
+                .fetch_optional(&mut *conn)
+                .await?
+                .ok_or_else(|| FieldError::new("authors", format!("there is no author {id}")))?,
+            (None, Some(name)) => {
+                let (id, name) = find_or_add(conn, "authors", Some(name))
+                    .await?
+                    .expect("a name was given");
+                Author { id, name }
+            }
+            (None, None) => unreachable!("validated credits have an ID or a name"),
+        };
+        resolved.push(Credit {
+            id: author.id,
+            name: author.name,
+            role: credit.role,
+        });
+    }
+    Ok(resolved)
+}
+
+/// The ID and name of the row in `table` (authors, publishers, series or
+/// tags) called `name`, ignoring case, adding one if there is none. `None`
+/// if there is no name.
+async fn find_or_add(
+    conn: &mut SqliteConnection,
+    table: &'static str,
+    name: Option<&str>,
+) -> Result<Option<(i32, String)>> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    sqlx::query(&format!(
+        "INSERT INTO {table} (name) VALUES ($1) ON CONFLICT DO NOTHING"
+    ))
+    .bind(name)
+    .execute(&mut *conn)
+    .await?;
+    let sql = format!("SELECT id, name FROM {table} WHERE lower(name)=lower($1)");
+    Ok(Some(
+        sqlx::query_as::<_, (i32, String)>(&sql)
+            .bind(name)
+            .fetch_one(&mut *conn)
+            .await?,
+    ))
+}
+
+/// Replace a book's credits.
+async fn set_credits(conn: &mut SqliteConnection, book_id: i32, credits: &[Credit]) -> Result<()> {
+    sqlx::query("DELETE FROM book_authors WHERE book_id=$1")
+        .bind(book_id)
+        .execute(&mut *conn)
+        .await?;
+    for (position, credit) in credits.iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO book_authors (book_id, author_id, position, role) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(book_id)
+        .bind(credit.id)
+        .bind(position as i64)
+        .bind(credit.role.as_str())
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Fill in the details of each book that are kept in other tables: its
+/// `authors`, `publisher`, `series` and `tags`.
+async fn attach_details(conn: &mut SqliteConnection, books: Vec<&mut Book>) -> Result<()> {
+    if books.is_empty() {
+        return Ok(());
+    }
+    let ids = serde_json::to_string(&books.iter().map(|book| book.id).collect::<Vec<_>>())
+        .expect("IDs serialize");
+    let rows = sqlx::query(
+        "SELECT book_authors.book_id, authors.id, authors.name, book_authors.role
+         FROM book_authors JOIN authors ON authors.id = book_authors.author_id
+         WHERE book_authors.book_id IN (SELECT value FROM json_each($1))
+         ORDER BY book_authors.book_id, book_authors.position",
+    )
+    .bind(&ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut credits: HashMap<i32, Vec<Credit>> = HashMap::new();
+    for row in rows {
+        credits.entry(row.get(0)).or_default().push(Credit {
+            id: row.get(1),
+            name: row.get(2),
+            role: AuthorRole::from_db(row.get(3))?,
+        });
+    }
+    let mut names: HashMap<i32, (Option<String>, Option<String>)> =
+        sqlx::query_as::<_, (i32, Option<String>, Option<String>)>(
+            "SELECT books.id, publishers.name, series.name
+             FROM books LEFT JOIN publishers ON publishers.id = books.publisher_id
+                        LEFT JOIN series ON series.id = books.series_id
+             WHERE books.id IN (SELECT value FROM json_each($1))
+               AND (books.publisher_id IS NOT NULL OR books.series_id IS NOT NULL)",
+        )
+        .bind(&ids)
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|(id, publisher, series)| (id, (publisher, series)))
+        .collect();
+    let rows = sqlx::query_as::<_, (i32, String)>(
+        "SELECT book_tags.book_id, tags.name
+         FROM book_tags JOIN tags ON tags.id = book_tags.tag_id
+         WHERE book_tags.book_id IN (SELECT value FROM json_each($1))
+         ORDER BY tags.name",
+    )
+    .bind(&ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+    for (id, tag) in rows {
+        tags.entry(id).or_default().push(tag);
+    }
+    for book in books {
+        book.authors = credits.remove(&book.id).unwrap_or_default();
+        (book.publisher, book.series) = names.remove(&book.id).unwrap_or_default();
+        book.tags = tags.remove(&book.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// The book as written by a change that leaves the details kept in other
+/// tables alone, with those details copied from `before`.
+fn with_details(after: Book, before: &Book) -> Book {
+    Book {
+        authors: before.authors.clone(),
+        publisher: before.publisher.clone(),
+        series: before.series.clone(),
+        tags: before.tags.clone(),
+        ..after
+    }
+}
+
+/// Fill in the history entry started by `begin_change`.
+async fn finish_change(
+    conn: &mut SqliteConnection,
+    change: i64,
+    before: &Book,
+    after: &Book,
+) -> Result<()> {
+    sqlx::query("UPDATE book_history SET changed_at=$1, before=$2, after=$3 WHERE id=$4")
+        .bind(timestamp(after.updated_at))
+        .bind(Json(before))
+        .bind(Json(after))
+        .bind(change)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Add an entry to book_history, stamped with `changed_at` or, if that is
+/// `None`, the current time. One of `before` and `after` must be present;
+/// the book's ID is taken from it.
+async fn record(
+    conn: &mut SqliteConnection,
+    action: ChangeAction,
+    actor: &str,
+    changed_at: Option<DateTime<Utc>>,
+    before: Option<&Book>,
+    after: Option<&Book>,
+) -> Result<()> {
+    let id = after.or(before).expect("a change has a before or after").id;
+    let sql = format!(
+        "INSERT INTO book_history (book_id, action, actor, changed_at, before, after)
+         VALUES ($1, $2, $3, COALESCE($6, {NOW}), $4, $5)"
+    );
+    sqlx::query(&sql)
+        .bind(id)
+        .bind(action.as_str())
+        .bind(actor)
+        .bind(before.map(Json))
+        .bind(after.map(Json))
+        .bind(changed_at.map(timestamp))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_database() {
+        let path = std::env::temp_dir().join(format!("books-{}.db", std::process::id()));
+        let config = DbConfig {
+            url: format!("sqlite://{}", path.display()),
+            ..DbConfig::default()
+        };
+        let repo = SqliteRepository::connect(&config).await.unwrap();
+        assert!(path.exists());
+        let journal_mode: String = sqlx::query("PRAGMA journal_mode")
+            .fetch_one(&repo.pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!("wal", journal_mode);
+        let foreign_keys: i32 = sqlx::query("PRAGMA foreign_keys")
+            .fetch_one(&repo.pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(1, foreign_keys);
+        repo.pool.close().await;
+
+        // Re-opening keeps the data, and doesn't re-run migrations
+        let repo = SqliteRepository::connect(&config).await.unwrap();
+        let book = repo.book_by_id(1).await.unwrap();
+        assert_eq!("Hands-on Rust", book.title);
+        repo.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! The error type shared by the database and REST layers.
+//!
+//! Database functions return `error::Result`, and because `Error`
+//! implements `IntoResponse` handlers can pass failures straight
+//! back to Axum with `?`. Each variant maps onto an HTTP status, and
+//! the body is an RFC 7807 "problem details" JSON document.
+
+use crate::db::Book;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Shorthand for results that fail with the domain `Error`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong when working with the books database.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The requested record does not exist.
+    #[error("{0}")]
+    NotFound(String),
+    /// The request clashes with the current state of the data.
+    #[error("{0}")]
+    Conflict(String),
+    /// The `version` in the request body is not the book's current one.
+    /// Carries the current book, so the client can merge and retry.
+    #[error("book {} has changed; the current version is {}", .0.id, .0.version)]
+    Stale(Box<Book>),
+    /// The `If-Match` header doesn't match the book's current `ETag`.
+    /// Carries the current book.
+    #[error("book {} has changed; the current version is {}", .0.id, .0.version)]
+    PreconditionFailed(Box<Book>),
+    /// The caller isn't allowed to do this.
+    #[error("{0}")]
+    Forbidden(String),
+    /// A write didn't say which version of the record it is changing.
+    #[error("{0}")]
+    PreconditionRequired(String),
+    /// The request can't be understood, such as a malformed query parameter.
+    #[error("{0}")]
+    BadRequest(String),
+    /// The request was well-formed, but one or more fields are not acceptable.
+    #[error("{}", FieldError::describe(.0))]
+    Validation(Vec<FieldError>),
+    /// The database failed in a way the caller can't do anything about.
+    #[error("database error: {0}")]
+    Database(sqlx::Error),
+}
+
+/// A problem with a single field of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct FieldError {
+    /// The name of the offending field
+    pub field: String,
+    /// What is wrong with it
+    pub message: String,
+}
+
+impl FieldError {
+    /// Describe a problem with `field`.
+    pub fn new(field: &str, message: impl ToString) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn describe(errors: &[FieldError]) -> String {
+        errors
+            .iter()
+            .map(|e| format!("{}: {}", e.field, e.message))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+impl From<FieldError> for Error {
+    fn from(err: FieldError) -> Self {
+        Error::Validation(vec![err])
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::NotFound("record not found".to_string()),
+            // The database's message names tables and indexes, which are
+            // no business of the client's.
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                tracing::warn!(error = %db_err, "unique violation");
+                Error::Conflict("the change clashes with an existing record".to_string())
+            }
+            err => Error::Database(err),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for Error {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        Error::Database(sqlx::Error::Migrate(Box::new(err)))
+    }
+}
+
+impl Error {
+    /// The HTTP status code this error is reported with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) | Error::Stale(_) => StatusCode::CONFLICT,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Error::PreconditionRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            Error::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A copy of this error, for handing one failure to several callers.
+    /// `sqlx::Error` can't be cloned, so a database error keeps only its
+    /// message.
+    pub fn duplicate(&self) -> Error {
+        match self {
+            Error::NotFound(msg) => Error::NotFound(msg.clone()),
+            Error::Conflict(msg) => Error::Conflict(msg.clone()),
+            Error::BadRequest(msg) => Error::BadRequest(msg.clone()),
+            Error::Stale(book) => Error::Stale(book.clone()),
+            Error::PreconditionFailed(book) => Error::PreconditionFailed(book.clone()),
+            Error::Forbidden(msg) => Error::Forbidden(msg.clone()),
+            Error::PreconditionRequired(msg) => Error::PreconditionRequired(msg.clone()),
+            Error::Validation(errors) => Error::Validation(errors.clone()),
+            Error::Database(err) => Error::Database(sqlx::Error::Protocol(err.to_string())),
+        }
+    }
+}
+
+/// An RFC 7807 problem details document.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Problem {
+    /// A URI identifying the problem type
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// A short, human-readable summary of the problem type
+    pub title: String,
+    /// The HTTP status code
+    pub status: u16,
+    /// A human-readable explanation of this occurrence of the problem
+    pub detail: String,
+    /// Field-level details, for validation failures
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// The record as it is now, for version conflicts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<Book>,
+}
+
+impl Error {
+    /// Describe this error as a problem details document.
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+        let errors = match self {
+            Error::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+        let current = match self {
+            Error::Stale(book) | Error::PreconditionFailed(book) => Some((**book).clone()),
+            _ => None,
+        };
+        let detail = match self {
+            // Don't leak database internals to the client.
+            Error::Database(_) => "an internal error occurred".to_string(),
+            err => err.to_string(),
+        };
+        Problem {
+            problem_type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            errors,
+            current,
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        // The client only learns that something went wrong, so keep the
+        // details for whoever runs the server.
+        if let Error::Database(err) = &self {
+            tracing::error!(error = %err, "database error");
+        }
+        (
+            self.status(),
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            Json(self.problem()),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_mapping() {
+        assert_eq!(
+            Error::from(sqlx::Error::RowNotFound).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            Error::Conflict("dup".to_string()).status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            Error::BadRequest("bad filter".to_string()).status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            Error::from(FieldError::new("title", "bad")).status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            Error::from(sqlx::Error::PoolTimedOut).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn unique_violations_hide_the_schema() {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE secret_table (secret_column TEXT UNIQUE)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let insert = "INSERT INTO secret_table VALUES ('x')";
+        sqlx::query(insert).execute(&pool).await.unwrap();
+        let err = Error::from(sqlx::query(insert).execute(&pool).await.unwrap_err());
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+        let detail = err.problem().detail;
+        assert!(!detail.contains("secret"), "{detail}");
+    }
+}
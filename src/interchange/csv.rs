@@ -0,0 +1,382 @@
+//! CSV export and import, for catalogs kept in spreadsheets.
+//!
+//! Exports have one row per book, with the columns in `EXPORT_COLUMNS`.
+//! Imports only need a title and an author column, found by name, and read
+//! ISBNs from an `isbn` column if there is one. The edition details are
+//! read from columns named as in an export (`publisher`, `published_on`,
+//! `edition`, `page_count`, `language`, `series` and `series_volume`) if
+//! they are there; any other columns are ignored, so an export can be
+//! imported as it is.
+//!
+//! Spreadsheets run cells that start with `=`, `+`, `-` or `@` as
+//! formulas, so exported text that starts with one of those gets a leading
+//! `'`, which spreadsheets hide and imports remove.
+
+use super::{ExportFormat, Record};
+use crate::db::{Book, NewBook};
+use crate::error::{Error, FieldError, Result};
+use chrono::SecondsFormat;
+use std::str::FromStr;
+
+/// The columns of an export, in order.
+pub const EXPORT_COLUMNS: [&str; 16] = [
+    "id",
+    "title",
+    "author",
+    "isbn",
+    "publisher",
+    "published_on",
+    "edition",
+    "page_count",
+    "language",
+    "series",
+    "series_volume",
+    "version",
+    "created_at",
+    "created_by",
+    "updated_at",
+    "updated_by",
+];
+
+/// Characters that make a spreadsheet treat a cell as a formula.
+const FORMULA_PREFIXES: [char; 4] = ['=', '+', '-', '@'];
+
+/// The CSV export format: a header row, then one row per book.
+pub struct CsvFormat;
+
+impl ExportFormat for CsvFormat {
+    fn start(&self) -> Vec<u8> {
+        let mut writer = ::csv::Writer::from_writer(Vec::new());
+        writer
+            .write_record(EXPORT_COLUMNS)
+            .expect("writing to memory can't fail");
+        writer.into_inner().expect("writing to memory can't fail")
+    }
+
+    fn books(&self, books: &[Book]) -> Vec<u8> {
+        let mut writer = ::csv::Writer::from_writer(Vec::new());
+        for book in books {
+            writer
+                .write_record([
+                    book.id.to_string(),
+                    escape_formula(&book.title),
+                    escape_formula(&book.author),
+                    book.isbn13.clone().unwrap_or_default(),
+                    escape_formula(book.publisher.as_deref().unwrap_or_default()),
+                    book.published_on
+                        .map(|date| date.to_string())
+                        .unwrap_or_default(),
+                    escape_formula(book.edition.as_deref().unwrap_or_default()),
+                    book.page_count.map(|n| n.to_string()).unwrap_or_default(),
+                    book.language.clone().unwrap_or_default(),
+                    escape_formula(book.series.as_deref().unwrap_or_default()),
+                    book.series_volume
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                    book.version.to_string(),
+                    book.created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    escape_formula(&book.created_by),
+                    book.updated_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    escape_formula(&book.updated_by),
+                ])
+                .expect("writing to memory can't fail");
+        }
+        writer.into_inner().expect("writing to memory can't fail")
+    }
+}
+
+fn escape_formula(text: &str) -> String {
+    if text.starts_with(FORMULA_PREFIXES) {
+        format!("'{text}")
+    } else {
+        text.to_string()
+    }
+}
+
+fn unescape_formula(text: &str) -> &str {
+    match text.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_PREFIXES) => rest,
+        _ => text,
+    }
+}
+
+/// The names of the columns holding each field of an imported book.
+/// Names are matched ignoring case and surrounding spaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns {
+    /// The title column, `title` by default
+    pub title: String,
+    /// The author column, `author` by default
+    pub author: String,
+    /// The ISBN column. If `None`, ISBNs are read from the `isbn` column
+    /// if there is one; if named, the column must be there.
+    pub isbn: Option<String>,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self {
+            title: "title".to_string(),
+            author: "author".to_string(),
+            isbn: None,
+        }
+    }
+}
+
+/// Read the books from a CSV file whose first row names the columns.
+///
+/// ## Arguments
+/// * `data` - the file, in UTF-8, with or without a byte order mark
+/// * `columns` - which columns hold the title, author and ISBN
+///
+/// ## Returns
+/// * A record for every row after the header, numbered as a spreadsheet
+///   would (the header is row 1), or `Error::Validation` if the header
+///   can't be read or is missing a column.
+pub fn read(data: &[u8], columns: &Columns) -> Result<Vec<Record>> {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let mut reader = ::csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(::csv::Trim::All)
+        .from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|e| FieldError::new("file", e))?
+        .clone();
+    let find = |field: &str, name: &str| {
+        headers
+            .iter()
+            .position(|header| header.eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| FieldError::new(field, format!("there is no {name:?} column")))
+    };
+    let isbn = match &columns.isbn {
+        Some(name) => find("isbn_column", name).map(Some),
+        None => Ok(find("isbn_column", "isbn").ok()),
+    };
+    let (title, author, isbn) = match (
+        find("title_column", &columns.title),
+        find("author_column", &columns.author),
+        isbn,
+    ) {
+        (Ok(title), Ok(author), Ok(isbn)) => (title, author, isbn),
+        (title, author, isbn) => {
+            return Err(Error::Validation(
+                [title.err(), author.err(), isbn.err()]
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+            ))
+        }
+    };
+
+    let optional = |name: &str| find(name, name).ok();
+    let details = Details {
+        publisher: optional("publisher"),
+        published_on: optional("published_on"),
+        edition: optional("edition"),
+        page_count: optional("page_count"),
+        language: optional("language"),
+        series: optional("series"),
+        series_volume: optional("series_volume"),
+    };
+
+    Ok(reader
+        .records()
+        .enumerate()
+        .map(|(index, record)| Record {
+            row: index as u64 + 2,
+            book: record
+                .map_err(|e| FieldError::new("row", e).into())
+                .and_then(|record| {
+                    let text = |column: Option<usize>| {
+                        column
+                            .and_then(|column| record.get(column))
+                            .filter(|value| !value.is_empty())
+                    };
+                    let mut errors = Vec::new();
+                    let book = NewBook {
+                        title: unescape_formula(text(Some(title)).unwrap_or_default()).to_string(),
+                        author: unescape_formula(text(Some(author)).unwrap_or_default())
+                            .to_string(),
+                        isbn: text(isbn).map(str::to_string),
+                        publisher: text(details.publisher).map(|v| unescape_formula(v).to_string()),
+                        published_on: parse(
+                            "published_on",
+                            text(details.published_on),
+                            "must be a date, such as 2021-03-15",
+                            &mut errors,
+                        ),
+                        edition: text(details.edition).map(|v| unescape_formula(v).to_string()),
+                        page_count: parse(
+                            "page_count",
+                            text(details.page_count),
+                            "must be a whole number",
+                            &mut errors,
+                        ),
+                        language: text(details.language).map(str::to_string),
+                        series: text(details.series).map(|v| unescape_formula(v).to_string()),
+                        series_volume: parse(
+                            "series_volume",
+                            text(details.series_volume),
+                            "must be a whole number",
+                            &mut errors,
+                        ),
+                        ..NewBook::default()
+                    };
+                    if errors.is_empty() {
+                        Ok(book)
+                    } else {
+                        Err(Error::Validation(errors))
+                    }
+                }),
+        })
+        .collect())
+}
+
+/// Where the optional edition details are in an import, if they are.
+struct Details {
+    publisher: Option<usize>,
+    published_on: Option<usize>,
+    edition: Option<usize>,
+    page_count: Option<usize>,
+    language: Option<usize>,
+    series: Option<usize>,
+    series_volume: Option<usize>,
+}
+
+/// Parse an optional cell, noting a `field` error if it can't be parsed.
+fn parse<T: FromStr>(
+    field: &str,
+    value: Option<&str>,
+    message: &str,
+    errors: &mut Vec<FieldError>,
+) -> Option<T> {
+    let value = value?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            errors.push(FieldError::new(field, message));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::{AuthorRole, Credit};
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    fn books(records: Vec<Record>) -> Vec<(u64, String, String, Option<String>)> {
+        records
+            .into_iter()
+            .map(|record| {
+                let book = record.book.unwrap();
+                (record.row, book.title, book.author, book.isbn)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_mapped_columns() {
+        let data = "\u{feff}Shelf,Book Title, Written By,ISBN\n\
+                    A1,Hands-on Rust,\"Wolverson, Herbert\",978-1-68050-816-1\n\
+                    A2,\"Multi\nline\",Someone\n";
+        let columns = Columns {
+            title: "book title".to_string(),
+            author: "written by".to_string(),
+            isbn: None,
+        };
+        assert_eq!(
+            books(read(data.as_bytes(), &columns).unwrap()),
+            vec![
+                (
+                    2,
+                    "Hands-on Rust".to_string(),
+                    "Wolverson, Herbert".to_string(),
+                    Some("978-1-68050-816-1".to_string())
+                ),
+                (3, "Multi\nline".to_string(), "Someone".to_string(), None),
+            ]
+        );
+
+        let columns = Columns {
+            isbn: Some("ean".to_string()),
+            ..Columns::default()
+        };
+        let Err(Error::Validation(errors)) = read(data.as_bytes(), &columns) else {
+            panic!("expected missing columns");
+        };
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let at = Utc.with_ymd_and_hms(2023, 10, 20, 9, 30, 15).unwrap();
+        let book = Book {
+            id: 1,
+            title: "Hands-on Rust".to_string(),
+            author: "Wolverson, Herbert".to_string(),
+            authors: vec![Credit {
+                id: 1,
+                name: "Wolverson, Herbert".to_string(),
+                role: AuthorRole::Author,
+            }],
+            version: 1,
+            created_at: at,
+            created_by: "test".to_string(),
+            updated_at: at,
+            updated_by: "test".to_string(),
+            deleted_at: None,
+            isbn13: Some("9781680508161".to_string()),
+            isbn10: Some("1680508164".to_string()),
+            publisher: Some("The Pragmatic Bookshelf".to_string()),
+            published_on: NaiveDate::from_ymd_opt(2021, 7, 13),
+            edition: Some("1st".to_string()),
+            page_count: Some(342),
+            language: Some("en".to_string()),
+            series: Some("=Pragmatic Express".to_string()),
+            series_volume: Some(3),
+            tags: Vec::new(),
+        };
+        let data = [
+            CsvFormat.start(),
+            CsvFormat.books(std::slice::from_ref(&book)),
+        ]
+        .concat();
+        let records = read(&data, &Columns::default()).unwrap();
+        assert_eq!(
+            records[0].book.as_ref().unwrap(),
+            &NewBook {
+                title: book.title,
+                author: book.author,
+                isbn: book.isbn13,
+                publisher: book.publisher,
+                published_on: book.published_on,
+                edition: book.edition,
+                page_count: book.page_count,
+                language: book.language,
+                series: book.series,
+                series_volume: book.series_volume,
+                ..NewBook::default()
+            }
+        );
+
+        let data = "title,author,published_on,page_count,series_volume\n\
+                    Hands-on Rust,\"Wolverson, Herbert\",July 2021,lots,\n";
+        let records = read(data.as_bytes(), &Columns::default()).unwrap();
+        let Err(Error::Validation(errors)) = &records[0].book else {
+            panic!("expected unreadable cells");
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["published_on", "page_count"]);
+    }
+
+    #[test]
+    fn formulas_round_trip() {
+        assert_eq!(escape_formula("=SUM(A1)"), "'=SUM(A1)");
+        assert_eq!(escape_formula("Plain"), "Plain");
+        assert_eq!(unescape_formula("'=SUM(A1)"), "=SUM(A1)");
+        assert_eq!(unescape_formula("'Tis Pity"), "'Tis Pity");
+    }
+}
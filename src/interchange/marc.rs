@@ -0,0 +1,1216 @@
+//! MARC 21 bibliographic records, for exchanging the catalog with library
+//! systems, in both the binary transmission format (ISO 2709) and MARCXML.
+//!
+//! A book maps to these fields:
+//!
+//! | Field | Book                                                    |
+//! |-------|---------------------------------------------------------|
+//! | 001   | `id` (exports only)                                     |
+//! | 005   | `updated_at` (exports only)                             |
+//! | 008   | the year of `published_on` in 07-10 (exports only), and |
+//! |       | `language` in 35-37 if there is no 041                  |
+//! | 020   | `isbn13` and `isbn10`, from `$a`; the first valid one   |
+//! |       | is imported                                             |
+//! | 041   | `language`, from `$a`                                   |
+//! | 100   | the first of `authors`: the name in `$a`, role in `$e`  |
+//! | 700   | the rest of `authors`, likewise                         |
+//! | 245   | `title`, from `$a`, with any subtitle in `$b`           |
+//! | 250   | `edition`, from `$a`                                    |
+//! | 264   | `publisher` in `$b` and `published_on` in `$c`, as      |
+//! |       | `2021-07-13` or just a year, read as 1 January          |
+//! | 300   | `page_count`, from the number of pages in `$a`          |
+//! | 490   | `series` in `$a` and `series_volume` in `$v`            |
+//! | 830   | likewise, and read in preference to 490                 |
+//!
+//! Exported languages are written as stored: two-letter ISO 639-1 codes go
+//! in 041 with their source named in `$2`, and only three-letter codes,
+//! which are also MARC language codes, go in 008.
+//!
+//! Exports drop control characters, which would otherwise be read as the
+//! binary format's delimiters and aren't allowed in XML.
+//!
+//! Imports strip the ISBD punctuation catalogers end subfields with, such
+//! as the ` /` before a statement of responsibility, and ignore any other
+//! fields, so records from other catalogs can be imported as they are.
+//! Corporate names (110 and 710), such as "United States. Congress", are
+//! among those ignored: authors are people, named "Surname, Forename".
+//! Binary records must be in UTF-8 (leader position 9 is `a`); MARC-8
+//! records only import cleanly if they are plain ASCII.
+
+use super::{ExportFormat, Record};
+use crate::db::{credit_line, AuthorRole, Book, NewBook, NewCredit};
+use crate::error::{Error, FieldError, Result};
+use crate::isbn;
+use chrono::{Datelike, NaiveDate};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+/// The MARCXML namespace.
+pub const MARCXML_NAMESPACE: &str = "http://www.loc.gov/MARC21/slim";
+
+/// Ends each record in the binary format.
+const RECORD_TERMINATOR: u8 = 0x1D;
+/// Ends the directory and each field in the binary format.
+const FIELD_TERMINATOR: u8 = 0x1E;
+/// Starts each subfield in the binary format.
+const SUBFIELD_DELIMITER: u8 = 0x1F;
+
+/// The leader of an exported record, before the lengths are filled in: a
+/// new (`n`) record for a book (`am`), in UTF-8 (`a`), with ISBD
+/// punctuation (`i`).
+const LEADER: &str = "00000nam a2200000 i 4500";
+
+/// Punctuation that catalogers end a subfield with to introduce the next.
+const ISBD_SEPARATORS: [&str; 5] = [" /", " :", " ;", " =", ","];
+
+/// A variable field: a control field (tags `001` to `009`), which only
+/// has a value, or a data field, which has indicators and subfields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Control {
+        tag: String,
+        value: String,
+    },
+    Data {
+        tag: String,
+        indicators: [char; 2],
+        subfields: Vec<(char, String)>,
+    },
+}
+
+/// A MARC 21 record, as either format reads or writes it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct MarcRecord {
+    leader: String,
+    fields: Vec<Field>,
+}
+
+impl MarcRecord {
+    fn from_book(book: &Book) -> Self {
+        let updated = book.updated_at;
+        let credits: Vec<(&str, AuthorRole)> = if book.authors.is_empty() {
+            vec![(book.author.as_str(), AuthorRole::Author)]
+        } else {
+            book.authors
+                .iter()
+                .map(|credit| (credit.name.as_str(), credit.role))
+                .collect()
+        };
+        let credits: Vec<Field> = credits
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, role))| {
+                let mut subfields = vec![('a', name.to_string())];
+                if role != AuthorRole::Author {
+                    subfields.push(('e', role.as_str().to_string()));
+                }
+                data(if i == 0 { "100" } else { "700" }, ['1', ' '], subfields)
+            })
+            .collect();
+        let (main, added) = credits.split_at(credits.len().min(1));
+        let isbns = [&book.isbn13, &book.isbn10]
+            .into_iter()
+            .flatten()
+            .map(|isbn| data("020", [' ', ' '], vec![('a', isbn.clone())]));
+        let language = book.language.as_ref().map(|code| match code.len() {
+            2 => data(
+                "041",
+                [' ', '7'],
+                vec![('a', code.clone()), ('2', "iso639-1".to_string())],
+            ),
+            _ => data("041", [' ', ' '], vec![('a', code.clone())]),
+        });
+        let edition = book
+            .edition
+            .as_ref()
+            .map(|edition| data("250", [' ', ' '], vec![('a', edition.clone())]));
+        let publication = [
+            book.publisher.as_ref().map(|name| ('b', name.clone())),
+            book.published_on.map(|date| ('c', date.to_string())),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        let publication = (!publication.is_empty()).then(|| data("264", [' ', '1'], publication));
+        let pages = book
+            .page_count
+            .map(|pages| data("300", [' ', ' '], vec![('a', format!("{pages} pages"))]));
+        let series = book.series.as_ref().map(|series| {
+            let mut subfields = vec![('a', series.clone())];
+            if let Some(volume) = book.series_volume {
+                subfields.push(('v', volume.to_string()));
+            }
+            subfields
+        });
+        Self {
+            leader: LEADER.to_string(),
+            fields: [
+                Field::Control {
+                    tag: "001".to_string(),
+                    value: book.id.to_string(),
+                },
+                Field::Control {
+                    tag: "005".to_string(),
+                    value: format!(
+                        "{}.{}",
+                        updated.format("%Y%m%d%H%M%S"),
+                        updated.timestamp_subsec_millis() / 100
+                    ),
+                },
+                Field::Control {
+                    tag: "008".to_string(),
+                    value: fixed_data(book),
+                },
+            ]
+            .into_iter()
+            .chain(isbns)
+            .chain(language)
+            .chain(main.iter().cloned())
+            .chain([data("245", ['1', '0'], vec![('a', book.title.clone())])])
+            .chain(edition)
+            .chain(publication)
+            .chain(pages)
+            .chain(
+                series
+                    .clone()
+                    .map(|subfields| data("490", ['1', ' '], subfields)),
+            )
+            .chain(added.iter().cloned())
+            .chain(series.map(|subfields| data("830", [' ', '0'], subfields)))
+            .collect(),
+        }
+        .without_controls()
+    }
+
+    /// The record with any control characters removed from its values.
+    fn without_controls(mut self) -> Self {
+        let clean = |value: &mut String| value.retain(|c| !c.is_control());
+        for field in &mut self.fields {
+            match field {
+                Field::Control { value, .. } => clean(value),
+                Field::Data { subfields, .. } => {
+                    subfields.iter_mut().for_each(|(_, value)| clean(value))
+                }
+            }
+        }
+        self
+    }
+
+    /// Every `code` subfield of every `tag` field, in order.
+    fn subfields<'a>(&'a self, tag: &str, code: char) -> impl Iterator<Item = &'a str> {
+        let tag = tag.to_string();
+        self.fields.iter().flat_map(move |field| match field {
+            Field::Data {
+                tag: t, subfields, ..
+            } if *t == tag => subfields
+                .iter()
+                .filter(|(c, _)| *c == code)
+                .map(|(_, value)| value.as_str())
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    /// The first `code` subfield of the first `tag` field that has one.
+    fn subfield(&self, tag: &str, code: char) -> Option<&str> {
+        self.subfields(tag, code).next()
+    }
+
+    /// The first valid ISBN in the 020 fields or, if none is valid, the
+    /// first ISBN, so that the import reports what is wrong with it.
+    /// Catalogers often follow the ISBN with a qualifier, as in
+    /// `9781680508161 (paperback)`, which is dropped.
+    fn isbn(&self) -> Option<String> {
+        let isbns: Vec<&str> = self
+            .subfields("020", 'a')
+            .filter_map(|isbn| isbn.split_whitespace().next())
+            .collect();
+        isbns
+            .iter()
+            .find(|isbn| isbn::parse(isbn).is_ok())
+            .or(isbns.first())
+            .map(|isbn| isbn.to_string())
+    }
+
+    /// The people named in the main (100) and added (700) entries, with
+    /// the role from each one's relator term (`$e`). Terms that aren't a
+    /// known role, such as `author.`, credit an author.
+    fn credits(&self) -> Vec<NewCredit> {
+        self.fields
+            .iter()
+            .filter_map(|field| match field {
+                Field::Data { tag, subfields, .. } if tag == "100" || tag == "700" => {
+                    let value = |code: char| {
+                        subfields
+                            .iter()
+                            .find(|(c, _)| *c == code)
+                            .map(|(_, value)| strip_isbd(value))
+                    };
+                    let name = value('a').filter(|name| !name.is_empty())?;
+                    Some(NewCredit {
+                        id: None,
+                        name: Some(name.to_string()),
+                        role: value('e')
+                            .and_then(AuthorRole::from_name)
+                            .unwrap_or_default(),
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A subfield of the publication statement: the 264 field for the
+    /// publication itself (second indicator `1`), or the first 264 if none
+    /// says so.
+    fn publication(&self, code: char) -> Option<&str> {
+        let statements: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|field| match field {
+                Field::Data {
+                    tag,
+                    indicators,
+                    subfields,
+                } if tag == "264" => Some((indicators, subfields)),
+                _ => None,
+            })
+            .collect();
+        let (_, subfields) = statements
+            .iter()
+            .find(|(indicators, _)| indicators[1] == '1')
+            .or(statements.first())?;
+        subfields
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, value)| strip_isbd(value))
+            .filter(|value| !value.is_empty())
+    }
+
+    /// The language from 041 `$a` or, failing that, 008 positions 35-37.
+    /// Older records run several codes together in one 041 `$a`, as in
+    /// `engfre`; only the first is read.
+    fn language(&self) -> Option<String> {
+        let coded = self.subfield("041", 'a').map(|code| {
+            let code = strip_isbd(code);
+            match code.get(..3) {
+                Some(first) if code.len() > 3 && code.len().is_multiple_of(3) => first,
+                _ => code,
+            }
+        });
+        let fixed = self.fields.iter().find_map(|field| match field {
+            Field::Control { tag, value } if tag == "008" => value
+                .get(35..38)
+                .filter(|code| code.chars().all(|c| c.is_ascii_alphabetic())),
+            _ => None,
+        });
+        coded.or(fixed).map(str::to_string)
+    }
+
+    /// A subfield of the series: from the added entry (830) if there is
+    /// one, since that has the series' authorized name, or else from the
+    /// series statement (490).
+    fn series(&self, code: char) -> Option<&str> {
+        ["830", "490"]
+            .into_iter()
+            .find(|tag| self.subfield(tag, 'a').is_some())
+            .and_then(|tag| self.subfield(tag, code))
+            .map(strip_isbd)
+            .filter(|value| !value.is_empty())
+    }
+
+    fn to_book(&self) -> Result<NewBook> {
+        let title = self.subfield("245", 'a').map(|title| {
+            let title = strip_isbd(title);
+            match self.subfield("245", 'b').map(strip_isbd) {
+                Some(rest) if !rest.is_empty() => format!("{title}: {rest}"),
+                _ => title.to_string(),
+            }
+        });
+        let authors = self.credits();
+        match (title, authors.is_empty()) {
+            (Some(title), false) => Ok(NewBook {
+                title,
+                author: credit_line(
+                    authors
+                        .iter()
+                        .map(|credit| (credit.name.as_deref().unwrap_or_default(), credit.role)),
+                ),
+                authors,
+                isbn: self.isbn(),
+                publisher: self.publication('b').map(str::to_string),
+                published_on: self.publication('c').and_then(parse_date),
+                edition: self
+                    .subfield("250", 'a')
+                    .map(|v| strip_isbd(v).trim_end_matches('.').to_string()),
+                page_count: self.subfield("300", 'a').and_then(parse_pages),
+                language: self.language(),
+                series: self.series('a').map(str::to_string),
+                series_volume: self.series('v').and_then(parse_number),
+            }),
+            (title, no_authors) => Err(Error::Validation(
+                [
+                    title
+                        .is_none()
+                        .then(|| FieldError::new("245", "there is no title ($a)")),
+                    no_authors.then(|| FieldError::new("100", "there is no author ($a)")),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            )),
+        }
+    }
+}
+
+/// Build a data field.
+fn data(tag: &str, indicators: [char; 2], subfields: Vec<(char, String)>) -> Field {
+    Field::Data {
+        tag: tag.to_string(),
+        indicators,
+        subfields,
+    }
+}
+
+/// The 40 characters of an exported 008 field. Only the date entered, the
+/// year of publication and a three-letter language are filled in; the
+/// other positions are `|`, for "not coded", or blank.
+fn fixed_data(book: &Book) -> String {
+    let dates = match book.published_on {
+        Some(date) => format!("s{:04}    ", date.year()),
+        None => "nuuuuuuuu".to_string(),
+    };
+    let language = book
+        .language
+        .as_deref()
+        .filter(|code| code.len() == 3)
+        .unwrap_or("|||");
+    format!(
+        "{}{dates}xx {}{language} d",
+        book.created_at.format("%y%m%d"),
+        "|".repeat(17)
+    )
+}
+
+/// Read a publication date written as `2021-07-13`, or only a year, as in
+/// `2021.`, `[2021]` or `©2021`, which is read as 1 January.
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    let text = text.trim_end_matches('.');
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .or_else(|| {
+            let digits: String = text
+                .chars()
+                .skip_while(|c| !c.is_ascii_digit())
+                .take_while(char::is_ascii_digit)
+                .collect();
+            (digits.len() == 4)
+                .then(|| NaiveDate::from_ymd_opt(digits.parse().ok()?, 1, 1))
+                .flatten()
+        })
+}
+
+/// Read the number of pages from a physical description such as
+/// `xvi, 342 pages :` or `342 p.`: the number before the first word
+/// starting with `p`.
+fn parse_pages(text: &str) -> Option<i32> {
+    let words: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ',')
+        .filter(|word| !word.is_empty())
+        .collect();
+    words
+        .windows(2)
+        .find(|pair| pair[1].starts_with('p'))
+        .and_then(|pair| pair[0].parse().ok())
+}
+
+/// Read the first number in text such as `3`, `v. 3` or `book 3.`.
+fn parse_number(text: &str) -> Option<i32> {
+    let digits: String = text
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+fn strip_isbd(text: &str) -> &str {
+    let text = text.trim();
+    ISBD_SEPARATORS
+        .iter()
+        .find_map(|separator| text.strip_suffix(separator))
+        .unwrap_or(text)
+        .trim_end()
+}
+
+/// The binary MARC 21 export format, as served with `application/marc`.
+pub struct Marc21Format;
+
+impl ExportFormat for Marc21Format {
+    fn books(&self, books: &[Book]) -> Vec<u8> {
+        books
+            .iter()
+            .flat_map(|book| encode(&MarcRecord::from_book(book)))
+            .collect()
+    }
+}
+
+/// Write a record in the binary format, filling in the lengths and
+/// addresses in its leader and directory.
+fn encode(record: &MarcRecord) -> Vec<u8> {
+    let mut directory = Vec::new();
+    let mut data = Vec::new();
+    for field in &record.fields {
+        let start = data.len();
+        let tag = match field {
+            Field::Control { tag, value } => {
+                data.extend_from_slice(value.as_bytes());
+                tag
+            }
+            Field::Data {
+                tag,
+                indicators,
+                subfields,
+            } => {
+                data.extend(indicators.iter().map(|&c| c as u8));
+                for (code, value) in subfields {
+                    data.push(SUBFIELD_DELIMITER);
+                    data.push(*code as u8);
+                    data.extend_from_slice(value.as_bytes());
+                }
+                tag
+            }
+        };
+        data.push(FIELD_TERMINATOR);
+        directory.extend_from_slice(
+            format!("{tag:0>3.3}{:04}{start:05}", data.len() - start).as_bytes(),
+        );
+    }
+    directory.push(FIELD_TERMINATOR);
+    data.push(RECORD_TERMINATOR);
+
+    let base = 24 + directory.len();
+    let leader = record.leader.as_bytes();
+    let mut out = Vec::with_capacity(base + data.len());
+    out.extend_from_slice(format!("{:05}", base + data.len()).as_bytes());
+    out.extend_from_slice(&leader[5..12]);
+    out.extend_from_slice(format!("{base:05}").as_bytes());
+    out.extend_from_slice(&leader[17..24]);
+    out.extend(directory);
+    out.extend(data);
+    out
+}
+
+/// Read the books from a file of binary MARC 21 records.
+///
+/// ## Arguments
+/// * `data` - the records, one after another
+///
+/// ## Returns
+/// * A record for every MARC record, numbered from 1. A record that can't
+///   be parsed fails on its own; the ones after it are still read.
+pub fn read(data: &[u8]) -> Vec<Record> {
+    data.split(|&b| b == RECORD_TERMINATOR)
+        .filter(|chunk| !chunk.iter().all(u8::is_ascii_whitespace))
+        .enumerate()
+        .map(|(index, chunk)| Record {
+            row: index as u64 + 1,
+            book: decode(chunk.trim_ascii_start()).and_then(|record| record.to_book()),
+        })
+        .collect()
+}
+
+/// Parse one binary record, without its record terminator.
+fn decode(chunk: &[u8]) -> Result<MarcRecord> {
+    let malformed = |message: &str| Error::from(FieldError::new("record", message));
+    let number = |bytes: &[u8]| -> Result<usize> {
+        std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| malformed("a length or address isn't a number"))
+    };
+    if chunk.len() < 24 {
+        return Err(malformed("the record is shorter than its leader"));
+    }
+    let base = number(&chunk[12..17])?;
+    if base < 25 || base > chunk.len() || chunk[base - 1] != FIELD_TERMINATOR {
+        return Err(malformed("the base address of data is wrong"));
+    }
+    let directory = &chunk[24..base - 1];
+    if !directory.len().is_multiple_of(12) {
+        return Err(malformed("the directory is malformed"));
+    }
+
+    let mut fields = Vec::with_capacity(directory.len() / 12);
+    for entry in directory.chunks(12) {
+        let tag = String::from_utf8_lossy(&entry[..3]).into_owned();
+        let length = number(&entry[3..7])?;
+        let start = base + number(&entry[7..12])?;
+        let field = chunk
+            .get(start..start + length)
+            .ok_or_else(|| malformed("a field is outside the record"))?;
+        let field = field.strip_suffix(&[FIELD_TERMINATOR]).unwrap_or(field);
+        if tag.as_str() < "010" {
+            fields.push(Field::Control {
+                tag,
+                value: String::from_utf8_lossy(field).into_owned(),
+            });
+            continue;
+        }
+        let mut parts = field.split(|&b| b == SUBFIELD_DELIMITER);
+        let indicators = parts.next().unwrap_or_default();
+        let indicator = |i: usize| indicators.get(i).map_or(' ', |&b| b as char);
+        fields.push(Field::Data {
+            tag,
+            indicators: [indicator(0), indicator(1)],
+            subfields: parts
+                .filter_map(|part| {
+                    let (&code, value) = part.split_first()?;
+                    Some((code as char, String::from_utf8_lossy(value).into_owned()))
+                })
+                .collect(),
+        });
+    }
+    Ok(MarcRecord {
+        leader: String::from_utf8_lossy(&chunk[..24]).into_owned(),
+        fields,
+    })
+}
+
+/// The MARCXML export format, as served with `application/marcxml+xml`:
+/// a `collection` of `record`s.
+pub struct MarcXmlFormat;
+
+impl ExportFormat for MarcXmlFormat {
+    fn start(&self) -> Vec<u8> {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<collection xmlns=\"{MARCXML_NAMESPACE}\">\n"
+        )
+        .into_bytes()
+    }
+
+    fn books(&self, books: &[Book]) -> Vec<u8> {
+        books
+            .iter()
+            .map(|book| encode_xml(&MarcRecord::from_book(book)))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn end(&self) -> Vec<u8> {
+        b"</collection>\n".to_vec()
+    }
+}
+
+/// Write a record as a MARCXML `record` element.
+fn encode_xml(record: &MarcRecord) -> String {
+    let mut xml = format!(
+        "  <record>\n    <leader>{}</leader>\n",
+        escape(&record.leader)
+    );
+    for field in &record.fields {
+        match field {
+            Field::Control { tag, value } => xml.push_str(&format!(
+                "    <controlfield tag=\"{}\">{}</controlfield>\n",
+                escape(tag),
+                escape(value)
+            )),
+            Field::Data {
+                tag,
+                indicators,
+                subfields,
+            } => {
+                xml.push_str(&format!(
+                    "    <datafield tag=\"{}\" ind1=\"{}\" ind2=\"{}\">\n",
+                    escape(tag),
+                    indicators[0],
+                    indicators[1]
+                ));
+                for (code, value) in subfields {
+                    xml.push_str(&format!(
+                        "      <subfield code=\"{}\">{}</subfield>\n",
+                        escape(&code.to_string()),
+                        escape(value)
+                    ));
+                }
+                xml.push_str("    </datafield>\n");
+            }
+        }
+    }
+    xml.push_str("  </record>\n");
+    xml
+}
+
+/// Read the books from a MARCXML document: a `collection` of `record`s,
+/// or a single `record`. Elements may use any namespace prefix.
+///
+/// ## Arguments
+/// * `data` - the document, in UTF-8
+///
+/// ## Returns
+/// * A record for every MARC record, numbered from 1, or
+///   `Error::Validation` if the document isn't well-formed XML.
+pub fn read_xml(data: &[u8]) -> Result<Vec<Record>> {
+    let unreadable = |e: &dyn std::fmt::Display| Error::from(FieldError::new("file", e));
+    let attribute = |element: &BytesStart, name: &str| -> Result<Option<String>> {
+        element
+            .try_get_attribute(name)
+            .map_err(|e| unreadable(&e))?
+            .map(|value| value.unescape_value().map(|v| v.into_owned()))
+            .transpose()
+            .map_err(|e| unreadable(&e))
+    };
+
+    let mut reader = Reader::from_reader(data);
+    let mut buf = Vec::new();
+    let mut records = Vec::new();
+    let mut record: Option<MarcRecord> = None;
+    // The text of the element being read, if it's one that holds text.
+    let mut text: Option<String> = None;
+    loop {
+        let event = reader
+            .read_event_into(&mut buf)
+            .map_err(|e| unreadable(&e))?;
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                match element.local_name().as_ref() {
+                    b"record" => record = Some(MarcRecord::default()),
+                    b"leader" => text = Some(String::new()),
+                    b"controlfield" => {
+                        if let Some(record) = record.as_mut() {
+                            record.fields.push(Field::Control {
+                                tag: attribute(element, "tag")?.unwrap_or_default(),
+                                value: String::new(),
+                            });
+                        }
+                        text = Some(String::new());
+                    }
+                    b"datafield" => {
+                        if let Some(record) = record.as_mut() {
+                            let indicator = |name| -> Result<char> {
+                                Ok(attribute(element, name)?
+                                    .and_then(|value| value.chars().next())
+                                    .unwrap_or(' '))
+                            };
+                            record.fields.push(Field::Data {
+                                tag: attribute(element, "tag")?.unwrap_or_default(),
+                                indicators: [indicator("ind1")?, indicator("ind2")?],
+                                subfields: Vec::new(),
+                            });
+                        }
+                    }
+                    b"subfield" => {
+                        let code = attribute(element, "code")?
+                            .and_then(|code| code.chars().next())
+                            .unwrap_or(' ');
+                        if let Some(Field::Data { subfields, .. }) =
+                            record.as_mut().and_then(|r| r.fields.last_mut())
+                        {
+                            subfields.push((code, String::new()));
+                        }
+                        text = Some(String::new());
+                    }
+                    _ => {}
+                }
+                if matches!(event, Event::Empty(_)) {
+                    finish(
+                        &mut record,
+                        &mut records,
+                        element.local_name().as_ref(),
+                        &mut text,
+                    );
+                }
+            }
+            Event::Text(ref content) => {
+                if let Some(text) = text.as_mut() {
+                    text.push_str(&content.unescape().map_err(|e| unreadable(&e))?);
+                }
+            }
+            Event::CData(ref content) => {
+                if let Some(text) = text.as_mut() {
+                    text.push_str(&String::from_utf8_lossy(content));
+                }
+            }
+            Event::End(ref element) => {
+                finish(
+                    &mut record,
+                    &mut records,
+                    element.local_name().as_ref(),
+                    &mut text,
+                );
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| Record {
+            row: index as u64 + 1,
+            book: record.to_book(),
+        })
+        .collect())
+}
+
+/// Handle the end of a MARCXML element: store the text it held, or the
+/// record it closed.
+fn finish(
+    record: &mut Option<MarcRecord>,
+    records: &mut Vec<MarcRecord>,
+    name: &[u8],
+    text: &mut Option<String>,
+) {
+    if name == b"record" {
+        records.extend(record.take());
+        return;
+    }
+    let (Some(current), Some(value)) = (record.as_mut(), text.take()) else {
+        return;
+    };
+    match (name, current.fields.last_mut()) {
+        (b"leader", _) => current.leader = value,
+        (b"controlfield", Some(Field::Control { value: v, .. })) => *v = value,
+        (b"subfield", Some(Field::Data { subfields, .. })) => {
+            if let Some((_, v)) = subfields.last_mut() {
+                *v = value;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether `data` looks like MARCXML rather than binary MARC 21.
+pub fn is_xml(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    data.trim_ascii_start().starts_with(b"<")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::Credit;
+    use chrono::{TimeZone, Utc};
+
+    fn book(id: i32, title: &str, author: &str) -> Book {
+        let at = Utc.with_ymd_and_hms(2023, 10, 20, 9, 30, 15).unwrap();
+        Book {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            version: 1,
+            created_at: at,
+            created_by: "test".to_string(),
+            updated_at: at,
+            updated_by: "test".to_string(),
+            deleted_at: None,
+            isbn13: None,
+            isbn10: None,
+            authors: vec![Credit {
+                id,
+                name: author.to_string(),
+                role: AuthorRole::Author,
+            }],
+            publisher: None,
+            published_on: None,
+            edition: None,
+            page_count: None,
+            language: None,
+            series: None,
+            series_volume: None,
+            tags: Vec::new(),
+        }
+    }
+
+    fn as_new(book: &Book) -> NewBook {
+        NewBook {
+            title: book.title.clone(),
+            author: book.author.clone(),
+            authors: book
+                .authors
+                .iter()
+                .map(|credit| NewCredit {
+                    id: None,
+                    name: Some(credit.name.clone()),
+                    role: credit.role,
+                })
+                .collect(),
+            isbn: book.isbn13.clone(),
+            publisher: book.publisher.clone(),
+            published_on: book.published_on,
+            edition: book.edition.clone(),
+            page_count: book.page_count,
+            language: book.language.clone(),
+            series: book.series.clone(),
+            series_volume: book.series_volume,
+        }
+    }
+
+    /// A book with every edition detail filled in.
+    fn detailed(id: i32) -> Book {
+        Book {
+            isbn13: Some("9781680508161".to_string()),
+            isbn10: Some("1680508164".to_string()),
+            publisher: Some("The Pragmatic Bookshelf".to_string()),
+            published_on: NaiveDate::from_ymd_opt(2021, 7, 13),
+            edition: Some("1st".to_string()),
+            page_count: Some(342),
+            language: Some("en".to_string()),
+            series: Some("Pragmatic Express".to_string()),
+            series_volume: Some(3),
+            ..book(id, "Hands-on Rust", "Wolverson, Herbert")
+        }
+    }
+
+    /// A book by two people, one of them a translator.
+    fn translated(id: i32) -> Book {
+        let authors = vec![
+            Credit {
+                id: 7,
+                name: "Ölçer, Zoë".to_string(),
+                role: AuthorRole::Author,
+            },
+            Credit {
+                id: 8,
+                name: "Lindqvist, Åsa".to_string(),
+                role: AuthorRole::Translator,
+            },
+        ];
+        Book {
+            author: credit_line(authors.iter().map(|c| (c.name.as_str(), c.role))),
+            authors,
+            language: Some("swe".to_string()),
+            published_on: NaiveDate::from_ymd_opt(1999, 1, 1),
+            ..book(id, "Ünïcode & <Markup>", "")
+        }
+    }
+
+    fn books(records: Vec<Record>) -> Vec<(u64, NewBook)> {
+        records
+            .into_iter()
+            .map(|record| (record.row, record.book.unwrap()))
+            .collect()
+    }
+
+    /// A record as another catalog might send it, with ISBD punctuation,
+    /// a subtitle and fields the service doesn't use.
+    fn sample() -> MarcRecord {
+        let data = |tag: &str, indicators: [char; 2], subfields: &[(char, &str)]| Field::Data {
+            tag: tag.to_string(),
+            indicators,
+            subfields: subfields
+                .iter()
+                .map(|(code, value)| (*code, value.to_string()))
+                .collect(),
+        };
+        MarcRecord {
+            leader: "01042cam a2200301 i 4500".to_string(),
+            fields: vec![
+                Field::Control {
+                    tag: "001".to_string(),
+                    value: "22572397".to_string(),
+                },
+                Field::Control {
+                    tag: "008".to_string(),
+                    value: "210524s2021    ncua          001 0 eng d".to_string(),
+                },
+                data(
+                    "020",
+                    [' ', ' '],
+                    &[('a', "9781680508161"), ('q', "paperback")],
+                ),
+                data(
+                    "100",
+                    ['1', ' '],
+                    &[('a', "Wolverson, Herbert,"), ('e', "author.")],
+                ),
+                data(
+                    "245",
+                    ['1', '0'],
+                    &[
+                        ('a', "Hands-on Rust :"),
+                        (
+                            'b',
+                            "effective learning through 2D game development and play /",
+                        ),
+                        ('c', "Herbert Wolverson."),
+                    ],
+                ),
+                data(
+                    "710",
+                    ['2', ' '],
+                    &[('a', "Pragmatic Programmers (Firm),"), ('e', "publisher.")],
+                ),
+                data(
+                    "264",
+                    [' ', '1'],
+                    &[
+                        ('a', "Raleigh, North Carolina :"),
+                        ('b', "The Pragmatic Bookshelf,"),
+                        ('c', "2021."),
+                    ],
+                ),
+                data("250", [' ', ' '], &[('a', "First edition.")]),
+                data(
+                    "300",
+                    [' ', ' '],
+                    &[('a', "xvi, 342 pages :"), ('b', "illustrations ;")],
+                ),
+                data(
+                    "490",
+                    ['1', ' '],
+                    &[('a', "The pragmatic express ;"), ('v', "v. 3")],
+                ),
+                data(
+                    "830",
+                    [' ', '0'],
+                    &[('a', "Pragmatic express ;"), ('v', "3.")],
+                ),
+            ],
+        }
+    }
+
+    fn sample_book() -> NewBook {
+        NewBook {
+            title: "Hands-on Rust: effective learning through 2D game development and play"
+                .to_string(),
+            author: "Wolverson, Herbert".to_string(),
+            authors: vec![NewCredit {
+                id: None,
+                name: Some("Wolverson, Herbert".to_string()),
+                role: AuthorRole::Author,
+            }],
+            isbn: Some("9781680508161".to_string()),
+            publisher: Some("The Pragmatic Bookshelf".to_string()),
+            published_on: NaiveDate::from_ymd_opt(2021, 1, 1),
+            edition: Some("First edition".to_string()),
+            page_count: Some(342),
+            language: Some("eng".to_string()),
+            series: Some("Pragmatic express".to_string()),
+            series_volume: Some(3),
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let exported = [detailed(1), translated(2)];
+        let data = Marc21Format.books(&exported);
+        assert_eq!(data.iter().filter(|&&b| b == RECORD_TERMINATOR).count(), 2);
+        let length: usize = std::str::from_utf8(&data[..5]).unwrap().parse().unwrap();
+        assert_eq!(data[length - 1], RECORD_TERMINATOR);
+
+        let record = decode(&data[..length - 1]).unwrap();
+        assert_eq!(
+            record,
+            MarcRecord::from_book(&exported[0]).with_leader(&record.leader)
+        );
+        assert_eq!(&record.leader[5..12], &LEADER[5..12]);
+        assert_eq!(
+            books(read(&data)),
+            exported
+                .iter()
+                .enumerate()
+                .map(|(i, b)| (i as u64 + 1, as_new(b)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped() {
+        let exported = [
+            book(
+                1,
+                "Record\u{1d}Field\u{1e}Sub\u{1f}field",
+                "Wolverson, Herbert",
+            ),
+            book(2, "Hands-on Rust", "Wolverson,\u{1f}Herbert"),
+        ];
+        let expected: Vec<(u64, NewBook)> = exported
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let mut book = as_new(b);
+                book.title.retain(|c| !c.is_control());
+                book.author.retain(|c| !c.is_control());
+                for credit in &mut book.authors {
+                    credit.name.as_mut().unwrap().retain(|c| !c.is_control());
+                }
+                (i as u64 + 1, book)
+            })
+            .collect();
+        assert_eq!(expected[0].1.title, "RecordFieldSubfield");
+
+        let data = Marc21Format.books(&exported);
+        assert_eq!(data.iter().filter(|&&b| b == RECORD_TERMINATOR).count(), 2);
+        assert_eq!(books(read(&data)), expected);
+
+        let xml = [
+            MarcXmlFormat.start(),
+            MarcXmlFormat.books(&exported),
+            MarcXmlFormat.end(),
+        ]
+        .concat();
+        assert_eq!(books(read_xml(&xml).unwrap()), expected);
+    }
+
+    #[test]
+    fn reads_binary_sample() {
+        let mut data = encode(&sample());
+        data.extend_from_slice(b"00026nam a2200025 i 4500\x1e\x1d\n");
+        let records = read(&data);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].book.as_ref().unwrap(), &sample_book());
+        let Err(Error::Validation(errors)) = &records[1].book else {
+            panic!("expected a record without a title or author");
+        };
+        assert_eq!(errors.len(), 2);
+
+        // A corporate main entry isn't an author
+        let corporate = MarcRecord {
+            fields: sample()
+                .fields
+                .into_iter()
+                .map(|field| match field {
+                    Field::Data {
+                        tag, indicators, ..
+                    } if tag == "100" => Field::Data {
+                        tag: "110".to_string(),
+                        indicators,
+                        subfields: vec![('a', "United States. Congress.".to_string())],
+                    },
+                    field => field,
+                })
+                .collect(),
+            ..sample()
+        };
+        let Err(Error::Validation(errors)) = &read(&encode(&corporate))[0].book else {
+            panic!("expected a record without an author");
+        };
+        assert_eq!(errors, &[FieldError::new("100", "there is no author ($a)")]);
+
+        let Err(Error::Validation(_)) = decode(b"00010nam") else {
+            panic!("expected a malformed record");
+        };
+    }
+
+    #[test]
+    fn xml_round_trip() {
+        let exported = [
+            detailed(1),
+            book(2, "Ünïcode & <Markup>", "\"Quoted\" Author"),
+            translated(3),
+        ];
+        let xml = [
+            MarcXmlFormat.start(),
+            MarcXmlFormat.books(&exported),
+            MarcXmlFormat.end(),
+        ]
+        .concat();
+        assert!(is_xml(&xml));
+        assert_eq!(
+            books(read_xml(&xml).unwrap()),
+            exported
+                .iter()
+                .enumerate()
+                .map(|(i, b)| (i as u64 + 1, as_new(b)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reads_xml_sample() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<marc:collection xmlns:marc="http://www.loc.gov/MARC21/slim">
+  <marc:record>
+    <marc:leader>01042cam a2200301 i 4500</marc:leader>
+    <marc:controlfield tag="001">22572397</marc:controlfield>
+    <marc:controlfield tag="008">210524s2021    ncua          001 0 eng d</marc:controlfield>
+    <marc:datafield tag="020" ind1=" " ind2=" ">
+      <marc:subfield code="a">9781680508161</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="100" ind1="1" ind2=" ">
+      <marc:subfield code="a">Wolverson, Herbert,</marc:subfield>
+      <marc:subfield code="e">author.</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="245" ind1="1" ind2="0">
+      <marc:subfield code="a">Hands-on Rust :</marc:subfield>
+      <marc:subfield code="b">effective learning through 2D game development and play /</marc:subfield>
+      <marc:subfield code="c">Herbert Wolverson.</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="250" ind1=" " ind2=" ">
+      <marc:subfield code="a">First edition.</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="264" ind1=" " ind2="4">
+      <marc:subfield code="c">©2020</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="264" ind1=" " ind2="1">
+      <marc:subfield code="a">Raleigh, North Carolina :</marc:subfield>
+      <marc:subfield code="b">The Pragmatic Bookshelf,</marc:subfield>
+      <marc:subfield code="c">[2021]</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="300" ind1=" " ind2=" ">
+      <marc:subfield code="a">xvi, 342 pages :</marc:subfield>
+    </marc:datafield>
+    <marc:datafield tag="490" ind1="0" ind2=" ">
+      <marc:subfield code="a">Pragmatic express ;</marc:subfield>
+      <marc:subfield code="v">v. 3</marc:subfield>
+    </marc:datafield>
+  </marc:record>
+  <marc:record>
+    <marc:leader>00000nam a2200000 i 4500</marc:leader>
+    <marc:datafield tag="245" ind1="0" ind2="0">
+      <marc:subfield code="a">Anonymous works</marc:subfield>
+    </marc:datafield>
+  </marc:record>
+</marc:collection>"#;
+        let records = read_xml(xml.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].book.as_ref().unwrap(), &sample_book());
+        assert!(records[1].book.is_err());
+
+        // The sample reads the same once written in the binary format.
+        let binary = read(&encode(&sample()));
+        assert_eq!(binary[0].book.as_ref().unwrap(), &sample_book());
+
+        assert!(read_xml(b"<collection><record>").unwrap().is_empty());
+        assert!(read_xml(b"<collection></record>").is_err());
+    }
+
+    #[test]
+    fn reads_edition_details() {
+        assert_eq!(
+            parse_date("2021-07-13"),
+            NaiveDate::from_ymd_opt(2021, 7, 13)
+        );
+        assert_eq!(parse_date("c2021."), NaiveDate::from_ymd_opt(2021, 1, 1));
+        assert_eq!(parse_date("[date of publication not identified]"), None);
+        assert_eq!(parse_pages("1 online resource (342 pages)"), Some(342));
+        assert_eq!(parse_pages("342 p."), Some(342));
+        assert_eq!(parse_pages("1 volume (unpaged)"), None);
+        assert_eq!(parse_number("book 12."), Some(12));
+
+        let record = MarcRecord {
+            fields: vec![data("041", ['0', ' '], vec![('a', "engfre".to_string())])],
+            ..MarcRecord::default()
+        };
+        assert_eq!(record.language().as_deref(), Some("eng"));
+
+        let exported = MarcRecord::from_book(&translated(1));
+        let Some(Field::Control { value, .. }) = exported.fields.get(2) else {
+            panic!("expected an 008 field");
+        };
+        assert_eq!(value.len(), 40);
+        assert_eq!(&value[6..11], "s1999");
+        assert_eq!(&value[35..38], "swe");
+    }
+
+    impl MarcRecord {
+        fn with_leader(mut self, leader: &str) -> Self {
+            self.leader = leader.to_string();
+            self
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Moving the catalog in and out of the service, in formats other tools
+//! understand.
+//!
+//! Each format reads a file into `Record`s, and `import` does the rest the
+//! same way for all of them: every book is validated, duplicates are
+//! skipped, and the others are written through `db::batch`, so imports get
+//! the same checks, history and cache invalidation as any other write.
+//! Exports go the other way through `export`, which pages through the
+//! catalog and leaves the encoding to an `ExportFormat`. It reads straight
+//! from the repository, so a full export doesn't crowd the cache.
+
+pub mod csv;
+pub mod marc;
+
+use crate::db::{self, Batch, BatchMode, BatchOp, Book, NewBook, MAX_BATCH_SIZE, MAX_PAGE_SIZE};
+use crate::error::{Error, FieldError, Result};
+use crate::state::AppState;
+use axum::body::Bytes;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// A file format the catalog can be exported in.
+pub trait ExportFormat: Send + Sync + 'static {
+    /// Whatever comes before the first book, such as a header row
+    fn start(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Encode a page of books
+    fn books(&self, books: &[Book]) -> Vec<u8>;
+
+    /// Whatever comes after the last book
+    fn end(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// Stream every live book in `format`, a page at a time, in ID order.
+///
+/// Each page starts after the last ID of the one before, so books added or
+/// deleted while the export runs don't shift the pages. It still isn't a
+/// snapshot: books changed meanwhile may appear as they were before or
+/// after the change.
+pub fn export<F: ExportFormat>(state: AppState, format: F) -> impl Stream<Item = Result<Bytes>> {
+    let format = Arc::new(format);
+    let pages = {
+        let format = format.clone();
+        stream::unfold(Some(0), move |after| {
+            let state = state.clone();
+            let format = format.clone();
+            async move {
+                let after = after?;
+                Some(match state.repo.books_after(after, MAX_PAGE_SIZE).await {
+                    Ok(books) => {
+                        let next = if books.len() as i64 == MAX_PAGE_SIZE {
+                            books.last().map(|book| book.id)
+                        } else {
+                            None
+                        };
+                        (Ok(Bytes::from(format.books(&books))), next)
+                    }
+                    Err(err) => (Err(err), None),
+                })
+            }
+        })
+    };
+    let start = Bytes::from(format.start());
+    stream::once(async move { Ok(start) })
+        .chain(pages)
+        .chain(stream::once(async move { Ok(Bytes::from(format.end())) }))
+}
+
+/// One book read from an import file.
+#[derive(Debug)]
+pub struct Record {
+    /// Where the book was in the file, counted the way the format's users
+    /// would count it
+    pub row: u64,
+    /// The book, or why it couldn't be read
+    pub book: Result<NewBook>,
+}
+
+/// What an import did with one record.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    /// The book was added, or would be in a dry run
+    Inserted,
+    /// The book is already in the catalog, or earlier in the file
+    Skipped,
+    /// The record couldn't be read, or the book is invalid
+    Failed,
+}
+
+/// The outcome of importing one record.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ImportRow {
+    /// Where the record was in the file
+    pub row: u64,
+    /// What happened to it
+    pub status: ImportStatus,
+    /// The book as stored, if it was inserted
+    pub book: Option<Book>,
+    /// Why it was skipped or failed
+    pub message: Option<String>,
+    /// Field-level details, for invalid books
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl ImportRow {
+    fn inserted(row: u64, book: Option<Book>) -> Self {
+        Self {
+            row,
+            status: ImportStatus::Inserted,
+            book,
+            message: None,
+            errors: Vec::new(),
+        }
+    }
+
+    fn skipped(row: u64, message: String) -> Self {
+        Self {
+            row,
+            status: ImportStatus::Skipped,
+            book: None,
+            message: Some(message),
+            errors: Vec::new(),
+        }
+    }
+
+    fn failed(row: u64, err: &Error) -> Self {
+        let problem = err.problem();
+        Self {
+            row,
+            status: ImportStatus::Failed,
+            book: None,
+            message: Some(problem.detail),
+            errors: problem.errors,
+        }
+    }
+}
+
+/// The outcome of an import.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ImportReport {
+    /// If true, nothing was written: `inserted` counts the books that
+    /// would have been added
+    pub dry_run: bool,
+    /// How many books were added
+    pub inserted: usize,
+    /// How many records were duplicates
+    pub skipped: usize,
+    /// How many records couldn't be imported
+    pub failed: usize,
+    /// What happened to each record, in file order
+    pub rows: Vec<ImportRow>,
+}
+
+/// Add the books in `records` to the catalog, skipping any whose ISBN, or
+/// title and author (ignoring case), are already in the catalog or earlier
+/// in the file.
+///
+/// ## Arguments
+/// * `state` - the repository and cache to use
+/// * `records` - the books read from the file
+/// * `dry_run` - if true, check everything but write nothing
+/// * `actor` - who is importing
+///
+/// ## Returns
+/// * A report of what happened to every record. Only a database failure
+///   is an error; problems with single records are reported in the rows.
+pub async fn import(
+    state: &AppState,
+    records: Vec<Record>,
+    dry_run: bool,
+    actor: &str,
+) -> Result<ImportReport> {
+    let mut rows = Vec::with_capacity(records.len());
+    let mut seen: HashMap<(String, String), u64> = HashMap::new();
+    let mut seen_isbns: HashMap<String, u64> = HashMap::new();
+    let mut pending: Vec<(u64, NewBook)> = Vec::new();
+    for record in records {
+        let book = match record.book.and_then(|book| book.validated()) {
+            Ok(book) => book,
+            Err(err) => {
+                rows.push(ImportRow::failed(record.row, &err));
+                continue;
+            }
+        };
+        let key = (book.title.to_lowercase(), book.author.to_lowercase());
+        if let Some(first) = seen.get(&key) {
+            rows.push(ImportRow::skipped(
+                record.row,
+                format!("duplicate of row {first}"),
+            ));
+            continue;
+        }
+        if let Some(isbn) = &book.isbn {
+            if let Some(first) = seen_isbns.get(isbn) {
+                rows.push(ImportRow::skipped(
+                    record.row,
+                    format!("has the same ISBN as row {first}"),
+                ));
+                continue;
+            }
+            seen_isbns.insert(isbn.clone(), record.row);
+            match db::book_by_isbn(state, isbn).await {
+                Ok(existing) => {
+                    rows.push(ImportRow::skipped(
+                        record.row,
+                        format!(
+                            "ISBN {isbn} is already in the catalog as book {}",
+                            existing.id
+                        ),
+                    ));
+                    continue;
+                }
+                Err(Error::NotFound(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        seen.insert(key, record.row);
+        if let Some(existing) = db::books_like(state, &book).await?.first() {
+            rows.push(ImportRow::skipped(
+                record.row,
+                format!("already in the catalog as book {}", existing.id),
+            ));
+            continue;
+        }
+        pending.push((record.row, book));
+    }
+
+    if dry_run {
+        rows.extend(
+            pending
+                .into_iter()
+                .map(|(row, _)| ImportRow::inserted(row, None)),
+        );
+    } else {
+        for chunk in pending.chunks(MAX_BATCH_SIZE) {
+            let batch = Batch {
+                mode: BatchMode::Partial,
+                operations: chunk
+                    .iter()
+                    .map(|(_, book)| BatchOp::Create { book: book.clone() })
+                    .collect(),
+            };
+            for item in db::batch(state, &batch, actor).await?.items {
+                let row = chunk[item.index].0;
+                rows.push(match item.outcome {
+                    Ok(book) => ImportRow::inserted(row, book),
+                    Err(err) => ImportRow::failed(row, &err),
+                });
+            }
+        }
+    }
+
+    rows.sort_by_key(|row| row.row);
+    let count = |status| rows.iter().filter(|row| row.status == status).count();
+    Ok(ImportReport {
+        dry_run,
+        inserted: count(ImportStatus::Inserted),
+        skipped: count(ImportStatus::Skipped),
+        failed: count(ImportStatus::Failed),
+        rows,
+    })
+}
@@ -0,0 +1,119 @@
+//! International Standard Book Numbers.
+//!
+//! An ISBN is 13 digits (ISBN-13) or, for books numbered before 2007, 10
+//! (ISBN-10), the last of which is a check digit. Every ISBN-10 is also an
+//! ISBN-13: prefix `978` and recompute the check digit. The service stores
+//! the ISBN-13, and derives the ISBN-10 for the books that have one.
+
+/// Read an ISBN-10 or ISBN-13, ignoring hyphens and spaces, and verify its
+/// check digit.
+///
+/// ## Arguments
+/// * `text` - the ISBN as written, such as `1-68050-816-X` or
+///   `978-1-68050-816-1`
+///
+/// ## Returns
+/// * The ISBN-13, as 13 digits, or a description of what is wrong.
+pub fn parse(text: &str) -> Result<String, &'static str> {
+    let isbn: Vec<char> = text
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let digits = |chars: &[char]| chars.iter().all(char::is_ascii_digit);
+    match isbn.len() {
+        10 => {
+            if !digits(&isbn[..9]) || !(isbn[9].is_ascii_digit() || isbn[9] == 'X') {
+                return Err("must be digits, with an ISBN-10 possibly ending in X");
+            }
+            if isbn10_check(&isbn[..9]) != isbn[9] {
+                return Err("has the wrong check digit");
+            }
+            let mut isbn13: Vec<char> = "978".chars().chain(isbn[..9].iter().copied()).collect();
+            isbn13.push(isbn13_check(&isbn13));
+            Ok(isbn13.into_iter().collect())
+        }
+        13 => {
+            if !digits(&isbn) {
+                return Err("must be digits, with an ISBN-10 possibly ending in X");
+            }
+            if !(isbn.starts_with(&['9', '7', '8']) || isbn.starts_with(&['9', '7', '9'])) {
+                return Err("must start with 978 or 979");
+            }
+            if isbn13_check(&isbn[..12]) != isbn[12] {
+                return Err("has the wrong check digit");
+            }
+            Ok(isbn.into_iter().collect())
+        }
+        _ => Err("must be an ISBN-10 or ISBN-13"),
+    }
+}
+
+/// The ISBN-10 form of an ISBN-13, if it has one.
+///
+/// ## Arguments
+/// * `isbn13` - an ISBN-13 as returned by `parse`
+///
+/// ## Returns
+/// * The ISBN-10, or `None` for `979` ISBNs, which have no ISBN-10.
+pub fn to_isbn10(isbn13: &str) -> Option<String> {
+    let body: Vec<char> = isbn13.strip_prefix("978")?.chars().take(9).collect();
+    if body.len() != 9 {
+        return None;
+    }
+    let check = isbn10_check(&body);
+    Some(body.into_iter().chain([check]).collect())
+}
+
+/// The check digit of an ISBN-10, from its first nine digits.
+fn isbn10_check(digits: &[char]) -> char {
+    let sum: u32 = digits
+        .iter()
+        .zip((2..=10).rev())
+        .map(|(d, weight)| d.to_digit(10).unwrap_or(0) * weight)
+        .sum();
+    match (11 - sum % 11) % 11 {
+        10 => 'X',
+        check => char::from_digit(check, 10).expect("a check digit is below 10"),
+    }
+}
+
+/// The check digit of an ISBN-13, from its first twelve digits.
+fn isbn13_check(digits: &[char]) -> char {
+    let sum: u32 = digits
+        .iter()
+        .zip([1, 3].into_iter().cycle())
+        .map(|(d, weight)| d.to_digit(10).unwrap_or(0) * weight)
+        .sum();
+    char::from_digit((10 - sum % 10) % 10, 10).expect("a check digit is below 10")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_both_lengths() {
+        assert_eq!(parse("978-1-68050-816-1").unwrap(), "9781680508161");
+        assert_eq!(parse("1680508164").unwrap(), "9781680508161");
+        assert_eq!(parse("0-8044-2957-x").unwrap(), "9780804429573");
+        assert_eq!(parse("979 10 90636 07 1").unwrap(), "9791090636071");
+    }
+
+    #[test]
+    fn rejects_bad_isbns() {
+        assert_eq!(parse("9781680508162"), Err("has the wrong check digit"));
+        assert_eq!(parse("1680508165"), Err("has the wrong check digit"));
+        assert_eq!(parse("9771680508164"), Err("must start with 978 or 979"));
+        assert!(parse("978168050816").is_err());
+        assert!(parse("X680508164").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn converts_to_isbn10() {
+        assert_eq!(to_isbn10("9781680508161").as_deref(), Some("1680508164"));
+        assert_eq!(to_isbn10("9780804429573").as_deref(), Some("080442957X"));
+        assert_eq!(to_isbn10("9791090636071"), None);
+    }
+}
@@ -1,24 +1,50 @@
+mod actor;
+mod cache;
+mod conditional;
+mod config;
 mod db;
+mod error;
+mod interchange;
+mod isbn;
+mod metrics;
+mod openapi;
 mod rest;
+mod state;
+mod validation;
 mod view;
 
+use crate::config::{CacheConfig, TrashConfig};
 use crate::db::init_db;
+use crate::state::AppState;
 use anyhow::Result;
-use axum::{Extension, Router};
-use sqlx::SqlitePool;
+use axum::routing::get;
+use axum::Router;
 use std::net::SocketAddr;
 
+/// Build version 1 of the REST API, to be nested under `/api/v1`.
+fn api_v1() -> Router<AppState> {
+    Router::new()
+        .nest("/books", rest::books_service())
+        .nest("/authors", rest::authors_service())
+}
+
 /// Build the overall web service router.
 /// Constructing the router in a function makes it easy to re-use in unit tests.
-fn router(connection_pool: SqlitePool) -> Router {
+fn router(state: AppState) -> Router {
     Router::new()
-        // Nest service allows you to attach another router to a URL base.
-        // "/" inside the service will be "/books" to the outside world.
-        .nest_service("/books", rest::books_service())
+        // Version 1 of the REST API. Future versions can be nested alongside it.
+        .nest("/api/v1", api_v1())
+        // Nest allows you to attach another router to a URL base.
+        // "/" inside the router will be "/books" to the outside world.
+        // These are the original, unversioned routes, kept for existing clients.
+        .nest("/books", rest::legacy_books_service())
+        // Cache counters, for monitoring
+        .route("/metrics", get(metrics::metrics))
         // Add the web view
         .nest_service("/", view::view_service())
-        // Add the connection pool as a "layer", available for dependency injection.
-        .layer(Extension(connection_pool))
+        // Share the repository and cache with every handler, available for
+        // dependency injection through the `State` extractor.
+        .with_state(state)
 }
 
 #[tokio::main]
@@ -26,11 +52,20 @@ async fn main() -> Result<()> {
     // Load environment variables from .env if available
     dotenv::dotenv().ok();
 
-    // Initialize the database and obtain a connection pool
-    let connection_pool = init_db().await?;
+    // Log to stderr
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    // Initialize the database and obtain a repository
+    let repo = init_db().await?;
+
+    // Put the configured cache in front of it
+    let cache = cache::from_config(&CacheConfig::from_env()?);
 
     // Initialize the Axum routing service
-    let app = router(connection_pool);
+    let state = AppState::new(repo, cache).with_trash(TrashConfig::from_env()?);
+    let app = router(state);
 
     // Define the address to listen on (everything)
     let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
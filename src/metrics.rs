@@ -0,0 +1,87 @@
+//! Counters for monitoring, in the Prometheus text exposition format.
+
+use crate::cache::CacheStats;
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use std::fmt::Write;
+
+/// The content type Prometheus expects from a scrape.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+/// Report the book cache's counters.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+///
+/// ## Returns
+/// * The counters, one metric per line.
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, TEXT_FORMAT)], render(&state.cache.stats()))
+}
+
+/// Format the cache counters as Prometheus metrics.
+fn render(stats: &CacheStats) -> String {
+    let metrics = [
+        (
+            "book_cache_hits_total",
+            "counter",
+            "Cache lookups that found a live entry.",
+            stats.hits,
+        ),
+        (
+            "book_cache_misses_total",
+            "counter",
+            "Cache lookups that went to the database.",
+            stats.misses,
+        ),
+        (
+            "book_cache_evictions_total",
+            "counter",
+            "Entries evicted to make room.",
+            stats.evictions,
+        ),
+        (
+            "book_cache_expirations_total",
+            "counter",
+            "Entries dropped after their time to live.",
+            stats.expirations,
+        ),
+        (
+            "book_cache_entries",
+            "gauge",
+            "Entries currently cached.",
+            stats.entries,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        writeln!(out, "{name} {value}").unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use axum_test_helper::TestClient;
+
+    #[tokio::test]
+    async fn counts_cache_hits() {
+        let client = TestClient::new(crate::router(crate::state::test_state().await));
+        client.get("/api/v1/books/1").send().await;
+        client.get("/api/v1/books/1").send().await;
+
+        let res = client.get("/metrics").send().await;
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = res.text().await;
+        assert!(body.contains("book_cache_hits_total 1\n"));
+        assert!(body.contains("book_cache_misses_total 1\n"));
+        assert!(body.contains("book_cache_entries 1\n"));
+    }
+}
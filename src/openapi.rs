@@ -0,0 +1,207 @@
+//! The OpenAPI description of the REST API.
+//!
+//! The document is generated from the `#[utoipa::path]` annotations on
+//! the handlers in `rest`, and the `ToSchema` types they exchange.
+
+use crate::db::{
+    Author, AuthorRole, Batch, BatchMode, BatchOp, Book, BookChange, BookPatch, BookUpdate,
+    ChangeAction, Credit, NewAuthor, NewBook, NewCredit, SearchHit, SortField, SortOrder, TagCount,
+};
+use crate::error::{FieldError, Problem};
+use crate::interchange::{ImportReport, ImportRow, ImportStatus};
+use crate::rest::{AuthorList, BatchResponse, BatchResult, BookList, PurgeReport};
+use utoipa::OpenApi;
+
+/// The OpenAPI 3 document for version 1 of the API.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Book Database",
+        description = "CRUD service for a small library catalog. \
+            The unversioned `/books` routes are deprecated aliases of `/api/v1/books`."
+    ),
+    paths(
+        crate::rest::get_all_books,
+        crate::rest::search,
+        crate::rest::get_trash,
+        crate::rest::purge_trash,
+        crate::rest::apply_batch,
+        crate::rest::export_csv,
+        crate::rest::import_csv,
+        crate::rest::export_marc,
+        crate::rest::export_marcxml,
+        crate::rest::import_marc,
+        crate::rest::get_book,
+        crate::rest::get_book_by_isbn,
+        crate::rest::get_book_history,
+        crate::rest::restore_book,
+        crate::rest::tag_book,
+        crate::rest::untag_book,
+        crate::rest::create_book,
+        crate::rest::replace_book,
+        crate::rest::patch_book,
+        crate::rest::remove_book,
+        crate::rest::get_authors,
+        crate::rest::create_author,
+        crate::rest::get_author,
+        crate::rest::rename_author,
+        crate::rest::remove_author,
+        crate::rest::get_author_books,
+    ),
+    components(schemas(
+        Book,
+        NewBook,
+        BookPatch,
+        BookUpdate,
+        BookList,
+        Author,
+        AuthorList,
+        AuthorRole,
+        Credit,
+        NewAuthor,
+        NewCredit,
+        PurgeReport,
+        Batch,
+        BatchMode,
+        BatchOp,
+        BatchResponse,
+        BatchResult,
+        ImportReport,
+        ImportRow,
+        ImportStatus,
+        SearchHit,
+        TagCount,
+        BookChange,
+        ChangeAction,
+        SortField,
+        SortOrder,
+        Problem,
+        FieldError
+    )),
+    tags(
+        (name = "books", description = "The book catalog"),
+        (name = "authors", description = "The people credited on books")
+    )
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum_test_helper::TestClient;
+    use utoipa::openapi::PathItemType;
+
+    const METHODS: [(PathItemType, &str); 5] = [
+        (PathItemType::Get, "GET"),
+        (PathItemType::Post, "POST"),
+        (PathItemType::Put, "PUT"),
+        (PathItemType::Patch, "PATCH"),
+        (PathItemType::Delete, "DELETE"),
+    ];
+
+    /// A client for the whole service, where book 1 has an ISBN and a tag,
+    /// so that paths naming them find it.
+    async fn client() -> TestClient {
+        let client = TestClient::new(crate::router(crate::state::test_state().await));
+        let res = client
+            .patch("/api/v1/books/1")
+            .json(&serde_json::json!({ "isbn": "9781680508161", "version": 1 }))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.put("/api/v1/books/1/tags/rust").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        client
+    }
+
+    /// Send a bodiless `method` request to `url`, with its parameters filled
+    /// in, and return the status, and whether the router served it at all
+    /// (rather than answering 405, or 404 with no body).
+    async fn probe(client: &TestClient, method: PathItemType, path: &str) -> (StatusCode, bool) {
+        let url = path
+            .replace("{id}", "1")
+            .replace("{isbn}", "9781680508161")
+            .replace("{tag}", "rust");
+        let req = match method {
+            PathItemType::Get => client.get(&url),
+            PathItemType::Post => client.post(&url),
+            PathItemType::Put => client.put(&url),
+            PathItemType::Patch => client.patch(&url),
+            PathItemType::Delete => client.delete(&url),
+            _ => unreachable!(),
+        };
+        let res = req.send().await;
+        let routed = res.status() != StatusCode::METHOD_NOT_ALLOWED
+            && !(res.status() == StatusCode::NOT_FOUND
+                && res.headers().get("content-type").is_none());
+        (res.status(), routed)
+    }
+
+    /// Every method the routers serve, on every path in their route tables,
+    /// must be documented.
+    #[tokio::test]
+    async fn routes_are_documented() {
+        let client = client().await;
+        let spec = ApiDoc::openapi();
+        let services = [
+            ("/api/v1/books", crate::rest::book_routes()),
+            ("/api/v1/authors", crate::rest::author_routes()),
+        ];
+        for (prefix, routes) in services {
+            for (route, _) in routes {
+                // Axum's `/:id` is OpenAPI's `/{id}`
+                let path = route
+                    .split('/')
+                    .map(|segment| match segment.strip_prefix(':') {
+                        Some(name) => format!("{{{name}}}"),
+                        None => segment.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let path = format!("{prefix}{}", path.trim_end_matches('/'));
+                let item = spec.paths.paths.get(&path);
+                assert!(item.is_some(), "{path} is routed but not documented");
+                for (method, name) in METHODS {
+                    let (status, routed) = probe(&client, method.clone(), &path).await;
+                    assert!(
+                        !routed || item.unwrap().operations.contains_key(&method),
+                        "{name} {path} is routed ({status}) but not documented"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Every documented operation must be routed, and every other method on
+    /// a documented path must be rejected by the router.
+    #[tokio::test]
+    async fn spec_matches_routes() {
+        let client = client().await;
+        let spec = ApiDoc::openapi();
+        assert!(!spec.paths.paths.is_empty());
+        for (path, item) in &spec.paths.paths {
+            for (method, name) in METHODS {
+                let (status, routed) = probe(&client, method.clone(), path).await;
+                let documented = item.operations.contains_key(&method);
+                assert_eq!(
+                    routed, documented,
+                    "{name} {path}: documented={documented}, router returned {status}"
+                );
+                // The path parameters name real records, so reads must find them.
+                if documented && method == PathItemType::Get && path.contains('{') {
+                    assert!(status.is_success(), "GET {path} returned {status}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_spec() {
+        let client = TestClient::new(crate::router(crate::state::test_state().await));
+        let res = client.get("/openapi.json").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let text = res.text().await;
+        assert!(text.contains("\"/api/v1/books/{id}\""));
+    }
+}
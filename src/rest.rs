@@ -1,155 +1,2328 @@
-use crate::db::{all_books, book_by_id, Book};
-use axum::extract::Path;
-use axum::http::StatusCode;
-use axum::routing::{delete, get, post, put};
-use axum::{extract, Extension, Json, Router};
-use sqlx::SqlitePool;
+use crate::actor::Actor;
+use crate::conditional::{Conditional, Conditions, Validators};
+use crate::db::{
+    all_books, author_by_id, authors, book_as_of, book_by_id, book_by_isbn, book_facets,
+    book_history, books_by_author, search_books, trash, Author, Batch, BatchOp, Book, BookChange,
+    BookFilter, BookPatch, BookQuery, BookUpdate, FilterExpr, NewAuthor, NewBook, Page, SearchHit,
+    SortField, SortOrder, TagCount,
+};
+use crate::error::{Error, FieldError, Problem, Result};
+use crate::interchange::csv::CsvFormat;
+use crate::interchange::marc::{Marc21Format, MarcXmlFormat};
+use crate::interchange::{self, ImportReport};
+use crate::state::AppState;
+use async_trait::async_trait;
+use axum::body::{Body, Bytes, StreamBody};
+use axum::extract::{FromRequest, FromRequestParts, Multipart, OriginalUri, Path, Query, State};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::map_response;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put, MethodRouter};
+use axum::{extract, Json, Router, TypedHeader};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// Every route of the books REST service, by path. The service is built
+/// from this table, so tests can check each route against the API docs.
+pub(crate) fn book_routes() -> Vec<(&'static str, MethodRouter<AppState>)> {
+    vec![
+        ("/", get(get_all_books).post(create_book)),
+        ("/search", get(search)),
+        ("/trash", get(get_trash).delete(purge_trash)),
+        ("/batch", post(apply_batch)),
+        ("/export.csv", get(export_csv)),
+        ("/export.mrc", get(export_marc)),
+        ("/export.xml", get(export_marcxml)),
+        ("/import", post(import_csv)),
+        ("/import/marc", post(import_marc)),
+        ("/isbn/:isbn", get(get_book_by_isbn)),
+        (
+            "/:id",
+            get(get_book)
+                .put(replace_book)
+                .patch(patch_book)
+                .delete(remove_book),
+        ),
+        ("/:id/history", get(get_book_history)),
+        ("/:id/restore", post(restore_book)),
+        ("/:id/tags/:tag", put(tag_book).delete(untag_book)),
+    ]
+}
+
+/// Every route of the authors REST service, by path.
+pub(crate) fn author_routes() -> Vec<(&'static str, MethodRouter<AppState>)> {
+    vec![
+        ("/", get(get_authors).post(create_author)),
+        (
+            "/:id",
+            get(get_author).put(rename_author).delete(remove_author),
+        ),
+        ("/:id/books", get(get_author_books)),
+    ]
+}
+
+/// Build a router serving `routes`.
+fn router_from(routes: Vec<(&'static str, MethodRouter<AppState>)>) -> Router<AppState> {
+    routes
+        .into_iter()
+        .fold(Router::new(), |router, (path, route)| {
+            router.route(path, route)
+        })
+}
 
 /// Build the books REST service.
 /// Placing it in its own module with a single service export
 /// allows for clean separation of responsibility.
-pub fn books_service() -> Router {
-    Router::new()
-        .route("/", get(get_all_books))
-        .route("/:id", get(get_book))
+pub fn books_service() -> Router<AppState> {
+    router_from(book_routes())
+}
+
+/// Build the authors REST service.
+pub fn authors_service() -> Router<AppState> {
+    router_from(author_routes())
+}
+
+/// The date after which the legacy `/books` routes may be removed,
+/// as an HTTP-date for the `Sunset` header (RFC 8594).
+const LEGACY_SUNSET: &str = "Fri, 30 Apr 2027 00:00:00 GMT";
+
+/// Build the pre-versioning books service. It serves everything
+/// `books_service` does, plus the old verb-style `/add`, `/edit` and
+/// `/delete/:id` routes, and marks every response as deprecated.
+/// The verb-style `/edit` and `/delete/:id` check versions just as their
+/// successors do.
+pub fn legacy_books_service() -> Router<AppState> {
+    books_service()
         .route("/add", post(add_book))
         .route("/edit", put(update_book))
         .route("/delete/:id", delete(delete_book))
+        .layer(map_response(mark_deprecated))
+}
+
+/// Add `Deprecation`, `Sunset` and successor `Link` headers to a response.
+async fn mark_deprecated(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    headers.insert("sunset", HeaderValue::from_static(LEGACY_SUNSET));
+    headers.insert(
+        header::LINK,
+        HeaderValue::from_static("</api/v1/books>; rel=\"successor-version\""),
+    );
+    response
+}
+
+/// Query-string parameters accepted by the book listing.
+/// Anything omitted falls back to the `BookQuery` default.
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListParams {
+    /// Maximum number of books to return (1-500, default 50)
+    limit: Option<i64>,
+    /// Number of books to skip
+    offset: Option<i64>,
+    /// Field to sort by (default `title`)
+    sort: Option<SortField>,
+    /// Sort direction (default `asc`)
+    order: Option<SortOrder>,
+    /// Only return books changed at or after this RFC 3339 time. Sort by
+    /// `updated_at` to sync changes incrementally.
+    updated_since: Option<DateTime<Utc>>,
+    /// Only return books from this publisher, ignoring case
+    publisher: Option<String>,
+    /// Only return books in this series, ignoring case
+    series: Option<String>,
+    /// Only return books with this number in their series
+    series_volume: Option<i32>,
+    /// Only return books with this edition statement, ignoring case
+    edition: Option<String>,
+    /// Only return books in this language (an ISO 639 code)
+    language: Option<String>,
+    /// Only return books published on or after this date (YYYY-MM-DD)
+    published_from: Option<NaiveDate>,
+    /// Only return books published on or before this date (YYYY-MM-DD)
+    published_to: Option<NaiveDate>,
+    /// Only return books with at least this many pages
+    min_pages: Option<i32>,
+    /// Only return books with at most this many pages
+    max_pages: Option<i32>,
+    /// Only return books matching this expression, such as
+    /// `title contains "Rust" and created_at > 2024-01-01`
+    filter: Option<String>,
+}
+
+impl From<ListParams> for BookQuery {
+    fn from(params: ListParams) -> Self {
+        let defaults = BookQuery::default();
+        BookQuery {
+            limit: params.limit.unwrap_or(defaults.limit),
+            offset: params.offset.unwrap_or(defaults.offset),
+            sort: params.sort.unwrap_or(defaults.sort),
+            order: params.order.unwrap_or(defaults.order),
+            updated_since: params.updated_since,
+            filter: BookFilter {
+                publisher: params.publisher,
+                series: params.series,
+                series_volume: params.series_volume,
+                edition: params.edition,
+                language: params.language,
+                published_from: params.published_from,
+                published_to: params.published_to,
+                min_pages: params.min_pages,
+                max_pages: params.max_pages,
+                expression: None,
+                tags: Vec::new(),
+            },
+        }
+        .normalized()
+    }
+}
+
+/// The query for a book listing: the `ListParams` with the `filter`
+/// expression parsed, plus any number of `tag` parameters, which `Query`
+/// can't collect into a list.
+struct ListQuery(BookQuery);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ListQuery {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> std::result::Result<Self, Self::Rejection> {
+        let Query(mut params) = Query::<ListParams>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let expression = match params.filter.take() {
+            Some(text) => Some(FilterExpr::parse(&text).map_err(|err| {
+                Error::BadRequest(format!("invalid filter, {err}")).into_response()
+            })?),
+            None => None,
+        };
+        let mut query = BookQuery::from(params);
+        query.filter.expression = expression;
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(parts.uri.query().unwrap_or_default()).unwrap_or_default();
+        query.filter.tags = pairs
+            .into_iter()
+            .filter(|(name, _)| name == "tag")
+            .map(|(_, tag)| tag)
+            .collect();
+        Ok(ListQuery(query.normalized()))
+    }
+}
+
+/// Response envelope for a page of books.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BookList {
+    /// The books on this page
+    pub items: Vec<Book>,
+    /// Total number of books available
+    pub total: i64,
+    /// The page size that was applied
+    pub limit: i64,
+    /// The offset that was applied
+    pub offset: i64,
+    /// Link to the next page, if there is one
+    pub next: Option<String>,
+    /// Link to the previous page, if there is one
+    pub prev: Option<String>,
+    /// How many of all the matching books carry each tag, most common
+    /// first. Only the main listing has facets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facets: Option<Vec<TagCount>>,
+}
+
+impl BookList {
+    /// Wrap a page of books, building next/prev links relative to `path`.
+    fn new(path: &str, query: &BookQuery, page: Page<Book>) -> Self {
+        let since = query.updated_since.map_or(String::new(), |since| {
+            format!(
+                "&updated_since={}",
+                since.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            )
+        });
+        let mut filter = match serde_urlencoded::to_string(&query.filter) {
+            Ok(filter) if !filter.is_empty() => format!("&{filter}"),
+            _ => String::new(),
+        };
+        for tag in &query.filter.tags {
+            if let Ok(tag) = serde_urlencoded::to_string([("tag", tag)]) {
+                filter.push('&');
+                filter.push_str(&tag);
+            }
+        }
+        let link = |offset: i64| {
+            format!(
+                "{path}?limit={}&offset={offset}&sort={}&order={}{since}{filter}",
+                query.limit,
+                query.sort.as_str(),
+                query.order.as_str()
+            )
+        };
+        let next =
+            (query.offset + query.limit < page.total).then(|| link(query.offset + query.limit));
+        let prev = (query.offset > 0).then(|| link((query.offset - query.limit).max(0)));
+        Self {
+            items: page.items,
+            total: page.total,
+            limit: query.limit,
+            offset: query.offset,
+            next,
+            prev,
+            facets: None,
+        }
+    }
 }
 
 /// Wrap the db layer in a GET request, using Axum's built-in JSON support.
 ///
 /// ## Arguments
-/// * `Extension(cnn)` - dependency injected by Axum from the database layer.
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `OriginalUri(uri)` - the request URI, used to build paging links.
+/// * `conditions` - any `If-None-Match` or `If-Modified-Since` headers.
+/// * `ListQuery(query)` - the paging, sorting and filter parameters.
 ///
 /// ## Returns
-/// Either an error 500, or a JSON list of all books in the database.
+/// Either an error, `304 Not Modified` if the client's copy is current, or
+/// a JSON page of books with paging links and tag facets. The `ETag` changes whenever any
+/// book does, so it is checked before the database is.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books",
+    tag = "books",
+    params(
+        ListParams,
+        ("tag" = Option<Vec<String>>, Query, description = "Only return books with this tag; repeat for books with every one"),
+    ),
+    responses(
+        (status = 200, description = "A page of books", body = BookList,
+            headers(("etag" = String), ("last-modified" = String))),
+        (status = 304, description = "The client's copy is current"),
+        (status = 400, description = "Invalid query parameters"),
+    )
+)]
 async fn get_all_books(
-    Extension(cnn): Extension<SqlitePool>,
-) -> Result<Json<Vec<Book>>, StatusCode> {
-    if let Ok(books) = all_books(&cnn).await {
-        Ok(Json(books))
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    conditions: Conditions,
+    ListQuery(query): ListQuery,
+) -> Result<Conditional<Json<BookList>>> {
+    // Read the generation before the page, so that a write in between
+    // leaves the ETag older than the content rather than newer.
+    let validators = Validators::for_list(&state.cache.generation(), uri.path(), &query);
+    if conditions.not_modified(&validators) {
+        return Ok(Conditional::NotModified(validators));
+    }
+    let page = all_books(&state, &query).await?;
+    let facets = book_facets(&state, &query).await?;
+    Ok(Conditional::Modified(
+        validators,
+        Json(BookList {
+            facets: Some(facets),
+            ..BookList::new(uri.path(), &query, page)
+        }),
+    ))
+}
+
+/// Lists the books in the trash.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `OriginalUri(uri)` - the request URI, used to build paging links.
+/// * `ListQuery(query)` - the paging, sorting and filter parameters.
+///
+/// ## Returns
+/// Either an error, or a JSON page of deleted books with paging links.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/trash",
+    tag = "books",
+    params(ListParams),
+    responses(
+        (status = 200, description = "A page of deleted books", body = BookList),
+        (status = 400, description = "Invalid query parameters"),
+    )
+)]
+async fn get_trash(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    ListQuery(query): ListQuery,
+) -> Result<Json<BookList>> {
+    let page = trash(&state, &query).await?;
+    Ok(Json(BookList::new(uri.path(), &query, page)))
+}
+
+/// The outcome of purging the trash.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PurgeReport {
+    /// How many books were permanently removed
+    pub purged: u64,
+}
+
+/// Permanently removes books that have been in the trash for longer than
+/// the retention period (`TRASH_RETENTION_DAYS`).
+///
+/// ## Arguments
+/// * `State(state)` - the repository and trash settings, injected by Axum.
+/// * `auth` - the `Authorization` header, which must carry `ADMIN_TOKEN` as
+///   a bearer token.
+/// * `actor` - who is purging, from the `X-User` header, for the history.
+///
+/// ## Returns
+/// Either an error (403 without the admin token, or if none is
+/// configured), or how many books were removed.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/books/trash",
+    tag = "books",
+    params(
+        ("authorization" = String, Header, description = "`Bearer` and the admin token"),
+        ("x-user" = Option<String>, Header, description = "Who is purging"),
+    ),
+    responses(
+        (status = 200, description = "Old deleted books were removed", body = PurgeReport),
+        (status = 403, description = "Only admins may purge", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn purge_trash(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    actor: Actor,
+) -> Result<Json<PurgeReport>> {
+    let token = auth.as_ref().map(|TypedHeader(auth)| auth.token());
+    let purged = crate::db::purge_books(&state, token, actor.name()).await?;
+    Ok(Json(PurgeReport { purged }))
+}
+
+/// What happened to one operation of a batch.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BatchResult {
+    /// The operation's position in the request
+    pub index: usize,
+    /// The status the operation would have had as a request of its own
+    pub status: u16,
+    /// The book as stored, for a create or update that succeeded
+    pub book: Option<Book>,
+    /// Why the operation failed
+    pub error: Option<Problem>,
+}
+
+/// Response body for a batch.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BatchResponse {
+    /// Whether the batch's changes were kept
+    pub committed: bool,
+    /// The operations that were attempted, in order
+    pub results: Vec<BatchResult>,
+}
+
+/// Applies many creates, updates and deletes in one transaction.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `actor` - who is making the changes, from the `X-User` header.
+/// * A Json-encoded `Batch` of operations, extracted from the post body.
+///
+/// ## Returns
+/// Either an error, `200 OK` with the result of every operation if the
+/// batch was committed, or, when an atomic batch fails, the status of the
+/// failed operation with the results up to and including it. Updates and
+/// deletes must give the `version` they change.
+#[utoipa::path(
+    post,
+    path = "/api/v1/books/batch",
+    tag = "books",
+    params(("x-user" = Option<String>, Header, description = "Who is making the changes")),
+    request_body = Batch,
+    responses(
+        (status = 200, description = "The batch was committed", body = BatchResponse),
+        (status = 404, description = "An atomic batch was rolled back: a book was missing", body = BatchResponse),
+        (status = 409, description = "An atomic batch was rolled back: a version was out of date", body = BatchResponse),
+        (status = 422, description = "The batch is too large, or an atomic batch was rolled back: an operation was invalid", body = BatchResponse),
+    )
+)]
+async fn apply_batch(
+    State(state): State<AppState>,
+    actor: Actor,
+    Json(batch): Json<Batch>,
+) -> Result<(StatusCode, Json<BatchResponse>)> {
+    let outcome = crate::db::batch(&state, &batch, actor.name()).await?;
+    let results: Vec<BatchResult> = outcome
+        .items
+        .into_iter()
+        .map(|item| match item.outcome {
+            Ok(book) => BatchResult {
+                index: item.index,
+                status: match batch.operations[item.index] {
+                    BatchOp::Create { .. } => StatusCode::CREATED,
+                    BatchOp::Update { .. } => StatusCode::OK,
+                    BatchOp::Delete { .. } => StatusCode::NO_CONTENT,
+                }
+                .as_u16(),
+                book,
+                error: None,
+            },
+            Err(err) => BatchResult {
+                index: item.index,
+                status: err.status().as_u16(),
+                book: None,
+                error: Some(err.problem()),
+            },
+        })
+        .collect();
+    let status = match results.last() {
+        Some(failed) if !outcome.committed => {
+            StatusCode::from_u16(failed.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        _ => StatusCode::OK,
+    };
+    Ok((
+        status,
+        Json(BatchResponse {
+            committed: outcome.committed,
+            results,
+        }),
+    ))
+}
+
+/// Downloads the whole catalog as CSV.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+///
+/// ## Returns
+/// Every live book, one per row in ID order, streamed a page at a time.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/export.csv",
+    tag = "books",
+    responses(
+        (status = 200, description = "The catalog, with a header row", body = String, content_type = "text/csv"),
+    )
+)]
+async fn export_csv(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"books.csv\"",
+            ),
+        ],
+        StreamBody::new(interchange::export(state, CsvFormat)),
+    )
+}
+
+/// Downloads the whole catalog as binary MARC 21 records.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+///
+/// ## Returns
+/// Every live book as a MARC 21 record in UTF-8, in ID order, streamed a
+/// page at a time.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/export.mrc",
+    tag = "books",
+    responses(
+        (status = 200, description = "The catalog, one record per book", body = Vec<u8>, content_type = "application/marc"),
+    )
+)]
+async fn export_marc(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "application/marc"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"books.mrc\"",
+            ),
+        ],
+        StreamBody::new(interchange::export(state, Marc21Format)),
+    )
+}
+
+/// Downloads the whole catalog as MARCXML.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+///
+/// ## Returns
+/// Every live book as a `record` in one `collection`, in ID order, streamed
+/// a page at a time.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/export.xml",
+    tag = "books",
+    responses(
+        (status = 200, description = "The catalog as a MARCXML collection", body = String, content_type = "application/marcxml+xml"),
+    )
+)]
+async fn export_marcxml(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/marcxml+xml; charset=utf-8",
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"books.xml\"",
+            ),
+        ],
+        StreamBody::new(interchange::export(state, MarcXmlFormat)),
+    )
+}
+
+/// Query-string parameters accepted by the CSV import.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportParams {
+    /// Check the file and report what would happen, without changing
+    /// anything
+    #[serde(default)]
+    dry_run: bool,
+    /// The name of the column holding titles (default `title`)
+    title_column: Option<String>,
+    /// The name of the column holding authors (default `author`)
+    author_column: Option<String>,
+    /// The name of the column holding ISBNs (default `isbn`, if present)
+    isbn_column: Option<String>,
+}
+
+/// Adds the books in a CSV file to the catalog.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `actor` - who is importing, from the `X-User` header.
+/// * `Query(params)` - dry-run mode, and which columns to read.
+/// * `request` - the file, either as the whole body or as the `file` part
+///   of a `multipart/form-data` upload.
+///
+/// ## Returns
+/// Either an error (422 if the file or its header can't be read), or a
+/// report of what happened to each row. Books whose title and author are
+/// already in the catalog, or earlier in the file, are skipped.
+#[utoipa::path(
+    post,
+    path = "/api/v1/books/import",
+    tag = "books",
+    params(
+        ("x-user" = Option<String>, Header, description = "Who is importing"),
+        ImportParams,
+    ),
+    request_body(content = String, content_type = "text/csv",
+        description = "A CSV file with a header row; may also be sent as the `file` part of a multipart/form-data upload"),
+    responses(
+        (status = 200, description = "What happened to each row", body = ImportReport),
+        (status = 422, description = "The file can't be read, or lacks a column", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn import_csv(
+    State(state): State<AppState>,
+    actor: Actor,
+    Query(params): Query<ImportParams>,
+    request: Request<Body>,
+) -> Result<Json<ImportReport>> {
+    let data = upload(&state, request).await?;
+    let defaults = interchange::csv::Columns::default();
+    let columns = interchange::csv::Columns {
+        title: params.title_column.unwrap_or(defaults.title),
+        author: params.author_column.unwrap_or(defaults.author),
+        isbn: params.isbn_column,
+    };
+    let records = interchange::csv::read(&data, &columns)?;
+    let report = interchange::import(&state, records, params.dry_run, actor.name()).await?;
+    Ok(Json(report))
+}
+
+/// Query-string parameters accepted by the MARC import.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MarcImportParams {
+    /// Check the file and report what would happen, without changing
+    /// anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Adds the books in a file of MARC 21 or MARCXML records to the catalog.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `actor` - who is importing, from the `X-User` header.
+/// * `Query(params)` - dry-run mode.
+/// * `request` - the file, either as the whole body or as the `file` part
+///   of a `multipart/form-data` upload. Files starting with `<` are read as
+///   MARCXML, anything else as binary MARC 21.
+///
+/// ## Returns
+/// Either an error (422 if a MARCXML file isn't well-formed), or a report
+/// of what happened to each record, numbered from 1. Records without a
+/// title (245) or author (100) fail; duplicates are skipped as in the CSV
+/// import.
+#[utoipa::path(
+    post,
+    path = "/api/v1/books/import/marc",
+    tag = "books",
+    params(
+        ("x-user" = Option<String>, Header, description = "Who is importing"),
+        MarcImportParams,
+    ),
+    request_body(content = Vec<u8>, content_type = "application/marc",
+        description = "Binary MARC 21 or MARCXML records; may also be sent as the `file` part of a multipart/form-data upload"),
+    responses(
+        (status = 200, description = "What happened to each record", body = ImportReport),
+        (status = 422, description = "The file can't be read", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn import_marc(
+    State(state): State<AppState>,
+    actor: Actor,
+    Query(params): Query<MarcImportParams>,
+    request: Request<Body>,
+) -> Result<Json<ImportReport>> {
+    let data = upload(&state, request).await?;
+    let records = if interchange::marc::is_xml(&data) {
+        interchange::marc::read_xml(&data)?
     } else {
-        Err(StatusCode::SERVICE_UNAVAILABLE)
+        interchange::marc::read(&data)
+    };
+    let report = interchange::import(&state, records, params.dry_run, actor.name()).await?;
+    Ok(Json(report))
+}
+
+/// The file uploaded with a request: the `file` part of a multipart form
+/// (or its first part, if none is called `file`), or else the whole body.
+async fn upload(state: &AppState, request: Request<Body>) -> Result<Bytes> {
+    let unreadable = |e: &dyn std::fmt::Display| Error::from(FieldError::new("file", e));
+    let is_multipart = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    if !is_multipart {
+        return Bytes::from_request(request, state)
+            .await
+            .map_err(|e| unreadable(&e.body_text()));
+    }
+    let mut multipart = Multipart::from_request(request, state)
+        .await
+        .map_err(|e| unreadable(&e.body_text()))?;
+    let mut first = None;
+    while let Some(field) = multipart.next_field().await.map_err(|e| unreadable(&e))? {
+        let is_file = field.name() == Some("file");
+        let data = field.bytes().await.map_err(|e| unreadable(&e))?;
+        if is_file {
+            return Ok(data);
+        }
+        first.get_or_insert(data);
+    }
+    first.ok_or_else(|| FieldError::new("file", "no file was uploaded").into())
+}
+
+/// Query-string parameters accepted by the search endpoint.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchParams {
+    /// Words to search for in titles and authors
+    q: String,
+    /// Maximum number of results to return (default 50)
+    limit: Option<i64>,
+}
+
+/// Full-text search over titles and authors.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Query(params)` - the search text `q`, and an optional result `limit`.
+///
+/// ## Returns
+/// Either an error (422 for a blank query), or a JSON list of matches
+/// ordered by relevance.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/search",
+    tag = "books",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "Matching books, best first", body = [SearchHit]),
+        (status = 422, description = "Blank search text", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn search(
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<SearchHit>>> {
+    if params.q.trim().is_empty() {
+        return Err(FieldError::new("q", "must not be empty").into());
     }
+    let limit = params.limit.unwrap_or(crate::db::DEFAULT_PAGE_SIZE);
+    Ok(Json(search_books(&state, &params.q, limit).await?))
+}
+
+/// Query-string parameters accepted when fetching a single book.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BookParams {
+    /// Return the book as it was at this RFC 3339 time, rebuilt from its
+    /// history, instead of as it is now.
+    as_of: Option<DateTime<Utc>>,
 }
 
 /// Gets a single book.
 ///
 /// ## Arguments
-/// * `Extension(cnn)` - dependency injected by Axum from the database layer.
+/// * `State(state)` - the repository and cache, injected by Axum.
 /// * `Path(id)` - id number, parsed by Axum from the path.
+/// * `conditions` - any `If-None-Match` or `If-Modified-Since` headers.
+/// * `Query(params)` - an optional `as_of` time to look back to.
 ///
 /// ## Returns
-/// Either a 500 status code, or a JSON encoded book.
+/// Either an error (404 if there is no such book, or there was none at
+/// `as_of`), `304 Not Modified` if the client's copy is current, or a JSON
+/// encoded book.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/{id}",
+    tag = "books",
+    params(("id" = i32, Path, description = "Book ID"), BookParams),
+    responses(
+        (status = 200, description = "The book", body = Book,
+            headers(("etag" = String), ("last-modified" = String))),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+    )
+)]
 async fn get_book(
-    Extension(cnn): Extension<SqlitePool>,
+    State(state): State<AppState>,
     Path(id): Path<i32>,
-) -> Result<Json<Book>, StatusCode> {
-    if let Ok(book) = book_by_id(&cnn, id).await {
-        Ok(Json(book))
-    } else {
-        Err(StatusCode::SERVICE_UNAVAILABLE)
-    }
+    conditions: Conditions,
+    Query(params): Query<BookParams>,
+) -> Result<Conditional<Json<Book>>> {
+    let book = match params.as_of {
+        Some(as_of) => book_as_of(&state, id, as_of).await?,
+        None => book_by_id(&state, id).await?,
+    };
+    Ok(conditions.respond(Validators::for_book(&book), Json(book)))
+}
+
+/// Gets the book with an ISBN.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(isbn)` - an ISBN-10 or ISBN-13, with or without hyphens.
+/// * `conditions` - any `If-None-Match` or `If-Modified-Since` headers.
+///
+/// ## Returns
+/// Either an error (422 if the ISBN isn't valid, 404 if no book has it),
+/// `304 Not Modified` if the client's copy is current, or a JSON encoded
+/// book.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/isbn/{isbn}",
+    tag = "books",
+    params(("isbn" = String, Path, description = "ISBN-10 or ISBN-13")),
+    responses(
+        (status = 200, description = "The book", body = Book,
+            headers(("etag" = String), ("last-modified" = String))),
+        (status = 304, description = "The client's copy is current"),
+        (status = 404, description = "No book has this ISBN", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Not a valid ISBN", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn get_book_by_isbn(
+    State(state): State<AppState>,
+    Path(isbn): Path<String>,
+    conditions: Conditions,
+) -> Result<Conditional<Json<Book>>> {
+    let book = book_by_isbn(&state, &isbn).await?;
+    Ok(conditions.respond(Validators::for_book(&book), Json(book)))
+}
+
+/// Gets everything that has happened to a book.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `Path(id)` - id number, parsed by Axum from the path.
+///
+/// ## Returns
+/// Either an error (404 if there has never been such a book), or the
+/// book's changes, oldest first, each with the book before and after.
+#[utoipa::path(
+    get,
+    path = "/api/v1/books/{id}/history",
+    tag = "books",
+    params(("id" = i32, Path, description = "Book ID")),
+    responses(
+        (status = 200, description = "The book's changes, oldest first", body = [BookChange]),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn get_book_history(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<BookChange>>> {
+    Ok(Json(book_history(&state, id).await?))
+}
+
+/// Takes a book back out of the trash.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(id)` - id number of the deleted book, parsed from the path.
+/// * `actor` - who is restoring the book, from the `X-User` header.
+///
+/// ## Returns
+/// Either an error (404 if the book isn't in the trash), or the restored
+/// book.
+#[utoipa::path(
+    post,
+    path = "/api/v1/books/{id}/restore",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("x-user" = Option<String>, Header, description = "Who is restoring the book"),
+    ),
+    responses(
+        (status = 200, description = "The restored book", body = Book),
+        (status = 404, description = "No such book in the trash", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Another book now has its ISBN", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn restore_book(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    actor: Actor,
+) -> Result<Json<Book>> {
+    Ok(Json(
+        crate::db::restore_book(&state, id, actor.name()).await?,
+    ))
+}
+
+/// Files a book under a tag. Tagging a book again changes nothing.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path((id, tag))` - id number of the book and the tag, parsed from the path.
+/// * `actor` - who is tagging the book, from the `X-User` header.
+///
+/// ## Returns
+/// Either an error (404 if there is no such book), or the tagged book.
+#[utoipa::path(
+    put,
+    path = "/api/v1/books/{id}/tags/{tag}",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("tag" = String, Path, description = "The tag, such as a subject or genre"),
+        ("x-user" = Option<String>, Header, description = "Who is tagging the book"),
+    ),
+    responses(
+        (status = 200, description = "The tagged book", body = Book),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid tag", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn tag_book(
+    State(state): State<AppState>,
+    Path((id, tag)): Path<(i32, String)>,
+    actor: Actor,
+) -> Result<Json<Book>> {
+    Ok(Json(
+        crate::db::tag_book(&state, id, &tag, actor.name()).await?,
+    ))
+}
+
+/// Takes a tag off a book.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path((id, tag))` - id number of the book and the tag, parsed from the path.
+/// * `actor` - who is untagging the book, from the `X-User` header.
+///
+/// ## Returns
+/// Either an error (404 if there is no such book, or it doesn't carry the
+/// tag), or the untagged book.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/books/{id}/tags/{tag}",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("tag" = String, Path, description = "The tag to take off"),
+        ("x-user" = Option<String>, Header, description = "Who is untagging the book"),
+    ),
+    responses(
+        (status = 200, description = "The untagged book", body = Book),
+        (status = 404, description = "No such book, or it doesn't carry the tag", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn untag_book(
+    State(state): State<AppState>,
+    Path((id, tag)): Path<(i32, String)>,
+    actor: Actor,
+) -> Result<Json<Book>> {
+    Ok(Json(
+        crate::db::untag_book(&state, id, &tag, actor.name()).await?,
+    ))
+}
+
+/// Create a book.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `OriginalUri(uri)` - the request URI, used to build the `Location` header.
+/// * `actor` - who is adding the book, from the `X-User` header.
+/// * A Json-encoded `NewBook` extracted from the post body.
+///
+/// ## Returns
+/// Either an error (409 if another book has the same ISBN), or
+/// `201 Created` with a `Location` header and the new book.
+#[utoipa::path(
+    post,
+    path = "/api/v1/books",
+    tag = "books",
+    params(("x-user" = Option<String>, Header, description = "Who is adding the book")),
+    request_body = NewBook,
+    responses(
+        (status = 201, description = "The created book", body = Book,
+            headers(("location" = String, description = "URL of the new book"))),
+        (status = 409, description = "Another book has this ISBN", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn create_book(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    actor: Actor,
+    extract::Json(book): extract::Json<NewBook>,
+) -> Result<impl IntoResponse> {
+    let book = crate::db::add_book(&state, &book, actor.name()).await?;
+    let location = format!("{}/{}", uri.path().trim_end_matches('/'), book.id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(book),
+    ))
 }
 
-/// Add a book to the database.
+/// Add a book to the database. Superseded by `POST /books`, which returns
+/// the whole book; this returns only the new ID.
 ///
 /// ## Arguments
-/// * `Extension(cnn)` - dependency injected by Axum from the database layer.
-/// * A Json-encoded book extracted from the post body.
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `actor` - who is adding the book, from the `X-User` header.
+/// * A Json-encoded book extracted from the post body. Any `id` is ignored.
 async fn add_book(
-    Extension(cnn): Extension<SqlitePool>,
-    extract::Json(book): extract::Json<Book>,
-) -> Result<Json<i32>, StatusCode> {
-    if let Ok(new_id) = crate::db::add_book(&cnn, &book.title, &book.author).await {
-        Ok(Json(new_id))
-    } else {
-        Err(StatusCode::SERVICE_UNAVAILABLE)
-    }
+    State(state): State<AppState>,
+    actor: Actor,
+    extract::Json(book): extract::Json<NewBook>,
+) -> Result<Json<i32>> {
+    let book = crate::db::add_book(&state, &book, actor.name()).await?;
+    Ok(Json(book.id))
+}
+
+/// Replace a book's title and author.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(id)` - id number of the book to replace, parsed from the path.
+/// * `conditions` - the `If-Match` header, if any.
+/// * `actor` - who is making the change, from the `X-User` header.
+/// * A Json-encoded `BookUpdate` holding the new representation.
+///
+/// ## Returns
+/// Either an error, or the updated book. The client must say which version
+/// it is replacing, with `If-Match` or the body's `version`; if that isn't
+/// the current version the update fails with 412 or 409 respectively, and
+/// the current book is returned in the problem's `current` field.
+#[utoipa::path(
+    put,
+    path = "/api/v1/books/{id}",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("if-match" = Option<String>, Header, description = "The book's current ETag"),
+        ("x-user" = Option<String>, Header, description = "Who is making the change"),
+    ),
+    request_body = BookUpdate,
+    responses(
+        (status = 200, description = "The updated book", body = Book),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The body's version is out of date, or another book has the ISBN", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The If-Match ETag is out of date", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "Neither If-Match nor a version was given", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn replace_book(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    conditions: Conditions,
+    actor: Actor,
+    extract::Json(update): extract::Json<BookUpdate>,
+) -> Result<Json<Book>> {
+    let state = &state;
+    let book = conditions
+        .guard_write(state, id, update.version, |version| async move {
+            let update = BookUpdate {
+                version: Some(version),
+                ..update
+            };
+            crate::db::update_book(state, id, &update, actor.name()).await
+        })
+        .await?;
+    Ok(Json(book))
+}
+
+/// Change some of a book's fields.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(id)` - id number of the book to change, parsed from the path.
+/// * `conditions` - the `If-Match` header, if any.
+/// * `actor` - who is making the change, from the `X-User` header.
+/// * A Json-encoded `BookPatch`; omitted fields are left alone.
+///
+/// ## Returns
+/// Either an error, or the updated book. Versions are checked as for
+/// `replace_book`.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/books/{id}",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("if-match" = Option<String>, Header, description = "The book's current ETag"),
+        ("x-user" = Option<String>, Header, description = "Who is making the change"),
+    ),
+    request_body = BookPatch,
+    responses(
+        (status = 200, description = "The updated book", body = Book),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The body's version is out of date, or another book has the ISBN", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The If-Match ETag is out of date", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "Neither If-Match nor a version was given", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn patch_book(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    conditions: Conditions,
+    actor: Actor,
+    extract::Json(patch): extract::Json<BookPatch>,
+) -> Result<Json<Book>> {
+    let state = &state;
+    let book = conditions
+        .guard_write(state, id, patch.version, |version| async move {
+            let patch = BookPatch {
+                version: Some(version),
+                ..patch
+            };
+            crate::db::patch_book(state, id, &patch, actor.name()).await
+        })
+        .await?;
+    Ok(Json(book))
+}
+
+/// The body accepted by the legacy `/edit` route: a whole book, with an
+/// optional version.
+#[derive(Debug, Deserialize)]
+struct LegacyEdit {
+    id: i32,
+    title: String,
+    author: String,
+    #[serde(default)]
+    version: Option<i64>,
 }
 
-/// Update a book with a patch request
+/// Update a book with a put request. Superseded by `PUT /books/:id`.
+/// The legacy body has no ISBN, so the book's ISBN is left alone.
 ///
 /// ## Arguments
-/// * `Extension(cnn)` - dependency injected by Axum from the database layer.
-/// * `book` - JSON encoded book to update, from the patch body.
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `conditions` - the `If-Match` header, if any.
+/// * `actor` - who is making the change, from the `X-User` header.
+/// * `book` - JSON encoded book to update, including its `id`.
+///
+/// ## Returns
+/// Either an error, or `200 OK`. Versions are checked as for
+/// `replace_book`.
 async fn update_book(
-    Extension(cnn): Extension<SqlitePool>,
-    extract::Json(book): extract::Json<Book>,
-) -> StatusCode {
-    if crate::db::update_book(&cnn, &book).await.is_ok() {
-        StatusCode::OK
-    } else {
-        StatusCode::SERVICE_UNAVAILABLE
-    }
+    State(state): State<AppState>,
+    conditions: Conditions,
+    actor: Actor,
+    extract::Json(book): extract::Json<LegacyEdit>,
+) -> Result<StatusCode> {
+    let state = &state;
+    conditions
+        .guard_write(state, book.id, book.version, |version| async move {
+            let patch = BookPatch {
+                title: Some(book.title),
+                author: Some(book.author),
+                version: Some(version),
+                ..BookPatch::default()
+            };
+            crate::db::patch_book(state, book.id, &patch, actor.name()).await
+        })
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Query-string parameters accepted when deleting a book.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteParams {
+    /// The version being deleted. May be left out when sending `If-Match`.
+    version: Option<i64>,
+}
+
+/// Move a book to the trash
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(id)` - id number of the book to delete, parsed from the path.
+/// * `conditions` - the `If-Match` header, if any.
+/// * `actor` - who is deleting the book, from the `X-User` header.
+/// * `Query(params)` - the `version` being deleted, if `If-Match` isn't sent.
+///
+/// ## Returns
+/// Either an error, or `204 No Content`. Versions are checked as for
+/// `replace_book`.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/books/{id}",
+    tag = "books",
+    params(
+        ("id" = i32, Path, description = "Book ID"),
+        ("if-match" = Option<String>, Header, description = "The book's current ETag"),
+        ("x-user" = Option<String>, Header, description = "Who is deleting the book"),
+        DeleteParams,
+    ),
+    responses(
+        (status = 204, description = "The book was moved to the trash"),
+        (status = 404, description = "No such book", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The version is out of date", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The If-Match ETag is out of date", body = Problem, content_type = "application/problem+json"),
+        (status = 428, description = "Neither If-Match nor a version was given", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn remove_book(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    conditions: Conditions,
+    actor: Actor,
+    Query(params): Query<DeleteParams>,
+) -> Result<StatusCode> {
+    let state = &state;
+    conditions
+        .guard_write(state, id, params.version, |version| async move {
+            crate::db::delete_book(state, id, Some(version), actor.name()).await
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
-/// Delete a book
+/// Delete a book. Superseded by `DELETE /books/:id`.
 ///
 /// ## Arguments
-/// * `Extension(cnn)` - dependency injected by Axum from the database layer.
+/// * `State(state)` - the repository and cache, injected by Axum.
 /// * `id` of the book to delete, extracted from the URL of the delete call.
-async fn delete_book(Extension(cnn): Extension<SqlitePool>, Path(id): Path<i32>) -> StatusCode {
-    if crate::db::delete_book(&cnn, id).await.is_ok() {
-        StatusCode::OK
-    } else {
-        StatusCode::SERVICE_UNAVAILABLE
-    }
+/// * `conditions` - the `If-Match` header, if any.
+/// * `actor` - who is deleting the book, from the `X-User` header.
+/// * `Query(params)` - the `version` being deleted, if `If-Match` isn't sent.
+///
+/// ## Returns
+/// Either an error, or `200 OK`. Versions are checked as for
+/// `replace_book`.
+async fn delete_book(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    conditions: Conditions,
+    actor: Actor,
+    Query(params): Query<DeleteParams>,
+) -> Result<StatusCode> {
+    let state = &state;
+    conditions
+        .guard_write(state, id, params.version, |version| async move {
+            crate::db::delete_book(state, id, Some(version), actor.name()).await
+        })
+        .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Query-string parameters accepted when listing authors.
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuthorListParams {
+    /// Maximum number of authors to return (1-500, default 50)
+    limit: Option<i64>,
+    /// Number of authors to skip
+    offset: Option<i64>,
+}
+
+/// Response envelope for a page of authors.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuthorList {
+    /// The authors on this page
+    pub items: Vec<Author>,
+    /// Total number of authors
+    pub total: i64,
+    /// The page size that was applied
+    pub limit: i64,
+    /// The offset that was applied
+    pub offset: i64,
+}
+
+/// Lists the authors, by name.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `Query(params)` - optional `limit` and `offset` parameters.
+///
+/// ## Returns
+/// Either an error, or a JSON page of authors.
+#[utoipa::path(
+    get,
+    path = "/api/v1/authors",
+    tag = "authors",
+    params(AuthorListParams),
+    responses(
+        (status = 200, description = "A page of authors", body = AuthorList),
+        (status = 400, description = "Invalid query parameters"),
+    )
+)]
+async fn get_authors(
+    State(state): State<AppState>,
+    Query(params): Query<AuthorListParams>,
+) -> Result<Json<AuthorList>> {
+    let defaults = BookQuery::default();
+    let limit = params
+        .limit
+        .unwrap_or(defaults.limit)
+        .clamp(1, crate::db::MAX_PAGE_SIZE);
+    let offset = params.offset.unwrap_or_default().max(0);
+    let page = authors(&state, limit, offset).await?;
+    Ok(Json(AuthorList {
+        items: page.items,
+        total: page.total,
+        limit,
+        offset,
+    }))
+}
+
+/// Gets a single author.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `Path(id)` - id number, parsed by Axum from the path.
+///
+/// ## Returns
+/// Either an error (404 if there is no such author), or a JSON encoded
+/// author.
+#[utoipa::path(
+    get,
+    path = "/api/v1/authors/{id}",
+    tag = "authors",
+    params(("id" = i32, Path, description = "Author ID")),
+    responses(
+        (status = 200, description = "The author", body = Author),
+        (status = 404, description = "No such author", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn get_author(State(state): State<AppState>, Path(id): Path<i32>) -> Result<Json<Author>> {
+    Ok(Json(author_by_id(&state, id).await?))
+}
+
+/// Lists the books that credit an author, in any role.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `OriginalUri(uri)` - the request URI, used to build paging links.
+/// * `Path(id)` - id number of the author, parsed from the path.
+/// * `ListQuery(query)` - the paging, sorting and filter parameters.
+///
+/// ## Returns
+/// Either an error (404 if there is no such author), or a JSON page of
+/// books with paging links.
+#[utoipa::path(
+    get,
+    path = "/api/v1/authors/{id}/books",
+    tag = "authors",
+    params(("id" = i32, Path, description = "Author ID"), ListParams),
+    responses(
+        (status = 200, description = "A page of the author's books", body = BookList),
+        (status = 400, description = "Invalid query parameters"),
+        (status = 404, description = "No such author", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn get_author_books(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path(id): Path<i32>,
+    ListQuery(query): ListQuery,
+) -> Result<Json<BookList>> {
+    let page = books_by_author(&state, id, &query).await?;
+    Ok(Json(BookList::new(uri.path(), &query, page)))
+}
+
+/// Adds an author, who can then be credited on books by ID.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `OriginalUri(uri)` - the request URI, used to build the `Location`.
+/// * A Json-encoded `NewAuthor`, extracted from the post body.
+///
+/// ## Returns
+/// Either an error (409 if another author has the name), or
+/// `201 Created` with a `Location` header and the new author.
+#[utoipa::path(
+    post,
+    path = "/api/v1/authors",
+    tag = "authors",
+    request_body = NewAuthor,
+    responses(
+        (status = 201, description = "The created author", body = Author,
+            headers(("location" = String, description = "URL of the new author"))),
+        (status = 409, description = "Another author has this name", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn create_author(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    extract::Json(author): extract::Json<NewAuthor>,
+) -> Result<impl IntoResponse> {
+    let author = crate::db::add_author(&state, &author).await?;
+    let location = format!("{}/{}", uri.path().trim_end_matches('/'), author.id);
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(author),
+    ))
+}
+
+/// Renames an author. The `author` line of every book crediting them
+/// changes too, as an update by `actor`.
+///
+/// ## Arguments
+/// * `State(state)` - the repository and cache, injected by Axum.
+/// * `Path(id)` - id number of the author, parsed from the path.
+/// * `actor` - who is making the change, from the `X-User` header.
+/// * A Json-encoded `NewAuthor` holding the new name.
+///
+/// ## Returns
+/// Either an error (404 if there is no such author, 409 if another author
+/// has the name), or the renamed author.
+#[utoipa::path(
+    put,
+    path = "/api/v1/authors/{id}",
+    tag = "authors",
+    params(
+        ("id" = i32, Path, description = "Author ID"),
+        ("x-user" = Option<String>, Header, description = "Who is making the change"),
+    ),
+    request_body = NewAuthor,
+    responses(
+        (status = 200, description = "The renamed author", body = Author),
+        (status = 404, description = "No such author", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Another author has this name", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn rename_author(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    actor: Actor,
+    extract::Json(author): extract::Json<NewAuthor>,
+) -> Result<Json<Author>> {
+    Ok(Json(
+        crate::db::rename_author(&state, id, &author, actor.name()).await?,
+    ))
+}
+
+/// Removes an author who isn't credited on any book.
+///
+/// ## Arguments
+/// * `State(state)` - the repository, injected by Axum.
+/// * `Path(id)` - id number of the author, parsed from the path.
+///
+/// ## Returns
+/// Either an error (404 if there is no such author, 409 if a book, even
+/// one in the trash, still credits them), or `204 No Content`.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/authors/{id}",
+    tag = "authors",
+    params(("id" = i32, Path, description = "Author ID")),
+    responses(
+        (status = 204, description = "The author was removed"),
+        (status = 404, description = "No such author", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "A book still credits the author", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+async fn remove_author(State(state): State<AppState>, Path(id): Path<i32>) -> Result<StatusCode> {
+    crate::db::delete_author(&state, id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::interchange::ImportStatus;
     use axum_test_helper::TestClient;
 
     async fn setup_tests() -> TestClient {
-        dotenv::dotenv().ok();
-        let connection_pool = crate::init_db().await.unwrap();
-        let app = crate::router(connection_pool);
+        let app = crate::router(crate::state::test_state().await);
         TestClient::new(app)
     }
 
     #[tokio::test]
     async fn get_all_books() {
         let client = setup_tests().await;
-        let res = client.get("/books").send().await;
+        let res = client.get("/api/v1/books").send().await;
         assert_eq!(res.status(), StatusCode::OK);
-        let books: Vec<Book> = res.json().await;
-        assert!(!books.is_empty());
+        let books: BookList = res.json().await;
+        assert!(!books.items.is_empty());
+        assert!(books.next.is_none());
+        assert!(books.prev.is_none());
     }
 
     #[tokio::test]
-    async fn get_one_book() {
+    async fn get_paged_books() {
         let client = setup_tests().await;
-        let res = client.get("/books/1").send().await;
+        let res = client
+            .get("/api/v1/books?limit=1&offset=0&sort=id&order=desc")
+            .send()
+            .await;
         assert_eq!(res.status(), StatusCode::OK);
-        let book: Book = res.json().await;
-        assert_eq!(book.id, 1)
+        let books: BookList = res.json().await;
+        assert_eq!(books.items.len(), 1);
+        assert!(books.total >= 2);
+        assert_eq!(
+            books.next.as_deref(),
+            Some("/api/v1/books?limit=1&offset=1&sort=id&order=desc")
+        );
+        assert!(books.prev.is_none());
+
+        let res = client.get("/api/v1/books?sort=publisher").send().await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
     }
 
     #[tokio::test]
-    async fn add_book() {
+    async fn updated_since() {
         let client = setup_tests().await;
-        let new_book = Book {
-            id: -1,
-            title: "Test POST Book".to_string(),
-            author: "Test POST Author".to_string(),
+        let new_book = NewBook {
+            title: "Synced book".to_string(),
+            author: "Sync, Ann".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
         };
-        let res = client.post("/books/add").json(&new_book).send().await;
-        assert_eq!(res.status(), StatusCode::OK);
-        let new_id: i32 = res.json().await;
-        assert!(new_id > 0);
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let res = client
+            .post("/api/v1/books")
+            .header(crate::actor::USER_HEADER, "ann")
+            .json(&new_book)
+            .send()
+            .await;
+        let created: Book = res.json().await;
+        assert_eq!(created.created_by, "ann");
 
-        let test_book = client.get(&format!("/books/{new_id}")).send().await;
-        assert_eq!(test_book.status(), StatusCode::OK);
-        let test_book: Book = test_book.json().await;
-        assert_eq!(new_id, test_book.id);
-        assert_eq!(new_book.title, test_book.title);
-        assert_eq!(new_book.author, test_book.author);
-    }
+        let since = created
+            .updated_at
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        let res = client
+            .get(&format!("/api/v1/books?limit=1&updated_since={since}"))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 1);
+        assert_eq!(books.items[0].id, created.id);
 
-    #[tokio::test]
+        let res = client
+            .get("/api/v1/books?updated_since=yesterday")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // Anonymous changes are recorded as such
+        let patch = BookPatch {
+            title: Some("Resynced book".to_string()),
+            version: Some(created.version),
+            ..BookPatch::default()
+        };
+        let path = format!("/api/v1/books/{}", created.id);
+        let patched: Book = client.patch(&path).json(&patch).send().await.json().await;
+        assert_eq!(patched.updated_by, crate::actor::ANONYMOUS);
+        assert_eq!(patched.created_by, "ann");
+    }
+
+    #[tokio::test]
+    async fn edition_filters() {
+        let client = setup_tests().await;
+        let new_book = NewBook {
+            title: "Hands-on Rust".to_string(),
+            author: "Wolverson, Herbert".to_string(),
+            publisher: Some("Pragmatic Bookshelf".to_string()),
+            published_on: NaiveDate::from_ymd_opt(2021, 7, 20),
+            page_count: Some(342),
+            language: Some("en".to_string()),
+            ..NewBook::default()
+        };
+        let res = client.post("/api/v1/books").json(&new_book).send().await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: Book = res.json().await;
+        assert_eq!(created.publisher.as_deref(), Some("Pragmatic Bookshelf"));
+
+        let res = client
+            .get("/api/v1/books?limit=1&publisher=pragmatic%20bookshelf&min_pages=300")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 1);
+        assert_eq!(books.items[0].id, created.id);
+        let res = client
+            .get("/api/v1/books?limit=1&published_from=2021-01-01&language=fr")
+            .send()
+            .await;
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 0);
+
+        // The paging links keep the filters
+        client.post("/api/v1/books").json(&new_book).send().await;
+        let res = client
+            .get("/api/v1/books?limit=1&publisher=Pragmatic+Bookshelf")
+            .send()
+            .await;
+        let books: BookList = res.json().await;
+        assert_eq!(
+            books.next.as_deref(),
+            Some(
+                "/api/v1/books?limit=1&offset=1&sort=title&order=asc&publisher=Pragmatic+Bookshelf"
+            )
+        );
+
+        let res = client
+            .get("/api/v1/books?published_from=last+year")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn tags_and_facets() {
+        let client = setup_tests().await;
+        let res = client.put("/api/v1/books/1/tags/Rust").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.tags, vec!["rust"]);
+        for (id, tag) in [(1, "games"), (2, "rust"), (2, "science%20fiction")] {
+            let res = client
+                .put(&format!("/api/v1/books/{id}/tags/{tag}"))
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = client
+            .get("/api/v1/books?limit=1&tag=rust&tag=games")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 1);
+        assert_eq!(books.items[0].id, 1);
+        let res = client.get("/api/v1/books?limit=1&tag=RUST").send().await;
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 2);
+        assert_eq!(
+            books.next.as_deref(),
+            Some("/api/v1/books?limit=1&offset=1&sort=title&order=asc&tag=rust")
+        );
+        let facets = books.facets.unwrap();
+        let counts: Vec<(&str, i64)> = facets
+            .iter()
+            .map(|facet| (facet.tag.as_str(), facet.count))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("rust", 2), ("games", 1), ("science fiction", 1)]
+        );
+
+        // Only the main listing has facets
+        let res = client.get("/api/v1/books/trash").send().await;
+        let books: BookList = res.json().await;
+        assert!(books.facets.is_none());
+
+        let res = client.delete("/api/v1/books/1/tags/rust").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.tags, vec!["games"]);
+        let res = client.delete("/api/v1/books/1/tags/rust").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn filter_expressions() {
+        let client = setup_tests().await;
+        let res = client
+            .get("/api/v1/books?limit=1&filter=title%20contains%20%22rust%22%20or%20id%20%3E%200")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 2);
+        assert_eq!(
+            books.next.as_deref(),
+            Some(
+                "/api/v1/books?limit=1&offset=1&sort=title&order=asc\
+                 &filter=title+contains+%22rust%22+or+id+%3E+0"
+            )
+        );
+        let res = client
+            .get("/api/v1/books?filter=title+contains+%22rust%22+or+id+%3E+0&offset=1")
+            .send()
+            .await;
+        let books: BookList = res.json().await;
+        assert_eq!(books.items.len(), 1);
+
+        let res = client
+            .get("/api/v1/books?filter=title%20contains%2042")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let problem: Problem = res.json().await;
+        assert_eq!(
+            problem.detail,
+            "invalid filter, at character 16: title is compared with a quoted string, \
+             such as \"Rust\""
+        );
+        let res = client
+            .get("/api/v1/books/trash?filter=password%20%3D%20%22x%22")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn book_history() {
+        let client = setup_tests().await;
+        let path = "/api/v1/books/1";
+        let before: Book = client.get(path).send().await.json().await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let patch = BookPatch {
+            title: Some("Hands-on Rust, revised".to_string()),
+            version: Some(before.version),
+            ..BookPatch::default()
+        };
+        let res = client
+            .patch(path)
+            .header(crate::actor::USER_HEADER, "ann")
+            .json(&patch)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get("/api/v1/books/1/history").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let history: Vec<BookChange> = res.json().await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].actor, "ann");
+        assert_eq!(history[1].before.as_ref().unwrap().title, before.title);
+
+        let as_of = before
+            .updated_at
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        let res = client.get(&format!("{path}?as_of={as_of}")).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let old: Book = res.json().await;
+        assert_eq!(old.title, before.title);
+        assert_eq!(old.version, before.version);
+
+        let res = client
+            .get(&format!("{path}?as_of=1970-01-01T00:00:00Z"))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.get("/api/v1/books/9999/history").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn purge_needs_admin_token() {
+        let state = crate::state::test_state()
+            .await
+            .with_trash(crate::config::TrashConfig {
+                retention: std::time::Duration::ZERO,
+                admin_token: Some("s3cret".to_string()),
+            });
+        let client = TestClient::new(crate::router(state));
+        let res = client.delete("/api/v1/books/2?version=1").send().await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        for auth in [None, Some("Bearer guess"), Some("Basic czNjcmV0")] {
+            let mut req = client
+                .delete("/api/v1/books/trash")
+                .header(crate::actor::USER_HEADER, "admin");
+            if let Some(auth) = auth {
+                req = req.header(header::AUTHORIZATION, auth);
+            }
+            assert_eq!(req.send().await.status(), StatusCode::FORBIDDEN);
+        }
+
+        let res = client
+            .delete("/api/v1/books/trash")
+            .header(header::AUTHORIZATION, "Bearer s3cret")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: PurgeReport = res.json().await;
+        assert_eq!(report.purged, 1);
+    }
+
+    #[tokio::test]
+    async fn trash() {
+        let client = setup_tests().await;
+        let res = client.delete("/api/v1/books/2?version=1").send().await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get("/api/v1/books/2").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = client.get("/api/v1/books/trash").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let binned: BookList = res.json().await;
+        assert_eq!(binned.total, 1);
+        assert_eq!(binned.items[0].id, 2);
+
+        // Nobody may purge by default, whatever they claim to be
+        let res = client
+            .delete("/api/v1/books/trash")
+            .header(crate::actor::USER_HEADER, "admin")
+            .header(header::AUTHORIZATION, "Bearer anything")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = client.post("/api/v1/books/2/restore").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let restored: Book = res.json().await;
+        assert_eq!(restored.version, 3);
+        let res = client.post("/api/v1/books/2/restore").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.get("/api/v1/books/2").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn batch() {
+        let client = setup_tests().await;
+        let body = serde_json::json!({
+            "operations": [
+                {"op": "create", "book": {"title": "Batched", "author": "Batch, Ann"}},
+                {"op": "update", "id": 1, "book": {"title": "Renamed", "author": "Wolverson, Herbert", "version": 1}},
+                {"op": "delete", "id": 2},
+            ]
+        });
+        let res = client.post("/api/v1/books/batch").json(&body).send().await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let response: BatchResponse = res.json().await;
+        assert!(!response.committed);
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0].index, 2);
+        assert_eq!(
+            response.results[0].error.as_ref().unwrap().errors[0].field,
+            "version"
+        );
+
+        let body = serde_json::json!({
+            "mode": "partial",
+            "operations": [
+                {"op": "create", "book": {"title": "Batched", "author": "Batch, Ann"}},
+                {"op": "update", "id": 1, "book": {"title": "Renamed", "author": "Wolverson, Herbert", "version": 1}},
+                {"op": "delete", "id": 2},
+            ]
+        });
+        let res = client
+            .post("/api/v1/books/batch")
+            .header(crate::actor::USER_HEADER, "importer")
+            .json(&body)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let response: BatchResponse = res.json().await;
+        assert!(response.committed);
+        let statuses: Vec<u16> = response.results.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, vec![201, 200, 422]);
+        assert_eq!(
+            response.results[0].book.as_ref().unwrap().created_by,
+            "importer"
+        );
+        let renamed: Book = client.get("/api/v1/books/1").send().await.json().await;
+        assert_eq!(renamed.title, "Renamed");
+
+        let res = client
+            .post("/api/v1/books/batch")
+            .json(&serde_json::json!({"operations": [{"op": "shred", "id": 1}]}))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn csv_export() {
+        let state = crate::state::test_state().await;
+        let client = TestClient::new(crate::router(state.clone()));
+        let res = client.get("/api/v1/books/export.csv").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/csv; charset=utf-8");
+        let csv = res.text().await;
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("id,title,author,isbn,publisher,published_on,edition,page_count,language,series,series_volume,version,created_at,created_by,updated_at,updated_by")
+        );
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("1,Hands-on Rust,\"Wolverson, Herbert\",,,,,,,,,1,"));
+        assert_eq!(lines.count(), 1);
+        // Exports read past the cache
+        assert_eq!(state.cache.stats().misses, 0);
+    }
+
+    #[tokio::test]
+    async fn csv_import() {
+        let client = setup_tests().await;
+        let csv = "Name,Writer\n\
+                   Zymurgy Handbook,\"Brewer, Ann\"\n\
+                   hands-on rust,\"wolverson, herbert\"\n\
+                   ,\"Nobody, No\"\n\
+                   Zymurgy handbook,\"Brewer, Ann\"\n";
+
+        // Columns are found by name
+        let res = client
+            .post("/api/v1/books/import?dry_run=true")
+            .header("content-type", "text/csv")
+            .body(csv)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let import = "/api/v1/books/import?title_column=name&author_column=writer";
+        let res = client
+            .post(&format!("{import}&dry_run=true"))
+            .header("content-type", "text/csv")
+            .body(csv)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: ImportReport = res.json().await;
+        assert!(report.dry_run);
+        assert_eq!((report.inserted, report.skipped, report.failed), (1, 2, 1));
+        let statuses: Vec<_> = report.rows.iter().map(|r| (r.row, r.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (2, ImportStatus::Inserted),
+                (3, ImportStatus::Skipped),
+                (4, ImportStatus::Failed),
+                (5, ImportStatus::Skipped)
+            ]
+        );
+        assert_eq!(report.rows[4 - 2].errors[0].field, "title");
+        let books: BookList = client.get("/api/v1/books").send().await.json().await;
+        assert_eq!(books.total, 2);
+
+        // The same file as a multipart upload, for real this time
+        let body = format!(
+            "--XyZ\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"books.csv\"\r\n\
+             Content-Type: text/csv\r\n\r\n\
+             {csv}\r\n\
+             --XyZ--\r\n"
+        );
+        let res = client
+            .post(import)
+            .header(crate::actor::USER_HEADER, "librarian")
+            .header("content-type", "multipart/form-data; boundary=XyZ")
+            .body(body)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: ImportReport = res.json().await;
+        assert!(!report.dry_run);
+        assert_eq!(report.inserted, 1);
+        let added = report.rows[0].book.as_ref().unwrap();
+        assert_eq!(added.title, "Zymurgy Handbook");
+        assert_eq!(added.created_by, "librarian");
+
+        // Importing again finds nothing new
+        let res = client
+            .post(import)
+            .header("content-type", "text/csv")
+            .body(csv)
+            .send()
+            .await;
+        let report: ImportReport = res.json().await;
+        assert_eq!((report.inserted, report.skipped, report.failed), (0, 3, 1));
+    }
+
+    #[tokio::test]
+    async fn marc_export_and_import() {
+        let client = setup_tests().await;
+        let res = client.get("/api/v1/books/export.mrc").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/marc");
+        let marc = res.bytes().await;
+        assert_eq!(marc.iter().filter(|&&b| b == 0x1D).count(), 2);
+
+        let res = client.get("/api/v1/books/export.xml").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let xml = res.text().await;
+        assert!(xml.contains("<subfield code=\"a\">Wolverson, Herbert</subfield>"));
+        assert!(xml.trim_end().ends_with("</collection>"));
+
+        // Both exports import as duplicates of what's already there
+        for body in [marc.to_vec(), xml.into_bytes()] {
+            let res = client
+                .post("/api/v1/books/import/marc?dry_run=true")
+                .body(body)
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let report: ImportReport = res.json().await;
+            assert_eq!((report.inserted, report.skipped, report.failed), (0, 2, 0));
+        }
+
+        let xml = r#"<collection xmlns="http://www.loc.gov/MARC21/slim"><record>
+            <leader>00000nam a2200000 i 4500</leader>
+            <datafield tag="100" ind1="1" ind2=" "><subfield code="a">Brewer, Ann,</subfield></datafield>
+            <datafield tag="245" ind1="1" ind2="0"><subfield code="a">Zymurgy handbook /</subfield></datafield>
+        </record></collection>"#;
+        let res = client
+            .post("/api/v1/books/import/marc")
+            .header("content-type", "application/marcxml+xml")
+            .body(xml)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let report: ImportReport = res.json().await;
+        let added = report.rows[0].book.as_ref().unwrap();
+        assert_eq!(
+            (added.title.as_str(), added.author.as_str()),
+            ("Zymurgy handbook", "Brewer, Ann")
+        );
+
+        let res = client
+            .post("/api/v1/books/import/marc")
+            .body("<collection><record></collection>")
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn get_one_book() {
+        let client = setup_tests().await;
+        let res = client.get("/api/v1/books/1").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.id, 1)
+    }
+
+    #[tokio::test]
+    async fn get_missing_book() {
+        let client = setup_tests().await;
+        let res = client.get("/api/v1/books/9999").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()["content-type"], "application/problem+json");
+        let problem: Problem = res.json().await;
+        assert_eq!(problem.status, 404);
+        assert_eq!(problem.title, "Not Found");
+        assert_eq!(problem.detail, "book 9999 not found");
+    }
+
+    #[tokio::test]
+    async fn conditional_get() {
+        let client = setup_tests().await;
+        for path in ["/api/v1/books?limit=1", "/api/v1/books/1"] {
+            let res = client.get(path).send().await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let etag = res.headers()[header::ETAG].clone();
+            let last_modified = res.headers()[header::LAST_MODIFIED].clone();
+
+            let res = client
+                .get(path)
+                .header(header::IF_NONE_MATCH, etag.clone())
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers()[header::ETAG], etag);
+            assert!(res.text().await.is_empty());
+
+            let res = client
+                .get(path)
+                .header(header::IF_MODIFIED_SINCE, last_modified)
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+            // A change to the book means a new representation
+            let patch = BookPatch {
+                title: Some(format!("Retitled for {path}")),
+                ..BookPatch::default()
+            };
+            let res = client
+                .patch("/api/v1/books/1")
+                .header(header::IF_MATCH, current_etag(&client, 1).await)
+                .json(&patch)
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = client
+                .get(path)
+                .header(header::IF_NONE_MATCH, etag.clone())
+                .send()
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_ne!(res.headers()[header::ETAG], etag);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_books() {
+        let client = setup_tests().await;
+        let res = client.get("/api/v1/books/search?q=teasers").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let hits: Vec<SearchHit> = res.json().await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].book.id, 2);
+
+        let res = client.get("/api/v1/books/search?q=").send().await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn authors() {
+        let client = setup_tests().await;
+        let res = client
+            .post("/api/v1/authors")
+            .json(&serde_json::json!({ "name": "Pratchett,Terry" }))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()["location"].to_str().unwrap().to_string();
+        let author: Author = res.json().await;
+        assert_eq!(location, format!("/api/v1/authors/{}", author.id));
+        assert_eq!(author.name, "Pratchett, Terry");
+        let res = client
+            .post("/api/v1/authors")
+            .json(&serde_json::json!({ "name": "pratchett, terry" }))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = client
+            .post("/api/v1/books")
+            .json(&serde_json::json!({
+                "title": "Good Omens",
+                "authors": [
+                    { "id": author.id },
+                    { "name": "Gaiman, Neil" },
+                ],
+            }))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let book: Book = res.json().await;
+        assert_eq!(book.author, "Pratchett, Terry; Gaiman, Neil");
+        assert_eq!(book.authors[0].id, author.id);
+
+        let res = client
+            .get(&format!("/api/v1/authors/{}/books", author.id))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let books: BookList = res.json().await;
+        assert_eq!(books.total, 1);
+        assert_eq!(books.items[0].id, book.id);
+
+        let res = client
+            .put(&format!("/api/v1/authors/{}", author.id))
+            .json(&serde_json::json!({ "name": "Pratchett, Sir Terry" }))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .get(&format!("/api/v1/books/{}", book.id))
+            .send()
+            .await;
+        let renamed: Book = res.json().await;
+        assert_eq!(renamed.author, "Pratchett, Sir Terry; Gaiman, Neil");
+
+        let res = client
+            .delete(&format!("/api/v1/authors/{}", author.id))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = client.get("/api/v1/authors?limit=500").send().await;
+        let list: AuthorList = res.json().await;
+        assert!(list.items.iter().any(|a| a.name == "Gaiman, Neil"));
+        let res = client.delete("/api/v1/authors/9999").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn isbn_lookup() {
+        let client = setup_tests().await;
+        let new_book = NewBook {
+            title: "Hands-on Rust".to_string(),
+            author: "Wolverson, Herbert".to_string(),
+            authors: Vec::new(),
+            isbn: Some("978-1-68050-816-1".to_string()),
+            ..NewBook::default()
+        };
+        let res = client.post("/api/v1/books").json(&new_book).send().await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: Book = res.json().await;
+        assert_eq!(created.isbn10.as_deref(), Some("1680508164"));
+
+        let res = client.get("/api/v1/books/isbn/1-68050-816-4").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key("etag"));
+        let found: Book = res.json().await;
+        assert_eq!(found.id, created.id);
+
+        let res = client.get("/api/v1/books/isbn/9791090636071").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.get("/api/v1/books/isbn/not-an-isbn").send().await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Problem = res.json().await;
+        assert_eq!(problem.errors[0].field, "isbn");
+
+        // The same ISBN written as an ISBN-10 is still a duplicate
+        let duplicate = NewBook {
+            isbn: Some("1680508164".to_string()),
+            ..new_book
+        };
+        let res = client.post("/api/v1/books").json(&duplicate).send().await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let problem: Problem = res.json().await;
+        assert_eq!(
+            problem.detail,
+            "another book already has ISBN 9781680508161"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_book() {
+        let client = setup_tests().await;
+        let new_book = NewBook {
+            title: "Test POST Book".to_string(),
+            author: "Author, Test POST".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
+        };
+        let res = client.post("/api/v1/books").json(&new_book).send().await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let created: Book = res.json().await;
+        assert!(created.id > 0);
+        assert_eq!(location, format!("/api/v1/books/{}", created.id));
+        assert_eq!(new_book.title, created.title);
+
+        let test_book = client.get(&location).send().await;
+        assert_eq!(test_book.status(), StatusCode::OK);
+        let test_book: Book = test_book.json().await;
+        assert_eq!(created.id, test_book.id);
+        assert_eq!(new_book.title, test_book.title);
+        assert_eq!(new_book.author, test_book.author);
+    }
+
+    #[tokio::test]
+    async fn add_book() {
+        let client = setup_tests().await;
+        // Legacy clients still send an id of -1; it is ignored.
+        let new_book = serde_json::json!({
+            "id": -1,
+            "title": "Test POST Book",
+            "author": "Author, Test POST",
+        });
+        let res = client.post("/books/add").json(&new_book).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let new_id: i32 = res.json().await;
+        assert!(new_id > 0);
+
+        let test_book = client.get(&format!("/books/{new_id}")).send().await;
+        assert_eq!(test_book.status(), StatusCode::OK);
+        let test_book: Book = test_book.json().await;
+        assert_eq!(new_id, test_book.id);
+        assert_eq!("Test POST Book", test_book.title);
+        assert_eq!("Author, Test POST", test_book.author);
+    }
+
+    #[tokio::test]
+    async fn replace_book() {
+        let client = setup_tests().await;
+        let replacement = BookUpdate {
+            title: "Replaced book".to_string(),
+            author: "Author, Replaced".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            version: Some(1),
+            ..BookUpdate::default()
+        };
+        let res = client
+            .put("/api/v1/books/1")
+            .json(&replacement)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.id, 1);
+        assert_eq!(book.title, replacement.title);
+        assert_eq!(book.author, replacement.author);
+
+        let res = client
+            .put("/api/v1/books/9999")
+            .json(&replacement)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patch_book() {
+        let client = setup_tests().await;
+        let patch = BookPatch {
+            title: Some("Patched book".to_string()),
+            version: Some(1),
+            ..BookPatch::default()
+        };
+        let res = client.patch("/api/v1/books/2").json(&patch).send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.title, "Patched book");
+        assert_eq!(book.author, "Wolverson, Herbert");
+
+        let res = client.patch("/api/v1/books/9999").json(&patch).send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
     async fn update_book() {
         let client = setup_tests().await;
         let mut book1: Book = client.get("/books/1").send().await.json().await;
@@ -158,31 +2331,224 @@ mod test {
         assert_eq!(res.status(), StatusCode::OK);
         let book2: Book = client.get("/books/1").send().await.json().await;
         assert_eq!(book1.title, book2.title);
+
+        book1.id = 9999;
+        let res = client.put("/books/edit").json(&book1).send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn add_invalid_book() {
+        let client = setup_tests().await;
+        let new_book = NewBook {
+            title: "x".repeat(10_000),
+            author: "   ".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
+        };
+        let res = client.post("/api/v1/books").json(&new_book).send().await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Problem = res.json().await;
+        let fields: Vec<&str> = problem.errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["title", "author"]);
     }
 
     #[tokio::test]
     async fn delete_book() {
         let client = setup_tests().await;
-        let new_book = Book {
-            id: -1,
+        let new_book = NewBook {
             title: "Delete me".to_string(),
-            author: "Delete me".to_string(),
+            author: "Me, Delete".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
         };
-        let new_id: i32 = client
-            .post("/books/add")
+        let new_book: Book = client
+            .post("/books")
             .json(&new_book)
             .send()
             .await
             .json()
             .await;
+        let new_id = new_book.id;
+
+        let res = client
+            .delete(&format!("/books/delete/{new_id}?version=1"))
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
 
         let res = client
-            .delete(&format!("/books/delete/{new_id}"))
+            .delete(&format!("/books/delete/{new_id}?version=1"))
             .send()
             .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let all_books: BookList = client.get("/books").send().await.json().await;
+        assert!(!all_books.items.iter().any(|b| b.id == new_id))
+    }
+
+    #[tokio::test]
+    async fn remove_book() {
+        let client = setup_tests().await;
+        let new_book = NewBook {
+            title: "Remove me".to_string(),
+            author: "Me, Remove".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            ..NewBook::default()
+        };
+        let new_book: Book = client
+            .post("/api/v1/books")
+            .json(&new_book)
+            .send()
+            .await
+            .json()
+            .await;
+        let path = format!("/api/v1/books/{}", new_book.id);
+
+        let res = client.delete(&path).send().await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+        let res = client.delete(&format!("{path}?version=1")).send().await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = client.get(&path).send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = client.delete(&format!("{path}?version=1")).send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// The `ETag` the server currently gives book `id`.
+    async fn current_etag(client: &TestClient, id: i32) -> HeaderValue {
+        let res = client.get(&format!("/api/v1/books/{id}")).send().await;
+        res.headers()[header::ETAG].clone()
+    }
+
+    #[tokio::test]
+    async fn optimistic_concurrency() {
+        let client = setup_tests().await;
+        let etag = current_etag(&client, 1).await;
+        let update = BookUpdate {
+            title: "First edit".to_string(),
+            author: "Wolverson, Herbert".to_string(),
+            authors: Vec::new(),
+            isbn: None,
+            version: None,
+            ..BookUpdate::default()
+        };
+
+        // Writes must say which version they change
+        let res = client.put("/api/v1/books/1").json(&update).send().await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+
+        let res = client
+            .put("/api/v1/books/1")
+            .header(header::IF_MATCH, etag.clone())
+            .json(&update)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let book: Book = res.json().await;
+        assert_eq!(book.version, 2);
+
+        // A second editor with the old ETag gets 412 and the current book
+        let res = client
+            .put("/api/v1/books/1")
+            .header(header::IF_MATCH, etag.clone())
+            .json(&update)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let problem: Problem = res.json().await;
+        assert_eq!(problem.current.unwrap().title, "First edit");
+
+        // With an old version in the body, it's a 409
+        let stale = BookUpdate {
+            version: Some(1),
+            ..update
+        };
+        let res = client.put("/api/v1/books/1").json(&stale).send().await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let problem: Problem = res.json().await;
+        assert_eq!(problem.current.unwrap().version, 2);
+
+        let res = client
+            .delete("/api/v1/books/1")
+            .header(header::IF_MATCH, etag)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = client
+            .delete("/api/v1/books/1")
+            .header(header::IF_MATCH, current_etag(&client, 1).await)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn legacy_optimistic_concurrency() {
+        let client = setup_tests().await;
+        let etag = current_etag(&client, 2).await;
+        let edit = serde_json::json!({
+            "id": 2,
+            "title": "Legacy edit",
+            "author": "Wolverson, Herbert",
+        });
+
+        // The deprecated routes must say which version they change too
+        let res = client.put("/books/edit").json(&edit).send().await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+        let res = client.delete("/books/delete/2").send().await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+
+        let res = client
+            .put("/books/edit")
+            .header(header::IF_MATCH, etag.clone())
+            .json(&edit)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Both are now out of date
+        let mut stale = edit.clone();
+        stale["version"] = 1.into();
+        let res = client.put("/books/edit").json(&stale).send().await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = client
+            .put("/books/edit")
+            .header(header::IF_MATCH, etag.clone())
+            .json(&edit)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let res = client.delete("/books/delete/2?version=1").send().await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = client
+            .delete("/books/delete/2")
+            .header(header::IF_MATCH, etag)
+            .send()
+            .await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+
+        let res = client.delete("/books/delete/2?version=2").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn legacy_routes_are_deprecated() {
+        let client = setup_tests().await;
+        let res = client.get("/books/1").send().await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["deprecation"], "true");
+        assert_eq!(res.headers()["sunset"], LEGACY_SUNSET);
+
+        let res = client.get("/api/v1/books/1").send().await;
         assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("deprecation").is_none());
 
-        let all_books: Vec<Book> = client.get("/books").send().await.json().await;
-        assert!(all_books.iter().find(|b| b.id == new_id).is_none())
+        // The verb-style routes only exist on the legacy service
+        let res = client.delete("/api/v1/books/delete/1").send().await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,48 @@
+//! Application state, shared with every handler through `Router::with_state`.
+
+use crate::cache::{Cache, SingleFlight};
+use crate::config::TrashConfig;
+use crate::db::Repository;
+use std::sync::Arc;
+
+/// Everything a request handler needs: where the books are stored, and
+/// the cache in front of them.
+#[derive(Clone)]
+pub struct AppState {
+    /// The book storage backend
+    pub repo: Repository,
+    /// The cache for books and query results
+    pub cache: Arc<dyn Cache>,
+    /// Cache misses currently being loaded, shared by concurrent requests
+    pub flights: Arc<SingleFlight>,
+    /// How long deleted books are kept, and who may purge them
+    pub trash: Arc<TrashConfig>,
+}
+
+impl AppState {
+    pub fn new(repo: Repository, cache: Arc<dyn Cache>) -> Self {
+        Self {
+            repo,
+            cache,
+            flights: Arc::default(),
+            trash: Arc::default(),
+        }
+    }
+
+    /// Use `trash` instead of the default trash settings.
+    pub fn with_trash(self, trash: TrashConfig) -> Self {
+        Self {
+            trash: Arc::new(trash),
+            ..self
+        }
+    }
+}
+
+/// State for a test: a fresh in-memory database with its own cache.
+#[cfg(test)]
+pub async fn test_state() -> AppState {
+    AppState::new(
+        crate::db::test_db().await,
+        crate::cache::from_config(&crate::config::CacheConfig::default()),
+    )
+}
@@ -0,0 +1,640 @@
+//! Checks and normalizes book data before it reaches the database.
+//!
+//! Every write in `db` passes through here, so the rules apply no matter
+//! which endpoint the data arrived from.
+
+use crate::db::{
+    credit_line, AuthorRole, BatchOp, BookPatch, BookUpdate, NewAuthor, NewBook, NewCredit,
+};
+use crate::error::{Error, FieldError, Result};
+use crate::isbn;
+
+/// The longest title we accept, in characters.
+pub const MAX_TITLE_LEN: usize = 256;
+
+/// The longest author name we accept, in characters.
+pub const MAX_AUTHOR_LEN: usize = 128;
+
+/// The most people that may be credited on one book.
+pub const MAX_CREDITS: usize = 50;
+
+/// The longest publisher or series name we accept, in characters.
+pub const MAX_NAME_LEN: usize = 256;
+
+/// The longest edition statement we accept, in characters.
+pub const MAX_EDITION_LEN: usize = 64;
+
+/// The most pages a book may have.
+pub const MAX_PAGES: i32 = 100_000;
+
+/// The longest tag we accept, in characters.
+pub const MAX_TAG_LEN: usize = 64;
+
+impl NewBook {
+    /// Validate the title, credits, ISBN and edition details together,
+    /// reporting every failing field.
+    ///
+    /// ## Returns
+    /// * The normalized book, with its credits in `authors` (see
+    ///   `validate_credits`) and any ISBN as an ISBN-13, or
+    ///   `Error::Validation` listing each problem.
+    pub fn validated(&self) -> Result<NewBook> {
+        let mut errors = Vec::new();
+        let title = keep(validate_title(&self.title), &mut errors);
+        let credits = keep(validate_credits(&self.author, &self.authors), &mut errors);
+        let isbn = keep(
+            optional(&self.isbn, |isbn| validate_isbn(isbn)),
+            &mut errors,
+        );
+        let publisher = keep(
+            optional(&self.publisher, |v| validate_publisher(v)),
+            &mut errors,
+        );
+        let edition = keep(
+            optional(&self.edition, |v| validate_edition(v)),
+            &mut errors,
+        );
+        let page_count = keep(
+            optional(&self.page_count, |n| validate_page_count(*n)),
+            &mut errors,
+        );
+        let language = keep(
+            optional(&self.language, |v| validate_language(v)),
+            &mut errors,
+        );
+        let series = keep(optional(&self.series, |v| validate_series(v)), &mut errors);
+        let series_volume = keep(
+            optional(&self.series_volume, |n| validate_volume(*n)),
+            &mut errors,
+        );
+        if !errors.is_empty() {
+            return Err(Error::Validation(errors));
+        }
+        let (author, authors) = credits.unwrap_or_default();
+        Ok(NewBook {
+            title: title.unwrap_or_default(),
+            author,
+            authors,
+            isbn: isbn.flatten(),
+            publisher: publisher.flatten(),
+            published_on: self.published_on,
+            edition: edition.flatten(),
+            page_count: page_count.flatten(),
+            language: language.flatten(),
+            series: series.flatten(),
+            series_volume: series_volume.flatten(),
+        })
+    }
+}
+
+impl BookUpdate {
+    /// Validate every field together, reporting every failing field.
+    ///
+    /// ## Returns
+    /// * The normalized update, or `Error::Validation` listing each problem.
+    pub fn validated(&self) -> Result<BookUpdate> {
+        let book = NewBook {
+            title: self.title.clone(),
+            author: self.author.clone(),
+            authors: self.authors.clone(),
+            isbn: self.isbn.clone(),
+            publisher: self.publisher.clone(),
+            published_on: self.published_on,
+            edition: self.edition.clone(),
+            page_count: self.page_count,
+            language: self.language.clone(),
+            series: self.series.clone(),
+            series_volume: self.series_volume,
+        }
+        .validated()?;
+        Ok(BookUpdate {
+            title: book.title,
+            author: book.author,
+            authors: book.authors,
+            isbn: book.isbn,
+            publisher: book.publisher,
+            published_on: book.published_on,
+            edition: book.edition,
+            page_count: book.page_count,
+            language: book.language,
+            series: book.series,
+            series_volume: book.series_volume,
+            version: self.version,
+        })
+    }
+}
+
+impl BookPatch {
+    /// Validate whichever fields are present, reporting every failing field.
+    ///
+    /// ## Returns
+    /// * The normalized patch, with `authors` set if either `author` or
+    ///   `authors` was, or `Error::Validation` listing each problem.
+    pub fn validated(&self) -> Result<BookPatch> {
+        let mut errors = Vec::new();
+        let title = keep(optional(&self.title, |t| validate_title(t)), &mut errors);
+        let credits = match (&self.authors, &self.author) {
+            (Some(authors), _) if !authors.is_empty() => validate_credits("", authors).map(Some),
+            (_, Some(author)) => validate_credits(author, &[]).map(Some),
+            _ => Ok(None),
+        };
+        let credits = keep(credits, &mut errors);
+        let isbn = keep(patched(&self.isbn, |isbn| validate_isbn(isbn)), &mut errors);
+        let publisher = keep(
+            patched(&self.publisher, |v| validate_publisher(v)),
+            &mut errors,
+        );
+        let edition = keep(patched(&self.edition, |v| validate_edition(v)), &mut errors);
+        let page_count = keep(
+            patched(&self.page_count, |n| validate_page_count(*n)),
+            &mut errors,
+        );
+        let language = keep(
+            patched(&self.language, |v| validate_language(v)),
+            &mut errors,
+        );
+        let series = keep(patched(&self.series, |v| validate_series(v)), &mut errors);
+        let series_volume = keep(
+            patched(&self.series_volume, |n| validate_volume(*n)),
+            &mut errors,
+        );
+        if !errors.is_empty() {
+            return Err(Error::Validation(errors));
+        }
+        let credits = credits.flatten();
+        Ok(BookPatch {
+            title: title.flatten(),
+            author: credits.as_ref().map(|(author, _)| author.clone()),
+            authors: credits.map(|(_, authors)| authors),
+            isbn: isbn.flatten(),
+            publisher: publisher.flatten(),
+            published_on: self.published_on,
+            edition: edition.flatten(),
+            page_count: page_count.flatten(),
+            language: language.flatten(),
+            series: series.flatten(),
+            series_volume: series_volume.flatten(),
+            version: self.version,
+        })
+    }
+}
+
+/// The value of a check that passed, or `None` after adding the failure
+/// to `errors`.
+fn keep<T>(result: std::result::Result<T, FieldError>, errors: &mut Vec<FieldError>) -> Option<T> {
+    result.map_err(|err| errors.push(err)).ok()
+}
+
+/// Check an optional field, if it is given.
+fn optional<T, F>(value: &Option<T>, check: F) -> std::result::Result<Option<T>, FieldError>
+where
+    F: Fn(&T) -> std::result::Result<T, FieldError>,
+{
+    value.as_ref().map(check).transpose()
+}
+
+/// Check a patched field, if it is being set to a new value.
+fn patched<T, F>(
+    value: &Option<Option<T>>,
+    check: F,
+) -> std::result::Result<Option<Option<T>>, FieldError>
+where
+    F: Fn(&T) -> std::result::Result<T, FieldError>,
+{
+    value
+        .as_ref()
+        .map(|value| optional(value, &check))
+        .transpose()
+}
+
+impl NewAuthor {
+    /// Normalize the name into "Surname, Forename" form and check it.
+    ///
+    /// ## Returns
+    /// * The normalized author, or `Error::Validation`.
+    pub fn validated(&self) -> Result<NewAuthor> {
+        validate_author(&self.name)
+            .map(|name| NewAuthor { name })
+            .map_err(|e| FieldError::new("name", e.message).into())
+    }
+}
+
+impl BatchOp {
+    /// Validate the book in a create or update. Updates and deletes in a
+    /// batch must name the version they change.
+    ///
+    /// ## Returns
+    /// * The normalized operation, or `Error::Validation` listing each problem.
+    pub fn validated(&self) -> Result<BatchOp> {
+        Ok(match self {
+            BatchOp::Create { book } => BatchOp::Create {
+                book: book.validated()?,
+            },
+            BatchOp::Update { id, book } => {
+                require_version(book.version)?;
+                BatchOp::Update {
+                    id: *id,
+                    book: book.validated()?,
+                }
+            }
+            BatchOp::Delete { version, .. } => {
+                require_version(*version)?;
+                self.clone()
+            }
+        })
+    }
+}
+
+/// Check that a write says which version it changes.
+fn require_version(version: Option<i64>) -> std::result::Result<(), FieldError> {
+    match version {
+        Some(_) => Ok(()),
+        None => Err(FieldError::new("version", "is required")),
+    }
+}
+
+/// Collapse runs of whitespace into single spaces, and trim the ends.
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Check that a (normalized) value is present, not too long, and free of
+/// control characters, which would corrupt MARC exports.
+fn check_length(field: &str, value: &str, max: usize) -> std::result::Result<(), FieldError> {
+    if value.is_empty() {
+        Err(FieldError::new(field, "is required"))
+    } else if value.chars().count() > max {
+        Err(FieldError::new(
+            field,
+            format!("must be at most {max} characters"),
+        ))
+    } else if value.chars().any(char::is_control) {
+        Err(FieldError::new(
+            field,
+            "must not contain control characters",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Trim a title and check its length.
+pub fn validate_title(title: &str) -> std::result::Result<String, FieldError> {
+    let title = collapse_whitespace(title);
+    check_length("title", &title, MAX_TITLE_LEN)?;
+    Ok(title)
+}
+
+/// Normalize an author into "Surname, Forename" form and check its length.
+///
+/// Single-word names (such as "Plato") are accepted as-is. Anything longer
+/// must contain exactly one comma separating a non-empty surname and
+/// forename; spacing around the comma is normalized.
+pub fn validate_author(author: &str) -> std::result::Result<String, FieldError> {
+    let author = collapse_whitespace(author);
+    check_length("author", &author, MAX_AUTHOR_LEN)?;
+    let parts: Vec<&str> = author.split(',').map(str::trim).collect();
+    match parts.as_slice() {
+        [single] if !single.contains(' ') => Ok(author),
+        [surname, forename] if !surname.is_empty() && !forename.is_empty() => {
+            Ok(format!("{surname}, {forename}"))
+        }
+        _ => Err(FieldError::new(
+            "author",
+            "must be written as \"Surname, Forename\"",
+        )),
+    }
+}
+
+/// Check a book's credits: the `authors` list if it isn't empty, or else
+/// the `author` string, read the way `Book::author` shows credits. Every
+/// name is normalized with `validate_author`.
+///
+/// ## Returns
+/// * The credits, each with either an ID or a name, and the display string
+///   for them. The display string is empty if any credit is by ID alone,
+///   as the name isn't known until the author is looked up.
+pub fn validate_credits(
+    author: &str,
+    authors: &[NewCredit],
+) -> std::result::Result<(String, Vec<NewCredit>), FieldError> {
+    let credits = if authors.is_empty() {
+        let author = collapse_whitespace(author);
+        check_length("author", &author, MAX_CREDITS * (MAX_AUTHOR_LEN + 16))?;
+        author
+            .split(';')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (name, role) = part
+                    .strip_suffix(')')
+                    .and_then(|rest| rest.rsplit_once(" ("))
+                    .and_then(|(name, role)| Some((name, AuthorRole::from_name(role)?)))
+                    .unwrap_or((part, AuthorRole::Author));
+                Ok(NewCredit {
+                    id: None,
+                    name: Some(validate_author(name)?),
+                    role,
+                })
+            })
+            .collect::<std::result::Result<Vec<_>, FieldError>>()?
+    } else {
+        authors
+            .iter()
+            .map(|credit| match (credit.id, &credit.name) {
+                (Some(id), _) => Ok(NewCredit {
+                    id: Some(id),
+                    name: None,
+                    role: credit.role,
+                }),
+                (None, Some(name)) => Ok(NewCredit {
+                    id: None,
+                    name: Some(
+                        validate_author(name).map_err(|e| FieldError::new("authors", e.message))?,
+                    ),
+                    role: credit.role,
+                }),
+                (None, None) => Err(FieldError::new("authors", "each needs an id or a name")),
+            })
+            .collect::<std::result::Result<Vec<_>, FieldError>>()?
+    };
+    if credits.is_empty() {
+        return Err(FieldError::new("author", "is required"));
+    }
+    if credits.len() > MAX_CREDITS {
+        let field = if authors.is_empty() {
+            "author"
+        } else {
+            "authors"
+        };
+        return Err(FieldError::new(
+            field,
+            format!("must not credit more than {MAX_CREDITS} people"),
+        ));
+    }
+    let names: Option<Vec<(&str, AuthorRole)>> = credits
+        .iter()
+        .map(|credit| Some((credit.name.as_deref()?, credit.role)))
+        .collect();
+    Ok((names.map(credit_line).unwrap_or_default(), credits))
+}
+
+/// Trim a publisher's name and check its length.
+pub fn validate_publisher(name: &str) -> std::result::Result<String, FieldError> {
+    let name = collapse_whitespace(name);
+    check_length("publisher", &name, MAX_NAME_LEN)?;
+    Ok(name)
+}
+
+/// Trim a series' name and check its length.
+pub fn validate_series(name: &str) -> std::result::Result<String, FieldError> {
+    let name = collapse_whitespace(name);
+    check_length("series", &name, MAX_NAME_LEN)?;
+    Ok(name)
+}
+
+/// Trim an edition statement and check its length.
+pub fn validate_edition(edition: &str) -> std::result::Result<String, FieldError> {
+    let edition = collapse_whitespace(edition);
+    check_length("edition", &edition, MAX_EDITION_LEN)?;
+    Ok(edition)
+}
+
+/// Check that a page count is plausible.
+pub fn validate_page_count(pages: i32) -> std::result::Result<i32, FieldError> {
+    if (1..=MAX_PAGES).contains(&pages) {
+        Ok(pages)
+    } else {
+        Err(FieldError::new(
+            "page_count",
+            format!("must be between 1 and {MAX_PAGES}"),
+        ))
+    }
+}
+
+/// Check that a number in a series is positive.
+pub fn validate_volume(volume: i32) -> std::result::Result<i32, FieldError> {
+    if volume >= 1 {
+        Ok(volume)
+    } else {
+        Err(FieldError::new("series_volume", "must be at least 1"))
+    }
+}
+
+/// Check that a language is a two- or three-letter ISO 639 code, and
+/// lowercase it.
+pub fn validate_language(code: &str) -> std::result::Result<String, FieldError> {
+    let code = code.trim().to_ascii_lowercase();
+    if (2..=3).contains(&code.len()) && code.chars().all(|c| c.is_ascii_lowercase()) {
+        Ok(code)
+    } else {
+        Err(FieldError::new(
+            "language",
+            "must be an ISO 639 code, such as \"en\"",
+        ))
+    }
+}
+
+/// Trim a tag, put it in lower case and check its length.
+pub fn validate_tag(tag: &str) -> std::result::Result<String, FieldError> {
+    let tag = collapse_whitespace(tag).to_lowercase();
+    check_length("tag", &tag, MAX_TAG_LEN)?;
+    Ok(tag)
+}
+
+/// Check an ISBN-10 or ISBN-13 and convert it to an ISBN-13.
+pub fn validate_isbn(isbn: &str) -> std::result::Result<String, FieldError> {
+    isbn::parse(isbn).map_err(|message| FieldError::new("isbn", message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn titles() {
+        assert_eq!(
+            validate_title("  Hands-on   Rust ").unwrap(),
+            "Hands-on Rust"
+        );
+        assert!(validate_title("   ").is_err());
+        assert!(validate_title(&"x".repeat(MAX_TITLE_LEN)).is_ok());
+        assert!(validate_title(&"x".repeat(MAX_TITLE_LEN + 1)).is_err());
+        assert!(validate_title("Hands-on\u{1d}Rust").is_err());
+        assert!(validate_title("Hands-on\u{7f}Rust").is_err());
+        assert_eq!(validate_title("Hands-on\tRust").unwrap(), "Hands-on Rust");
+    }
+
+    #[test]
+    fn authors() {
+        assert_eq!(
+            validate_author("Wolverson,Herbert").unwrap(),
+            "Wolverson, Herbert"
+        );
+        assert_eq!(
+            validate_author(" Le Guin ,  Ursula K. ").unwrap(),
+            "Le Guin, Ursula K."
+        );
+        assert_eq!(validate_author("Plato").unwrap(), "Plato");
+        assert!(validate_author("Herbert Wolverson").is_err());
+        assert!(validate_author("Wolverson,").is_err());
+        assert!(validate_author("a, b, c").is_err());
+        assert!(validate_author("").is_err());
+        assert!(validate_author("Wolverson,\u{1f}Herbert").is_err());
+    }
+
+    #[test]
+    fn credits() {
+        let (line, credits) =
+            validate_credits("Sebald ,W. G.;  Hulse,Michael (TRANSLATOR); ", &[]).unwrap();
+        assert_eq!(line, "Sebald, W. G.; Hulse, Michael (translator)");
+        assert_eq!(
+            credits
+                .iter()
+                .map(|c| (c.name.as_deref().unwrap(), c.role))
+                .collect::<Vec<_>>(),
+            [
+                ("Sebald, W. G.", AuthorRole::Author),
+                ("Hulse, Michael", AuthorRole::Translator)
+            ]
+        );
+        // An unknown role is just part of the name
+        let (_, credits) = validate_credits("Hulse, Michael (typist)", &[]).unwrap();
+        assert_eq!(credits[0].name.as_deref(), Some("Hulse, Michael (typist)"));
+        assert_eq!(credits[0].role, AuthorRole::Author);
+
+        // A list wins over the string, and credits by ID need no name
+        let listed = [
+            NewCredit {
+                id: Some(4),
+                name: Some("ignored".to_string()),
+                role: AuthorRole::Editor,
+            },
+            NewCredit {
+                name: Some("Le Guin,Ursula K.".to_string()),
+                ..NewCredit::default()
+            },
+        ];
+        let (line, credits) = validate_credits("Anyone, At All", &listed).unwrap();
+        assert_eq!(line, "");
+        assert_eq!(credits[0].name, None);
+        assert_eq!(credits[1].name.as_deref(), Some("Le Guin, Ursula K."));
+
+        let err = validate_credits("", &[NewCredit::default()]).unwrap_err();
+        assert_eq!(err.field, "authors");
+        assert_eq!(validate_credits(" ; ", &[]).unwrap_err().field, "author");
+        let many = vec![
+            NewCredit {
+                id: Some(1),
+                ..NewCredit::default()
+            };
+            MAX_CREDITS + 1
+        ];
+        assert!(validate_credits("", &many).is_err());
+    }
+
+    #[test]
+    fn editions() {
+        assert_eq!(
+            validate_publisher(" Pragmatic   Bookshelf ").unwrap(),
+            "Pragmatic Bookshelf"
+        );
+        assert!(validate_series(" ").is_err());
+        assert!(validate_edition(&"x".repeat(MAX_EDITION_LEN + 1)).is_err());
+        assert_eq!(validate_language("EN").unwrap(), "en");
+        assert_eq!(validate_language("deu").unwrap(), "deu");
+        assert!(validate_language("english").is_err());
+        assert!(validate_language("e1").is_err());
+        assert!(validate_page_count(0).is_err());
+        assert!(validate_page_count(MAX_PAGES).is_ok());
+        assert!(validate_volume(0).is_err());
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(
+            validate_tag("  Science   FICTION ").unwrap(),
+            "science fiction"
+        );
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag(&"x".repeat(MAX_TAG_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn reports_every_field() {
+        let book = NewBook {
+            title: String::new(),
+            author: String::new(),
+            authors: Vec::new(),
+            isbn: Some("978-1-68050-816-2".to_string()),
+            ..NewBook::default()
+        };
+        let Err(Error::Validation(errors)) = book.validated() else {
+            panic!("expected a validation error");
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["title", "author", "isbn"]);
+    }
+
+    #[test]
+    fn patches_only_check_present_fields() {
+        let patch = BookPatch {
+            title: None,
+            author: Some("Wolverson ,Herbert".to_string()),
+            authors: None,
+            isbn: Some(Some("1-68050-816-4".to_string())),
+            version: Some(3),
+            ..BookPatch::default()
+        };
+        let patch = patch.validated().unwrap();
+        assert_eq!(patch.title, None);
+        assert_eq!(patch.author.as_deref(), Some("Wolverson, Herbert"));
+        assert_eq!(patch.isbn, Some(Some("9781680508161".to_string())));
+        assert_eq!(patch.version, Some(3));
+
+        // Removing the ISBN needs no checks
+        let patch = BookPatch {
+            isbn: Some(None),
+            ..BookPatch::default()
+        };
+        assert_eq!(patch.validated().unwrap(), patch);
+
+        let patch = BookPatch {
+            title: Some(" ".to_string()),
+            ..BookPatch::default()
+        };
+        assert!(patch.validated().is_err());
+    }
+
+    #[test]
+    fn batch_writes_need_versions() {
+        let create = BatchOp::Create {
+            book: NewBook {
+                title: " Batched ".to_string(),
+                author: "Author,Test".to_string(),
+                authors: Vec::new(),
+                isbn: None,
+                ..NewBook::default()
+            },
+        };
+        let BatchOp::Create { book } = create.validated().unwrap() else {
+            panic!("expected a create");
+        };
+        assert_eq!(book.title, "Batched");
+        assert_eq!(book.author, "Author, Test");
+
+        let delete = BatchOp::Delete {
+            id: 1,
+            version: None,
+        };
+        let Err(Error::Validation(errors)) = delete.validated() else {
+            panic!("expected a validation error");
+        };
+        assert_eq!(errors[0].field, "version");
+        let delete = BatchOp::Delete {
+            id: 1,
+            version: Some(1),
+        };
+        assert_eq!(delete.validated().unwrap(), delete);
+    }
+}
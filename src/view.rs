@@ -1,13 +1,29 @@
+use crate::openapi::ApiDoc;
 use axum::response::Html;
 use axum::routing::get;
-use axum::Router;
+use axum::{Json, Router};
+use utoipa::OpenApi;
 
 pub fn view_service() -> Router {
-    Router::new().route("/", get(index_page))
+    Router::new()
+        .route("/", get(index_page))
+        .route("/docs", get(docs_page))
+        .route("/openapi.json", get(openapi_json))
 }
 
 const INDEX_PAGE: &str = include_str!("index.html");
+const DOCS_PAGE: &str = include_str!("docs.html");
 
 async fn index_page() -> Html<&'static str> {
     Html(INDEX_PAGE)
 }
+
+/// Interactive API documentation, rendered by Swagger UI from `/openapi.json`.
+async fn docs_page() -> Html<&'static str> {
+    Html(DOCS_PAGE)
+}
+
+/// The OpenAPI document describing the REST API.
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}